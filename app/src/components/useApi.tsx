@@ -1,7 +1,15 @@
 import { DateTime } from 'luxon';
 import { createMemo, createResource, onCleanup } from 'solid-js';
 
-const PREFIX = globalThis?.location?.origin ?? `http://localhost`;
+const PREFIX = globalThis?.document?.baseURI ?? `http://localhost/`;
+
+// API paths are relative to the base path the server is mounted at
+function apiUrl(path: string): URL {
+  const url = new URL(path.replace(/^\//, ''), PREFIX);
+  if (process.env.NODE_ENV === 'development')
+    url.port = '8000';
+  return url;
+}
 
 class FetchError extends Error {
   statusCode: number;
@@ -32,20 +40,12 @@ async function fetcher(url: URL | string): Promise<Object | Object[]> {
 
 export function useApi(func: Function) {
 
-  const results = createResource(() => {
-    const url = new URL(func(), PREFIX);
-    if (process.env.NODE_ENV === 'development')
-      url.port = '8000';
-    return url;
-  }, fetcher);
+  const results = createResource(() => apiUrl(func()), fetcher);
   return results;
 }
 
 export function useEvents() {
-  const url = new URL('/events', PREFIX);
-  if (process.env.NODE_ENV === 'development')
-    url.port = '8000';
-  const sse = new EventSource(url);
+  const sse = new EventSource(apiUrl('/events'));
   onCleanup(() => {
     sse.close();
   });
@@ -4,6 +4,8 @@ import viteCompression from 'vite-plugin-compression';
 
 export default defineConfig({
   plugins: [solidPlugin(), viteCompression()],
+  // Relative to the <base> the server adds, so the UI works under a sub-path
+  base: './',
   server: {
     port: 3000,
   },
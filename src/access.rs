@@ -0,0 +1,230 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use crate::{auth::Cidr, matcher::Matcher, search::Record, utils::escape};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// What a user may do, each role can do everything the ones before it can
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Search messages from their host groups
+    #[default]
+    Viewer,
+    /// Also manage alert rules, silences and alerts
+    Operator,
+    /// Also run raw Cypher and see every host
+    Admin,
+}
+
+/// A hostname, or a pattern with a `*` at the start, the end or both
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostnamePattern(String);
+
+impl HostnamePattern {
+    fn parts(&self) -> (bool, &str, bool) {
+        let prefix = self.0.starts_with('*');
+        let suffix = self.0.len() > 1 && self.0.ends_with('*');
+        let inner = self.0.trim_start_matches('*').trim_end_matches('*');
+        (prefix, inner, suffix)
+    }
+
+    pub fn matches(&self, hostname: &str) -> bool {
+        match self.parts() {
+            (false, inner, false) => hostname == inner,
+            (false, inner, true) => hostname.starts_with(inner),
+            (true, inner, false) => hostname.ends_with(inner),
+            (true, inner, true) => hostname.contains(inner),
+        }
+    }
+
+    /// Cypher condition on `alias.name`
+    fn condition(&self, alias: &str) -> String {
+        let (op, inner) = match self.parts() {
+            (false, inner, false) => ("=", inner),
+            (false, inner, true) => ("STARTS WITH", inner),
+            (true, inner, false) => ("ENDS WITH", inner),
+            (true, inner, true) => ("CONTAINS", inner),
+        };
+        format!("{alias}.name {op} '{}'", escape(inner))
+    }
+}
+
+impl<'de> Deserialize<'de> for HostnamePattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        let inner = pattern.trim_start_matches('*').trim_end_matches('*');
+        if inner.contains('*') || (inner.is_empty() && pattern != "*") {
+            return Err(serde::de::Error::custom(format!(
+                "Invalid hostname pattern {pattern}, * may only be at the start or end"
+            )));
+        }
+        Ok(HostnamePattern(pattern))
+    }
+}
+
+/// Hosts matched by hostname pattern or address
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HostGroup {
+    #[serde(default)]
+    pub hostnames: Vec<HostnamePattern>,
+    #[serde(default)]
+    pub networks: Vec<Cidr>,
+}
+
+/// The role and host groups of a user, host groups are every host when left out
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Grant {
+    #[serde(default)]
+    pub role: Role,
+    pub groups: Option<Vec<String>>,
+}
+
+/// Beginnings of the addresses in an IPv4 network as they are stored, `None` for a single address.
+/// Networks that don't end on a whole octet are split into one prefix per value of the last partial octet.
+fn ipv4_prefixes(network: &Cidr) -> Option<Vec<String>> {
+    let IpAddr::V4(addr) = network.addr() else {
+        return None;
+    };
+    let prefix = network.prefix() as usize;
+    if prefix == 32 {
+        return None;
+    }
+    let octets = addr.octets();
+    let whole = prefix / 8;
+    let fixed: Vec<String> = octets[..whole].iter().map(u8::to_string).collect();
+    let bits = prefix % 8;
+    let values: Vec<Option<u8>> = match bits {
+        0 => vec![None],
+        bits => {
+            let base = octets[whole] & (u8::MAX << (8 - bits));
+            (0..1u16 << (8 - bits)).map(|v| Some(base + v as u8)).collect()
+        }
+    };
+    Some(
+        values
+            .into_iter()
+            .map(|value| {
+                let mut parts = fixed.clone();
+                parts.extend(value.map(|v| v.to_string()));
+                match parts.len() {
+                    0 => String::new(),
+                    _ => format!("{}.", parts.join(".")),
+                }
+            })
+            .collect(),
+    )
+}
+
+/// The hosts a user may see messages from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scope {
+    hostnames: Vec<HostnamePattern>,
+    networks: Vec<Cidr>,
+}
+
+impl Scope {
+    pub fn new(groups: &[&HostGroup]) -> Scope {
+        Scope {
+            hostnames: groups.iter().flat_map(|g| g.hostnames.iter().cloned()).collect(),
+            networks: groups.iter().flat_map(|g| g.networks.iter().copied()).collect(),
+        }
+    }
+
+    pub fn allows(&self, hostname: Option<&str>, ip: Option<&str>) -> bool {
+        hostname.is_some_and(|h| self.hostnames.iter().any(|p| p.matches(h)))
+            || ip
+                .and_then(|ip| ip.parse::<IpAddr>().ok())
+                .is_some_and(|ip| self.networks.iter().any(|n| n.contains(&ip)))
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        self.allows(record.hostname.as_deref(), record.ip.as_deref())
+    }
+
+    /// Whether an alert or host status for `host`, a hostname or an address, may be seen
+    pub fn allows_host(&self, host: &str) -> bool {
+        match host.parse::<IpAddr>() {
+            Ok(_) => self.allows(None, Some(host)),
+            Err(_) => self.allows(Some(host), None),
+        }
+    }
+
+    /// Whether an alert rule or silence only covers hosts that may be seen, by its hostname or address
+    pub fn allows_matcher(&self, matcher: &Matcher) -> bool {
+        let ip = matcher.ip.map(|ip| ip.to_string());
+        self.allows(matcher.hostname.as_deref(), ip.as_deref())
+    }
+
+    /// Cypher condition on the `hostname` and `address` bound by [`crate::search::Filter::match_clause`]
+    pub fn condition(&self) -> String {
+        let mut conditions: Vec<String> = self.hostnames.iter().map(|p| p.condition("hostname")).collect();
+        for network in &self.networks {
+            match ipv4_prefixes(network) {
+                Some(prefixes) => {
+                    // IPv4 clients of an IPv6 socket are stored as mapped addresses
+                    conditions.extend(prefixes.into_iter().map(|prefix| {
+                        format!("address.ip STARTS WITH '{prefix}' OR address.ip STARTS WITH '::ffff:{prefix}'")
+                    }));
+                }
+                None => conditions.push(format!("address.ip = '{}'", network.addr())),
+            }
+        }
+        match conditions.is_empty() {
+            true => "false".to_string(),
+            false => format!("({})", conditions.join(" OR ")),
+        }
+    }
+}
+
+/// Checks grants only refer to groups that exist and IPv6 networks are single addresses, which is all searches
+/// can match
+pub fn validate(groups: &HashMap<String, HostGroup>, grants: &[&Grant]) -> Result<()> {
+    for name in grants.iter().flat_map(|g| g.groups.iter().flatten()) {
+        if !groups.contains_key(name) {
+            bail!("Unknown host group {name}");
+        }
+    }
+    for network in groups.values().flat_map(|g| &g.networks) {
+        if network.addr().is_ipv6() && network.prefix() != 128 {
+            bail!("Host group network {network} must be a single IPv6 address");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HostGroup, HostnamePattern, Scope};
+    use crate::{matcher::Matcher, search::Filter};
+
+    #[test]
+    fn scopes() {
+        let group: HostGroup = serde_json::from_value(serde_json::json!({
+            "hostnames": ["core-*", "*.net.example.com", "fw1"],
+            "networks": ["10.1.16.0/22", "192.168.0.0/16", "2001:db8::1"],
+        }))
+        .unwrap();
+        let scope = Scope::new(&[&group]);
+        assert!(scope.allows(Some("core-sw1"), None));
+        assert!(scope.allows(Some("edge.net.example.com"), None));
+        assert!(!scope.allows(Some("fw10"), Some("10.1.20.1")));
+        assert!(scope.allows(Some("hr-db"), Some("10.1.19.255")));
+        assert!(scope.allows_host("::ffff:192.168.3.4"));
+        assert!(!scope.allows_host("hr-db"));
+        assert!(serde_json::from_str::<HostnamePattern>(r#""core*sw""#).is_err());
+        assert!(scope.allows_matcher(&Matcher { hostname: Some("fw1".to_string()), ..Default::default() }));
+        assert!(scope.allows_matcher(&Matcher { ip: "10.1.17.3".parse().ok(), ..Default::default() }));
+        assert!(!scope.allows_matcher(&Matcher { appname: Some("sshd".to_string()), ..Default::default() }));
+
+        let condition = scope.condition();
+        assert!(condition.starts_with("(hostname.name STARTS WITH 'core-' OR hostname.name ENDS WITH '.net.example.com' OR hostname.name = 'fw1' OR address.ip STARTS WITH '10.1.16.' OR address.ip STARTS WITH '::ffff:10.1.16.' OR "));
+        assert!(condition.contains("address.ip STARTS WITH '10.1.19.' OR"));
+        assert!(!condition.contains("'10.1.20."));
+        assert!(condition.ends_with("OR address.ip STARTS WITH '192.168.' OR address.ip STARTS WITH '::ffff:192.168.' OR address.ip = '2001:db8::1')"));
+        assert_eq!(Scope::default().condition(), "false");
+
+        let filter = Filter { scope: Some(Scope::default()), ..Default::default() };
+        assert!(filter.to_query().contains(" WITH node, severity, facility, hostname, appname, address WHERE false RETURN"));
+    }
+}
@@ -0,0 +1,809 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    env, fs,
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{
+    broadcast::{error::RecvError, Sender},
+    watch::Receiver,
+};
+
+use crate::{
+    audit, database, health,
+    matcher::Matcher,
+    notify::{Notification, Notifiers},
+    search::Record,
+    syslog::Stored,
+    utils::escape,
+    watchdog,
+};
+use anyhow::{bail, Context, Result};
+use redis::aio::MultiplexedConnection;
+use redis_graph::{AsyncGraphCommands, GraphResult};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+fn default_threshold() -> usize {
+    1
+}
+
+fn default_window() -> u64 {
+    300
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    pub name: String,
+    #[serde(flatten)]
+    pub matcher: Matcher,
+    /// Matches from a single host needed within `window` before the alert fires
+    #[serde(default = "default_threshold")]
+    pub threshold: usize,
+    /// Seconds over which matches are counted, a firing alert resolves once a whole window passes without a match
+    #[serde(default = "default_window")]
+    pub window: u64,
+}
+
+/// A rule managed over the HTTP API, stored as an `AlertRule` node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredRule {
+    #[serde(flatten)]
+    pub rule: Rule,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+/// Mutes notifications for alerts triggered by matching messages between `starts_at` and `ends_at`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Silence {
+    #[serde(default, skip_deserializing)]
+    pub id: u64,
+    #[serde(flatten)]
+    pub matcher: Matcher,
+    /// Milliseconds since the epoch, defaults to now
+    #[serde(default)]
+    pub starts_at: u64,
+    /// Milliseconds since the epoch
+    #[serde(default)]
+    pub ends_at: u64,
+    /// Seconds from `starts_at`, instead of `ends_at`
+    #[serde(default, skip_serializing)]
+    pub duration: Option<u64>,
+    pub comment: Option<String>,
+}
+
+impl Silence {
+    /// Fills in the defaults for a new silence
+    pub fn normalize(&mut self, now: u64) -> Result<()> {
+        if self.starts_at == 0 {
+            self.starts_at = now;
+        }
+        if let Some(duration) = self.duration.take() {
+            self.ends_at = self.starts_at + duration * 1000;
+        }
+        if self.ends_at <= self.starts_at {
+            bail!("A silence needs an ends_at after starts_at or a duration");
+        }
+        Ok(())
+    }
+
+    pub fn mutes(&self, stored: &Stored, now: u64) -> bool {
+        (self.starts_at..self.ends_at).contains(&now) && self.matcher.matches(&stored.msg, &stored.ip)
+    }
+}
+
+pub fn rules_path_from_env() -> Option<String> {
+    env::var("EZSYSLOG_ALERT_RULES").ok()
+}
+
+/// Refuses rule names starting with [`watchdog::RESERVED_PREFIX`], which would mix with the server's own alerts
+pub fn check_rule_name(name: &str) -> Result<()> {
+    if name.starts_with(watchdog::RESERVED_PREFIX) {
+        bail!("Rule names starting with {} are reserved", watchdog::RESERVED_PREFIX);
+    }
+    Ok(())
+}
+
+/// Reads a JSON list of rules
+pub fn load_rules(path: &str) -> Result<Vec<Rule>> {
+    let data = fs::read(path).with_context(|| format!("Unable to read alert rules from {path}"))?;
+    let rules: Vec<Rule> = serde_json::from_slice(&data)?;
+    for rule in &rules {
+        check_rule_name(&rule.name).with_context(|| format!("Invalid rule in {path}"))?;
+    }
+    Ok(rules)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Transition {
+    /// A rule reached its threshold for a host, `messages` are the matches in the window
+    Fire {
+        rule: String,
+        host: String,
+        messages: Vec<usize>,
+    },
+    /// Another match for an alert that is already firing
+    Append { alert: u64, message: usize },
+    /// A firing alert went a whole window without a match
+    Resolve { alert: u64, rule: String, host: String },
+}
+
+#[derive(Default)]
+struct Window {
+    /// Timestamp in milliseconds and node id of each match within the window
+    matches: VecDeque<(u64, usize)>,
+    /// Node id of the alert while it is firing
+    alert: Option<u64>,
+    last_match: u64,
+}
+
+/// Counts rule matches per host and decides when alerts fire and resolve, leaving storage to the caller
+pub struct Engine {
+    rules: Vec<Rule>,
+    windows: HashMap<(String, String), Window>,
+}
+
+impl Engine {
+    pub fn new(rules: Vec<Rule>) -> Engine {
+        Engine {
+            rules,
+            windows: HashMap::new(),
+        }
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Swaps in a new set of rules, alerts of rules that are gone resolve on the next tick
+    pub fn set_rules(&mut self, rules: Vec<Rule>) {
+        self.rules = rules;
+    }
+
+    /// Picks up an alert that was already firing, e.g. before a restart. Alerts of silent hosts are left to the
+    /// watchdog, they would otherwise resolve on the next tick.
+    pub fn restore(&mut self, rule: String, host: String, alert: u64, last_match: u64) {
+        if rule == watchdog::SILENT_RULE {
+            return;
+        }
+        let window = self.windows.entry((rule, host)).or_default();
+        window.alert = Some(alert);
+        window.last_match = last_match;
+    }
+
+    /// Records the node id of an alert after a [`Transition::Fire`] was stored
+    pub fn fired(&mut self, rule: &str, host: &str, alert: u64) {
+        if let Some(window) = self.windows.get_mut(&(rule.to_string(), host.to_string())) {
+            window.alert = Some(alert);
+        }
+    }
+
+    /// Gives back the matches of a [`Transition::Fire`] that couldn't be stored, it fires again on the next tick
+    pub fn failed(&mut self, rule: &str, host: &str, messages: &[usize], now: u64) {
+        let window = self.windows.entry((rule.to_string(), host.to_string())).or_default();
+        for id in messages.iter().rev() {
+            window.matches.push_front((now, *id));
+        }
+        window.last_match = window.last_match.max(now);
+    }
+
+    pub fn observe(&mut self, stored: &Stored, now: u64) -> Vec<Transition> {
+        let host = stored
+            .msg
+            .hostname
+            .clone()
+            .unwrap_or_else(|| stored.ip.to_string());
+        let mut transitions = vec![];
+        for rule in &self.rules {
+            if !rule.matcher.matches(&stored.msg, &stored.ip) {
+                continue;
+            }
+            let window = self
+                .windows
+                .entry((rule.name.clone(), host.clone()))
+                .or_default();
+            window.last_match = now;
+            if let Some(alert) = window.alert {
+                transitions.push(Transition::Append {
+                    alert,
+                    message: stored.id,
+                });
+                continue;
+            }
+            window.matches.push_back((now, stored.id));
+            let start = now.saturating_sub(rule.window * 1000);
+            while window.matches.front().is_some_and(|(t, _)| *t < start) {
+                window.matches.pop_front();
+            }
+            if window.matches.len() >= rule.threshold {
+                transitions.push(Transition::Fire {
+                    rule: rule.name.clone(),
+                    host: host.clone(),
+                    messages: window.matches.drain(..).map(|(_, id)| id).collect(),
+                });
+            }
+        }
+        transitions
+    }
+
+    /// Resolves alerts without a match for a whole window, fires again those that [failed](Engine::failed) and
+    /// forgets hosts that have gone quiet
+    pub fn tick(&mut self, now: u64) -> Vec<Transition> {
+        let mut transitions = vec![];
+        let rules: HashMap<&str, &Rule> = self.rules.iter().map(|r| (r.name.as_str(), r)).collect();
+        self.windows.retain(|(rule, host), window| {
+            // Rules that were removed resolve immediately
+            let length = rules.get(rule.as_str()).map_or(0, |r| r.window * 1000);
+            let threshold = rules.get(rule.as_str()).map_or(usize::MAX, |r| r.threshold);
+            let quiet = now.saturating_sub(window.last_match) >= length;
+            let start = now.saturating_sub(length);
+            while window.matches.front().is_some_and(|(t, _)| *t < start) {
+                window.matches.pop_front();
+            }
+            if window.alert.is_none() && window.matches.len() >= threshold {
+                transitions.push(Transition::Fire {
+                    rule: rule.clone(),
+                    host: host.clone(),
+                    messages: window.matches.drain(..).map(|(_, id)| id).collect(),
+                });
+            }
+            if let (Some(alert), true) = (window.alert, quiet) {
+                transitions.push(Transition::Resolve {
+                    alert,
+                    rule: rule.clone(),
+                    host: host.clone(),
+                });
+                window.alert = None;
+            }
+            !(quiet && window.alert.is_none())
+        });
+        transitions
+    }
+}
+
+/// What a rule would have done over past messages
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Firing {
+    pub host: String,
+    pub started_at: u64,
+    pub resolved_at: Option<u64>,
+    /// Ids of the messages that triggered the alert or matched while it was firing
+    pub messages: Vec<usize>,
+}
+
+/// Structured data is not part of a record so rules with `params` never match
+fn stored_from_record(record: &Record) -> Stored {
+    Stored {
+        id: record.id as usize,
+        ip: record
+            .ip
+            .as_deref()
+            .and_then(|ip| ip.parse().ok())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        msg: record.to_message().into(),
+        server_timestamp: record.server_timestamp,
+    }
+}
+
+/// Runs a rule over records in `server_timestamp` order without storing anything
+pub fn dry_run(rule: Rule, records: &[Record]) -> Vec<Firing> {
+    let window = rule.window * 1000;
+    let mut engine = Engine::new(vec![rule]);
+    let mut firings: Vec<Firing> = vec![];
+    let mut last_match = HashMap::new();
+    for record in records {
+        let now = record.server_timestamp;
+        let mut transitions = engine.tick(now);
+        transitions.extend(engine.observe(&stored_from_record(record), now));
+        for transition in transitions {
+            match transition {
+                Transition::Fire { rule, host, messages } => {
+                    engine.fired(&rule, &host, firings.len() as u64);
+                    last_match.insert(firings.len(), now);
+                    firings.push(Firing { host, started_at: now, resolved_at: None, messages });
+                }
+                Transition::Append { alert, message } => {
+                    last_match.insert(alert as usize, now);
+                    firings[alert as usize].messages.push(message);
+                }
+                Transition::Resolve { alert, .. } => {
+                    firings[alert as usize].resolved_at = Some(last_match[&(alert as usize)] + window);
+                }
+            }
+        }
+    }
+    firings
+}
+
+pub async fn stored_rules(con: &mut MultiplexedConnection) -> Result<Vec<StoredRule>> {
+    let results = con
+        .graph_ro_query(
+            database::GRAPH_NAME,
+            "MATCH (r:AlertRule) RETURN r.definition as definition, r.enabled as enabled ORDER BY r.name",
+        )
+        .await?;
+    let mut rules = vec![];
+    for result in results.data {
+        let definition: String = result.get_scalar("definition").unwrap_or_default();
+        rules.push(StoredRule {
+            rule: serde_json::from_str(&definition)?,
+            enabled: database::get_bool(&result, "enabled"),
+        });
+    }
+    Ok(rules)
+}
+
+/// Creates or replaces the rule with the same name
+pub async fn save_rule(con: &mut MultiplexedConnection, rule: &StoredRule) -> Result<()> {
+    let query = format!(
+        "MERGE (r:AlertRule {{name: '{name}'}}) SET r.definition = '{definition}', r.enabled = {enabled}",
+        name = escape(&rule.rule.name),
+        definition = escape(&serde_json::to_string(&rule.rule)?),
+        enabled = rule.enabled,
+    );
+    con.graph_query(database::GRAPH_NAME, query).await?;
+    Ok(())
+}
+
+/// Returns false when there is no such rule
+pub async fn enable_rule(con: &mut MultiplexedConnection, name: &str, enabled: bool) -> Result<bool> {
+    let query = format!(
+        "MATCH (r:AlertRule {{name: '{name}'}}) SET r.enabled = {enabled} RETURN r.name as name",
+        name = escape(name)
+    );
+    Ok(!con.graph_query(database::GRAPH_NAME, query).await?.data.is_empty())
+}
+
+pub async fn delete_rule(con: &mut MultiplexedConnection, name: &str) -> Result<bool> {
+    let query = format!(
+        "MATCH (r:AlertRule {{name: '{name}'}}) DELETE r RETURN count(r) as deleted",
+        name = escape(name)
+    );
+    let result = con.graph_query(database::GRAPH_NAME, query).await?;
+    Ok(result.data.first().and_then(|r| r.get_scalar::<u64>("deleted")).unwrap_or(0) > 0)
+}
+
+/// Silences that haven't ended by `now`
+pub async fn silences(con: &mut MultiplexedConnection, now: u64) -> Result<Vec<Silence>> {
+    query_silences(con, &format!("s.ends_at > {now}")).await
+}
+
+pub async fn silence(con: &mut MultiplexedConnection, id: u64) -> Result<Option<Silence>> {
+    Ok(query_silences(con, &format!("ID(s) = {id}")).await?.pop())
+}
+
+async fn query_silences(con: &mut MultiplexedConnection, condition: &str) -> Result<Vec<Silence>> {
+    let query = format!(
+        "MATCH (s:Silence) WHERE {condition} RETURN ID(s) as id, s.matcher as matcher, s.starts_at as starts_at, s.ends_at as ends_at, s.comment as comment ORDER BY s.starts_at"
+    );
+    let mut silences = vec![];
+    for result in con.graph_ro_query(database::GRAPH_NAME, query).await?.data {
+        let matcher: String = result.get_scalar("matcher").unwrap_or_default();
+        silences.push(Silence {
+            id: result.get_scalar("id").unwrap_or_default(),
+            matcher: serde_json::from_str(&matcher)?,
+            starts_at: result.get_scalar("starts_at").unwrap_or_default(),
+            ends_at: result.get_scalar("ends_at").unwrap_or_default(),
+            duration: None,
+            comment: result.get_scalar("comment"),
+        });
+    }
+    Ok(silences)
+}
+
+/// Stores a normalized silence, returning its id
+pub async fn create_silence(con: &mut MultiplexedConnection, silence: &Silence) -> Result<u64> {
+    let query = format!(
+        "CREATE (s:Silence {{matcher: '{matcher}', starts_at: {starts_at}, ends_at: {ends_at}, comment: {comment}}}) RETURN ID(s) as id",
+        matcher = escape(&serde_json::to_string(&silence.matcher)?),
+        starts_at = silence.starts_at,
+        ends_at = silence.ends_at,
+        comment = silence
+            .comment
+            .as_deref()
+            .map(|c| format!("'{}'", escape(c)))
+            .unwrap_or_else(|| "null".to_string()),
+    );
+    let result = con.graph_query(database::GRAPH_NAME, query).await?;
+    result
+        .data
+        .first()
+        .and_then(|r| r.get_scalar("id"))
+        .context("Created silence returned no node id")
+}
+
+pub async fn delete_silence(con: &mut MultiplexedConnection, id: u64) -> Result<bool> {
+    let query = format!("MATCH (s:Silence) WHERE ID(s) = {id} DELETE s RETURN count(s) as deleted");
+    let result = con.graph_query(database::GRAPH_NAME, query).await?;
+    Ok(result.data.first().and_then(|r| r.get_scalar::<u64>("deleted")).unwrap_or(0) > 0)
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlertRecord {
+    pub id: u64,
+    pub rule: String,
+    pub host: String,
+    pub state: String,
+    pub started_at: u64,
+    pub last_match: Option<u64>,
+    pub count: Option<u64>,
+    pub resolved_at: Option<u64>,
+    pub silenced: bool,
+    pub acknowledged_at: Option<u64>,
+    pub acknowledged_by: Option<String>,
+}
+
+impl AlertRecord {
+    fn from_result(result: &GraphResult) -> Option<AlertRecord> {
+        Some(AlertRecord {
+            id: result.get_scalar("id")?,
+            rule: result.get_scalar("rule")?,
+            host: result.get_scalar("host")?,
+            state: result.get_scalar("state")?,
+            started_at: result.get_scalar("started_at").unwrap_or_default(),
+            last_match: result.get_scalar("last_match"),
+            count: result.get_scalar("count"),
+            resolved_at: result.get_scalar("resolved_at"),
+            silenced: database::get_bool(result, "silenced"),
+            acknowledged_at: result.get_scalar("acknowledged_at"),
+            acknowledged_by: result.get_scalar("acknowledged_by"),
+        })
+    }
+}
+
+/// The newest alerts, optionally only those in `state`
+pub async fn alerts(con: &mut MultiplexedConnection, state: Option<&str>, limit: usize) -> Result<Vec<AlertRecord>> {
+    let filter = state
+        .map(|state| format!("WHERE alert.state = '{}'", escape(state)))
+        .unwrap_or_default();
+    query_alerts(con, &filter, limit).await
+}
+
+pub async fn alert(con: &mut MultiplexedConnection, id: u64) -> Result<Option<AlertRecord>> {
+    Ok(query_alerts(con, &format!("WHERE ID(alert) = {id}"), 1).await?.pop())
+}
+
+async fn query_alerts(con: &mut MultiplexedConnection, filter: &str, limit: usize) -> Result<Vec<AlertRecord>> {
+    let query = format!(
+        "
+        MATCH (alert:Alert) {filter}
+        RETURN ID(alert) as id, alert.rule as rule, alert.host as host, alert.state as state, alert.started_at as started_at, alert.last_match as last_match, alert.count as count, alert.resolved_at as resolved_at, alert.silenced as silenced, alert.acknowledged_at as acknowledged_at, alert.acknowledged_by as acknowledged_by
+        ORDER BY started_at DESC LIMIT {limit}
+        "
+    );
+    let results = con.graph_ro_query(database::GRAPH_NAME, query).await?;
+    Ok(results.data.iter().filter_map(AlertRecord::from_result).collect())
+}
+
+/// Marks an alert as seen by someone, acknowledged alerts don't send a notification when they resolve
+/// Acknowledges a firing alert, `false` when there is no such alert or it isn't firing
+pub async fn acknowledge(con: &mut MultiplexedConnection, id: u64, by: Option<&str>, now: u64) -> Result<bool> {
+    let query = format!(
+        "MATCH (alert:Alert) WHERE ID(alert) = {id} AND alert.state = 'firing' SET alert.acknowledged_at = {now}, alert.acknowledged_by = {by} RETURN ID(alert) as id",
+        by = by
+            .map(|by| format!("'{}'", escape(by)))
+            .unwrap_or_else(|| "null".to_string()),
+    );
+    Ok(!con.graph_query(database::GRAPH_NAME, query).await?.data.is_empty())
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Unable to get system time")
+        .as_millis() as u64
+}
+
+async fn restore(con: &mut MultiplexedConnection, engine: &mut Engine) -> Result<()> {
+    let results = con
+        .graph_ro_query(
+            database::GRAPH_NAME,
+            format!(
+                "MATCH (alert:Alert) WHERE alert.state = 'firing' AND alert.rule <> '{}' RETURN ID(alert) as id, alert.rule as rule, alert.host as host, alert.last_match as last_match",
+                watchdog::SILENT_RULE
+            ),
+        )
+        .await?;
+    for result in results.data {
+        if let (Some(id), Some(rule), Some(host)) = (
+            result.get_scalar("id"),
+            result.get_scalar("rule"),
+            result.get_scalar("host"),
+        ) {
+            engine.restore(rule, host, id, result.get_scalar("last_match").unwrap_or(0));
+        }
+    }
+    Ok(())
+}
+
+/// Stores a transition, returning the alert's node id and whether it was silenced or acknowledged
+async fn apply(
+    con: &mut MultiplexedConnection,
+    transition: &Transition,
+    now: u64,
+    silenced: bool,
+) -> Result<Option<GraphResult>> {
+    let query = match transition {
+        Transition::Fire { rule, host, messages } => format!(
+            "
+            MATCH (msg:Message) WHERE ID(msg) IN [{ids}]
+            WITH collect(msg) as msgs
+            WHERE size(msgs) > 0
+            CREATE (alert:Alert {{rule: '{rule}', host: '{host}', state: 'firing', started_at: {now}, last_match: {now}, count: {count}, silenced: {silenced}}})
+            WITH alert, msgs
+            UNWIND msgs as msg
+            CREATE (alert)-[:triggered_by]->(msg)
+            RETURN DISTINCT ID(alert) as id
+            ",
+            rule = escape(rule),
+            host = escape(host),
+            count = messages.len(),
+            ids = messages.iter().map(usize::to_string).collect::<Vec<_>>().join(","),
+        ),
+        Transition::Append { alert, message } => format!(
+            "
+            MATCH (alert:Alert), (msg:Message) WHERE ID(alert) = {alert} AND ID(msg) = {message}
+            CREATE (alert)-[:triggered_by]->(msg)
+            SET alert.count = alert.count + 1, alert.last_match = {now}
+            "
+        ),
+        Transition::Resolve { alert, .. } => format!(
+            "
+            MATCH (alert:Alert) WHERE ID(alert) = {alert}
+            SET alert.state = 'resolved', alert.resolved_at = {now}
+            RETURN ID(alert) as id, alert.silenced as silenced, alert.acknowledged_at as acknowledged_at
+            "
+        ),
+    };
+    let result = con.graph_query(database::GRAPH_NAME, query).await?;
+    Ok(result.data.into_iter().next())
+}
+
+/// Rules from the `EZSYSLOG_ALERT_RULES` file together with the enabled rules stored in the graph, which win
+/// when both have a rule with the same name
+async fn load_all_rules(con: &mut MultiplexedConnection) -> Result<Vec<Rule>> {
+    let mut rules = match rules_path_from_env() {
+        Some(path) => load_rules(&path)?,
+        None => vec![],
+    };
+    for stored in stored_rules(con).await? {
+        rules.retain(|r| r.name != stored.rule.name);
+        if stored.enabled {
+            rules.push(stored.rule);
+        }
+    }
+    Ok(rules)
+}
+
+pub async fn listen(
+    mut shutdown_signal: Receiver<()>,
+    sender: Sender<crate::Signal>,
+) -> Result<()> {
+    info!("Alert engine started");
+    let mut con = database::connect().await?;
+    let mut engine = Engine::new(load_all_rules(&mut con).await?);
+    restore(&mut con, &mut engine).await?;
+    let notifiers = Notifiers::from_env()?;
+    let mut active_silences = silences(&mut con, now_millis()).await?;
+    health::running("alert");
+
+    let mut receiver = sender.subscribe();
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        let now = now_millis();
+        let mut latest = None;
+        let transitions = tokio::select! {
+            _ = shutdown_signal.changed() => {
+                break;
+            },
+            _ = interval.tick() => {
+                // Rules and silences may have been changed over the HTTP API of any instance, the last ones
+                // that loaded are kept while the file or the database has a problem
+                match load_all_rules(&mut con).await {
+                    Ok(rules) => {
+                        if serde_json::to_value(&rules)? != serde_json::to_value(engine.rules())? {
+                            let names: Vec<&str> = rules.iter().map(|r| r.name.as_str()).collect();
+                            let event = audit::Event::system("config.reload", serde_json::json!({ "rules": names }));
+                            if let Err(e) = audit::record(&mut con, &event).await {
+                                error!("Unable to audit reloading rules: {e}");
+                            }
+                        }
+                        engine.set_rules(rules);
+                    }
+                    Err(e) => error!("Unable to reload alert rules, keeping the current ones: {e:#}"),
+                }
+                match silences(&mut con, now).await {
+                    Ok(silences) => active_silences = silences,
+                    Err(e) => error!("Unable to reload silences: {e:#}"),
+                }
+                engine.tick(now)
+            },
+            signal = receiver.recv() => match signal {
+                Ok(crate::Signal::NewMessage(stored)) => {
+                    let transitions = engine.observe(&stored, now);
+                    latest = Some(stored);
+                    transitions
+                }
+                Ok(crate::Signal::Stop) | Err(RecvError::Closed) => break,
+                Err(RecvError::Lagged(count)) => {
+                    warn!(count, "Alert engine fell behind, messages were not checked");
+                    continue;
+                }
+            },
+        };
+        for transition in transitions {
+            let silenced = latest
+                .as_ref()
+                .is_some_and(|stored| active_silences.iter().any(|s| s.mutes(stored, now)));
+            let result = match apply(&mut con, &transition, now, silenced).await {
+                Ok(result) => result,
+                Err(e) => {
+                    error!(?transition, "Unable to store alert: {e:#}");
+                    if let Transition::Fire { rule, host, messages } = &transition {
+                        engine.failed(rule, host, messages, now);
+                    }
+                    continue;
+                }
+            };
+            let alert = result.as_ref().and_then(|r| r.get_scalar::<u64>("id"));
+            match &transition {
+                Transition::Fire { rule, host, .. } => {
+                    let Some(alert) = alert else {
+                        // The matched messages were pruned before the alert was stored
+                        warn!(%rule, %host, "Not firing alert for messages that are gone");
+                        continue;
+                    };
+                    info!(%rule, %host, "Alert firing");
+                    engine.fired(rule, host, alert);
+                    if !silenced {
+                        notifiers.notify(Notification {
+                            alert,
+                            rule: rule.clone(),
+                            host: host.clone(),
+                            state: "firing".to_string(),
+                            timestamp: now,
+                            message: latest.as_ref().map(|stored| stored.msg.msg.clone()),
+                        });
+                    }
+                }
+                Transition::Resolve { alert, rule, host } => {
+                    info!(%rule, %host, "Alert resolved");
+                    let muted = result.as_ref().is_some_and(|r| {
+                        database::get_bool(r, "silenced") || r.get_scalar::<u64>("acknowledged_at").is_some()
+                    });
+                    if !muted {
+                        notifiers.notify(Notification {
+                            alert: *alert,
+                            rule: rule.clone(),
+                            host: host.clone(),
+                            state: "resolved".to_string(),
+                            timestamp: now,
+                            message: None,
+                        });
+                    }
+                }
+                Transition::Append { .. } => {}
+            }
+        }
+    }
+
+    info!("Alert engine stopped");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_rule_name, dry_run, Engine, Firing, Rule, Transition};
+    use crate::{search::Record, syslog::Stored, watchdog};
+    use syslog_loose::parse_message;
+
+    fn stored(id: usize, line: &str) -> Stored {
+        Stored {
+            id,
+            ip: "10.0.0.1".parse().unwrap(),
+            msg: parse_message(line).into(),
+            server_timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn threshold_per_host() {
+        let rule: Rule = serde_json::from_str(
+            r#"{"name": "ssh", "appname": "sshd", "msg": "Failed", "threshold": 2, "window": 60}"#,
+        )
+        .unwrap();
+        let mut engine = Engine::new(vec![rule]);
+        let failed = "<38>Oct 11 22:14:15 a sshd[1]: Failed password";
+
+        assert!(engine.observe(&stored(1, failed), 0).is_empty());
+        // Outside the window of the first match
+        assert!(engine.observe(&stored(2, failed), 61_000).is_empty());
+        assert!(engine.observe(&stored(3, "<38>Oct 11 22:14:15 b sshd[1]: Failed password"), 62_000).is_empty());
+        assert!(engine.observe(&stored(4, "<38>Oct 11 22:14:15 a sshd[1]: Accepted"), 62_000).is_empty());
+        assert_eq!(
+            engine.observe(&stored(5, failed), 62_000),
+            [Transition::Fire { rule: "ssh".to_string(), host: "a".to_string(), messages: vec![2, 5] }]
+        );
+        engine.fired("ssh", "a", 100);
+        assert_eq!(engine.observe(&stored(6, failed), 63_000), [Transition::Append { alert: 100, message: 6 }]);
+
+        assert!(engine.tick(100_000).is_empty());
+        assert_eq!(
+            engine.tick(123_000),
+            [Transition::Resolve { alert: 100, rule: "ssh".to_string(), host: "a".to_string() }]
+        );
+        assert!(engine.tick(200_000).is_empty());
+    }
+
+    #[test]
+    fn failed_fire_retries() {
+        let rule: Rule = serde_json::from_str(r#"{"name": "ssh", "msg": "Failed", "threshold": 2, "window": 60}"#).unwrap();
+        let mut engine = Engine::new(vec![rule]);
+        let failed = "<38>Oct 11 22:14:15 a sshd[1]: Failed password";
+        let fire = || Transition::Fire { rule: "ssh".to_string(), host: "a".to_string(), messages: vec![1, 2] };
+
+        engine.observe(&stored(1, failed), 0);
+        assert_eq!(engine.observe(&stored(2, failed), 1_000), [fire()]);
+        engine.failed("ssh", "a", &[1, 2], 1_000);
+        assert_eq!(engine.tick(10_000), [fire()]);
+        assert!(engine.tick(20_000).is_empty());
+
+        // Given up once the matches are older than the window
+        engine.failed("ssh", "a", &[1, 2], 1_000);
+        assert!(engine.tick(62_000).is_empty());
+    }
+
+    #[test]
+    fn reserved_rule_names() {
+        assert!(check_rule_name("silent").is_ok());
+        assert!(check_rule_name(watchdog::SILENT_RULE).is_err());
+        assert!(check_rule_name("ezsyslog:mine").is_err());
+    }
+
+    #[test]
+    fn restore_leaves_silent_hosts() {
+        let mut engine = Engine::new(vec![]);
+        engine.restore(watchdog::SILENT_RULE.to_string(), "core-router".to_string(), 7, 0);
+        engine.restore("gone".to_string(), "a".to_string(), 8, 0);
+        assert_eq!(
+            engine.tick(10_000),
+            [Transition::Resolve { alert: 8, rule: "gone".to_string(), host: "a".to_string() }]
+        );
+    }
+
+    #[test]
+    fn dry_run_over_records() {
+        let rule: Rule = serde_json::from_str(r#"{"name": "disk", "severity": "err", "facility": "daemon", "window": 60}"#).unwrap();
+        let record = |id: u64, server_timestamp: u64, severity: &str| Record {
+            id,
+            server_timestamp,
+            timestamp: None,
+            msgid: None,
+            msg: "disk full".to_string(),
+            severity: Some(severity.to_string()),
+            facility: Some("daemon".to_string()),
+            hostname: Some("nas".to_string()),
+            appname: None,
+            ip: Some("10.0.0.2".to_string()),
+        };
+        let records = [
+            record(1, 0, "info"),
+            record(2, 1_000, "crit"),
+            record(3, 30_000, "err"),
+            record(4, 200_000, "err"),
+        ];
+        assert_eq!(
+            dry_run(rule, &records),
+            [
+                Firing { host: "nas".to_string(), started_at: 1_000, resolved_at: Some(90_000), messages: vec![2, 3] },
+                Firing { host: "nas".to_string(), started_at: 200_000, resolved_at: None, messages: vec![4] },
+            ]
+        );
+    }
+}
@@ -0,0 +1,432 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::watch::Receiver;
+
+use crate::{
+    database,
+    metrics::METRICS,
+    search::{self, Filter, Record},
+    syslog,
+};
+use anyhow::Result;
+use chrono::{TimeZone, Utc};
+use futures_util::TryStreamExt;
+use redis::aio::MultiplexedConnection;
+use redis_graph::AsyncGraphCommands;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+const DAY: u64 = 86_400_000;
+
+/// Messages archived and deleted at a time
+const BATCH_SIZE: usize = 1000;
+
+const MANIFEST: &str = "manifest.json";
+
+/// Days messages are kept in the graph, from `EZSYSLOG_RETENTION_DAYS`. Zero turns retention off like leaving it
+/// unset, rather than deleting every message.
+pub fn retention_from_env() -> Option<u64> {
+    env::var("EZSYSLOG_RETENTION_DAYS").ok().and_then(|d| d.parse().ok()).filter(|d| *d > 0)
+}
+
+/// One archive file, a day of messages from one host
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Relative to the archive directory
+    pub path: PathBuf,
+    pub host: String,
+    /// Earliest `server_timestamp` in the file
+    pub start: u64,
+    /// Latest `server_timestamp` in the file
+    pub end: u64,
+    pub count: usize,
+    /// Bytes of the file holding these records, anything after it is left over from an append that failed
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+/// Contents of `manifest.json`
+#[derive(Default, Serialize, Deserialize)]
+struct Manifest {
+    entries: Vec<Entry>,
+    /// `(id, server_timestamp)` of the records archived last, which may still be in the graph
+    #[serde(default)]
+    pending: BTreeSet<(u64, u64)>,
+}
+
+/// Manifests used to be only the list of entries
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredManifest {
+    Manifest(Manifest),
+    Entries(Vec<Entry>),
+}
+
+/// Messages that aged out of the graph, as `YYYY/MM/DD/host.ndjson.zst` files of [`Record`]s with a
+/// `manifest.json` listing what each file holds
+pub struct Archive {
+    dir: PathBuf,
+    entries: BTreeMap<PathBuf, Entry>,
+    pending: BTreeSet<(u64, u64)>,
+}
+
+fn key(record: &Record) -> (u64, u64) {
+    (record.id, record.server_timestamp)
+}
+
+/// Hostname, or the address when there is none, made safe to use as a file name
+fn host_of(record: &Record) -> String {
+    let host = record.hostname.as_deref().or(record.ip.as_deref()).unwrap_or("unknown");
+    host.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || ".-_:".contains(c) { c } else { '_' })
+        .collect()
+}
+
+impl Archive {
+    pub fn open(dir: &Path) -> Result<Archive> {
+        fs::create_dir_all(dir)?;
+        let manifest = match fs::read(dir.join(MANIFEST)) {
+            Ok(data) => match serde_json::from_slice(&data)? {
+                StoredManifest::Manifest(manifest) => manifest,
+                StoredManifest::Entries(entries) => Manifest { entries, ..Default::default() },
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Archive {
+            dir: dir.to_path_buf(),
+            entries: manifest.entries.into_iter().map(|e| (e.path.clone(), e)).collect(),
+            pending: manifest.pending,
+        })
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.values()
+    }
+
+    /// Adds records to the files for their day and host, each call appends a zstd frame to a file. Records of the
+    /// last call that weren't [deleted](Archive::deleted) yet are skipped, so retrying a batch doesn't archive it twice.
+    pub fn append(&mut self, records: &[Record]) -> Result<()> {
+        let mut files: BTreeMap<PathBuf, (String, Vec<&Record>)> = BTreeMap::new();
+        for record in records.iter().filter(|r| !self.pending.contains(&key(r))) {
+            let day = Utc.timestamp_millis_opt(record.server_timestamp as i64).unwrap();
+            let host = host_of(record);
+            let path = PathBuf::from(day.format("%Y/%m/%d").to_string()).join(format!("{host}.ndjson.zst"));
+            files.entry(path).or_insert_with(|| (host, vec![])).1.push(record);
+        }
+        for (path, (host, records)) in files {
+            let full = self.dir.join(&path);
+            fs::create_dir_all(full.parent().unwrap())?;
+            let entry = self.entries.entry(path.clone()).or_insert_with(|| Entry {
+                path,
+                host,
+                start: u64::MAX,
+                end: 0,
+                count: 0,
+                size: Some(0),
+            });
+            let file = OpenOptions::new().create(true).append(true).open(&full)?;
+            if let Some(size) = entry.size {
+                file.set_len(size)?;
+            }
+            let mut encoder = zstd::Encoder::new(file, 0)?;
+            for record in &records {
+                serde_json::to_writer(&mut encoder, record)?;
+                encoder.write_all(b"\n")?;
+            }
+            let file = encoder.finish()?;
+            file.sync_all()?;
+
+            entry.size = Some(file.metadata()?.len());
+            for record in records {
+                entry.start = entry.start.min(record.server_timestamp);
+                entry.end = entry.end.max(record.server_timestamp);
+                entry.count += 1;
+            }
+        }
+        self.pending = records.iter().map(key).collect();
+        self.save()
+    }
+
+    /// Notes that archived records are gone from the graph, so they would be archived again if they came back
+    pub fn deleted(&mut self, records: &[Record]) -> Result<()> {
+        for record in records {
+            self.pending.remove(&key(record));
+        }
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let manifest = self.dir.join(MANIFEST);
+        let tmp = manifest.with_extension("tmp");
+        let data = Manifest {
+            entries: self.entries.values().cloned().collect(),
+            pending: self.pending.clone(),
+        };
+        fs::write(&tmp, serde_json::to_vec_pretty(&data)?)?;
+        fs::rename(tmp, manifest)?;
+        Ok(())
+    }
+
+    /// Archived records matching the filter in `(server_timestamp, id)` order, up to its `limit`. Files are read
+    /// earliest first and only until the rest can't hold earlier records, keeping at most `limit` records and a file.
+    pub fn search(&self, filter: &Filter) -> Result<Vec<Record>> {
+        let mut records: Vec<Record> = vec![];
+        let mut entries: Vec<&Entry> = self
+            .entries
+            .values()
+            .filter(|e| filter.start.is_none_or(|s| e.end >= s) && filter.end.is_none_or(|end| e.start <= end))
+            .collect();
+        entries.sort_by_key(|e| e.start);
+        for entry in entries {
+            if let Some(limit) = filter.limit {
+                let full = records.len() >= limit;
+                if full && records.last().is_none_or(|r| r.server_timestamp < entry.start) {
+                    break;
+                }
+            }
+            let decoder = zstd::Decoder::new(File::open(self.dir.join(&entry.path))?)?;
+            for line in BufReader::new(decoder).lines() {
+                let record: Record = serde_json::from_str(&line?)?;
+                if filter.matches(&record) {
+                    records.push(record);
+                }
+            }
+            records.sort_by_key(|r| (r.server_timestamp, r.id));
+            if let Some(limit) = filter.limit {
+                records.truncate(limit);
+            }
+        }
+        Ok(records)
+    }
+}
+
+fn id_list(records: &[Record]) -> String {
+    records.iter().map(|r| r.id.to_string()).collect::<Vec<_>>().join(",")
+}
+
+async fn delete(con: &mut MultiplexedConnection, records: &[Record]) -> Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    let query = format!(
+        "MATCH (node:Message) WHERE ID(node) IN [{}] OPTIONAL MATCH (data:Data)-[:data]->(node) DELETE data, node",
+        id_list(records)
+    );
+    con.graph_query(database::GRAPH_NAME, query).await?;
+    Ok(())
+}
+
+/// Writes messages received before `cutoff` to the archive, when there is one, and deletes them from the graph.
+/// Restored messages are not archived again, they are deleted once restored before `restored_cutoff`.
+pub async fn prune(
+    con: &mut MultiplexedConnection,
+    cutoff: u64,
+    mut archive: Option<&mut Archive>,
+    restored_cutoff: u64,
+) -> Result<usize> {
+    let query = format!(
+        "MATCH (node:Message) WHERE node.restored_at < {restored_cutoff} OPTIONAL MATCH (data:Data)-[:data]->(node) DELETE data, node"
+    );
+    con.graph_query(database::GRAPH_NAME, query).await?;
+
+    let filter = Filter {
+        end: Some(cutoff.saturating_sub(1)),
+        limit: None,
+        ..Default::default()
+    };
+    let mut records = Box::pin(search::stream(con.clone(), filter));
+    let mut batch = vec![];
+    let mut pruned = 0;
+    loop {
+        let record = records.try_next().await?;
+        let done = record.is_none();
+        batch.extend(record);
+        if batch.len() < BATCH_SIZE && !done {
+            continue;
+        }
+        if batch.is_empty() {
+            break;
+        }
+        let query = format!(
+            "MATCH (node:Message) WHERE ID(node) IN [{}] AND node.restored_at IS NOT NULL RETURN ID(node) as id",
+            id_list(&batch)
+        );
+        let restored: Vec<u64> = con
+            .graph_ro_query(database::GRAPH_NAME, query)
+            .await?
+            .data
+            .iter()
+            .filter_map(|r| r.get_scalar("id"))
+            .collect();
+        batch.retain(|r| !restored.contains(&r.id));
+        if let Some(archive) = archive.as_deref_mut() {
+            archive.append(&batch)?;
+        }
+        delete(con, &batch).await?;
+        if let Some(archive) = archive.as_deref_mut() {
+            archive.deleted(&batch)?;
+        }
+        METRICS.retention_deleted.inc_by(batch.len() as u64);
+        pruned += batch.len();
+        batch.clear();
+        if done {
+            break;
+        }
+    }
+    Ok(pruned)
+}
+
+/// Puts archived records back into the graph, marked with `restored_at` so they are deleted again later
+pub async fn restore(con: &mut MultiplexedConnection, records: &[Record], now: u64) -> Result<usize> {
+    for batch in records.chunks(BATCH_SIZE) {
+        let mut ids = vec![];
+        for record in batch {
+            let ip: IpAddr = record
+                .ip
+                .as_deref()
+                .and_then(|ip| ip.parse().ok())
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+            let id = syslog::insert_msg(con, record.to_message(), &ip, record.server_timestamp as u128).await?;
+            ids.push(id.to_string());
+        }
+        let query = format!(
+            "MATCH (node:Message) WHERE ID(node) IN [{}] SET node.restored_at = {now}",
+            ids.join(",")
+        );
+        con.graph_query(database::GRAPH_NAME, query).await?;
+    }
+    Ok(records.len())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Unable to get system time")
+        .as_millis() as u64
+}
+
+/// Hours restored messages stay in the graph, from `EZSYSLOG_RESTORE_HOURS`
+pub fn restore_hours_from_env() -> u64 {
+    env::var("EZSYSLOG_RESTORE_HOURS").ok().and_then(|h| h.parse().ok()).unwrap_or(24)
+}
+
+/// Prunes messages older than `EZSYSLOG_RETENTION_DAYS` every hour, archiving them to `EZSYSLOG_ARCHIVE_DIR`
+/// when it is set
+pub async fn listen(mut shutdown_signal: Receiver<()>) -> Result<()> {
+    let Some(days) = retention_from_env() else {
+        return Ok(());
+    };
+    info!("Retention started");
+    let mut con = database::connect().await?;
+    let retention = days * DAY;
+    let mut archive = match env::var("EZSYSLOG_ARCHIVE_DIR") {
+        Ok(dir) => Some(Archive::open(Path::new(&dir))?),
+        Err(_) => None,
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        tokio::select! {
+            _ = shutdown_signal.changed() => break,
+            _ = interval.tick() => {}
+        }
+        let now = now_millis();
+        let restored_cutoff = now.saturating_sub(restore_hours_from_env() * 60 * 60 * 1000);
+        match prune(&mut con, now.saturating_sub(retention), archive.as_mut(), restored_cutoff).await {
+            Ok(0) => {}
+            Ok(count) => info!(count, "Pruned messages"),
+            Err(e) => error!("Unable to prune messages: {e}"),
+        }
+    }
+
+    info!("Retention stopped");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Archive;
+    use crate::search::{Filter, Record};
+    use std::path::PathBuf;
+
+    #[test]
+    fn append_and_search() {
+        let dir = std::env::temp_dir().join(format!("ezsyslog-archive-{}", std::process::id()));
+        let record = |id: u64, server_timestamp: u64, hostname: &str| Record {
+            id,
+            server_timestamp,
+            timestamp: None,
+            msgid: None,
+            msg: format!("message {id}"),
+            severity: Some("info".to_string()),
+            facility: None,
+            hostname: Some(hostname.to_string()),
+            appname: None,
+            ip: Some("10.0.0.1".to_string()),
+        };
+        let mut archive = Archive::open(&dir).unwrap();
+        archive.append(&[record(1, 1_700_000_000_000, "web/1"), record(2, 1_700_000_001_000, "db")]).unwrap();
+        archive.append(&[record(3, 1_700_000_002_000, "web/1")]).unwrap();
+
+        let archive = Archive::open(&dir).unwrap();
+        let web = archive.entries().find(|e| e.host == "web_1").unwrap();
+        assert_eq!(web.path, PathBuf::from("2023/11/14/web_1.ndjson.zst"));
+        assert_eq!((web.start, web.end, web.count), (1_700_000_000_000, 1_700_000_002_000, 2));
+
+        let filter = Filter { hostname: Some("web".to_string()), ..Default::default() };
+        let ids: Vec<u64> = archive.search(&filter).unwrap().iter().map(|r| r.id).collect();
+        assert_eq!(ids, [1, 3]);
+        let filter = Filter { start: Some(1_700_000_000_500), ..Default::default() };
+        assert_eq!(archive.search(&filter).unwrap().len(), 2);
+        let filter = Filter { limit: Some(2), ..Default::default() };
+        let ids: Vec<u64> = archive.search(&filter).unwrap().iter().map(|r| r.id).collect();
+        assert_eq!(ids, [1, 2]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn retried_batches() {
+        let dir = std::env::temp_dir().join(format!("ezsyslog-archive-retry-{}", std::process::id()));
+        let record = |id: u64| Record {
+            id,
+            server_timestamp: 1_700_000_000_000 + id,
+            timestamp: None,
+            msgid: None,
+            msg: format!("message {id}"),
+            severity: None,
+            facility: None,
+            hostname: Some("web".to_string()),
+            appname: None,
+            ip: None,
+        };
+        let mut archive = Archive::open(&dir).unwrap();
+        archive.append(&[record(1), record(2)]).unwrap();
+        // Deleting failed, the batch comes around again with a newer record
+        let mut archive = Archive::open(&dir).unwrap();
+        archive.append(&[record(1), record(2), record(3)]).unwrap();
+        archive.deleted(&[record(1), record(2), record(3)]).unwrap();
+        let entry = archive.entries().next().unwrap().clone();
+        assert_eq!((entry.start, entry.end, entry.count), (1_700_000_000_001, 1_700_000_000_003, 3));
+
+        // Bytes written by an append that never made it into the manifest are dropped
+        let path = dir.join(&entry.path);
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, b"partial frame").unwrap();
+        archive.append(&[record(4)]).unwrap();
+        let ids: Vec<u64> = archive.search(&Filter::default()).unwrap().iter().map(|r| r.id).collect();
+        assert_eq!(ids, [1, 2, 3, 4]);
+
+        // Manifests that only list entries still open
+        std::fs::write(dir.join("manifest.json"), serde_json::to_vec(&[entry]).unwrap()).unwrap();
+        assert_eq!(Archive::open(&dir).unwrap().entries().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
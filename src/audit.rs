@@ -0,0 +1,220 @@
+use std::{env, fmt::Write, time::Duration};
+
+use crate::{
+    alert::now_millis,
+    auth::{Identity, Method},
+    database,
+    utils::escape,
+};
+use anyhow::Result;
+use redis::aio::MultiplexedConnection;
+use redis_graph::{AsyncGraphCommands, GraphResult};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::watch::Receiver;
+
+/// Audit events are kept in their own graph so they can't be read with the `/search` Cypher of the message graph
+/// and have their own retention
+pub const GRAPH_NAME: &str = "syslog_audit";
+
+const DAY: u64 = 86_400_000;
+
+/// Days audit events are kept, from `EZSYSLOG_AUDIT_RETENTION_DAYS`
+pub fn retention_from_env() -> u64 {
+    env::var("EZSYSLOG_AUDIT_RETENTION_DAYS").ok().and_then(|d| d.parse().ok()).unwrap_or(365)
+}
+
+/// Something a user did, stored as an `AuditEvent` node
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    #[serde(default)]
+    pub id: u64,
+    /// Milliseconds since the epoch
+    pub timestamp: u64,
+    pub user: String,
+    pub method: Method,
+    pub ip: Option<String>,
+    /// e.g. `export` or `rules.create`
+    pub action: String,
+    /// Query string, path and body of the request
+    pub params: Value,
+    /// HTTP status of the response
+    pub status: Option<u16>,
+}
+
+impl Event {
+    pub fn new(identity: &Identity, ip: Option<String>, action: &str, params: Value) -> Event {
+        Event {
+            id: 0,
+            timestamp: now_millis(),
+            user: identity.name.clone(),
+            method: identity.method,
+            ip,
+            action: action.to_string(),
+            params,
+            status: None,
+        }
+    }
+
+    /// Something the server did by itself, such as picking up changed alert rules
+    pub fn system(action: &str, params: Value) -> Event {
+        let identity = Identity {
+            name: "system".to_string(),
+            ..Identity::anonymous()
+        };
+        Event::new(&identity, None, action, params)
+    }
+
+    fn from_result(result: &GraphResult) -> Option<Event> {
+        let method: String = result.get_scalar("method")?;
+        Some(Event {
+            id: result.get_scalar("id")?,
+            timestamp: result.get_scalar("timestamp")?,
+            user: result.get_scalar("user")?,
+            method: serde_json::from_value(Value::String(method)).ok()?,
+            ip: result.get_scalar("ip"),
+            action: result.get_scalar("action")?,
+            params: result
+                .get_scalar::<String>("params")
+                .and_then(|p| serde_json::from_str(&p).ok())
+                .unwrap_or_default(),
+            status: result.get_scalar("status"),
+        })
+    }
+}
+
+fn create_query(event: &Event) -> Result<String> {
+    let method = serde_json::to_value(event.method)?;
+    Ok(format!(
+        "CREATE (:AuditEvent {{timestamp: {timestamp}, user: '{user}', method: '{method}', ip: {ip}, action: '{action}', params: '{params}', status: {status}}})",
+        timestamp = event.timestamp,
+        user = escape(&event.user),
+        method = method.as_str().unwrap_or_default(),
+        ip = event.ip.as_ref().map(|ip| format!("'{}'", escape(ip))).unwrap_or_else(|| "null".to_string()),
+        action = escape(&event.action),
+        params = escape(&event.params.to_string()),
+        status = event.status.map(|s| s.to_string()).unwrap_or_else(|| "null".to_string()),
+    ))
+}
+
+pub async fn record(con: &mut MultiplexedConnection, event: &Event) -> Result<()> {
+    con.graph_query(GRAPH_NAME, create_query(event)?).await?;
+    Ok(())
+}
+
+/// Audit event search, newest first
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct Filter {
+    pub user: Option<String>,
+    /// Actions starting with this, so `rules` finds every rule change
+    pub action: Option<String>,
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl Filter {
+    fn to_query(&self) -> String {
+        let mut conditions = vec![];
+        if let Some(user) = &self.user {
+            conditions.push(format!("event.user = '{}'", escape(user)));
+        }
+        if let Some(action) = &self.action {
+            conditions.push(format!("event.action STARTS WITH '{}'", escape(action)));
+        }
+        if let Some(start) = self.start {
+            conditions.push(format!("event.timestamp >= {start}"));
+        }
+        if let Some(end) = self.end {
+            conditions.push(format!("event.timestamp <= {end}"));
+        }
+        let mut query = "MATCH (event:AuditEvent)".to_string();
+        if !conditions.is_empty() {
+            write!(query, " WHERE {}", conditions.join(" AND ")).unwrap();
+        }
+        write!(
+            query,
+            " RETURN ID(event) as id, event.timestamp as timestamp, event.user as user, event.method as method, event.ip as ip, event.action as action, event.params as params, event.status as status ORDER BY timestamp DESC, id DESC LIMIT {}",
+            self.limit.unwrap_or(1000)
+        )
+        .unwrap();
+        query
+    }
+}
+
+pub async fn events(con: &mut MultiplexedConnection, filter: &Filter) -> Result<Vec<Event>> {
+    let results = match con.graph_ro_query(GRAPH_NAME, filter.to_query()).await {
+        Ok(results) => results,
+        // Nothing has been audited yet
+        Err(e) if e.to_string().contains("empty key") => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(results.data.iter().filter_map(Event::from_result).collect())
+}
+
+/// Deletes audit events older than `EZSYSLOG_AUDIT_RETENTION_DAYS` every hour
+pub async fn listen(mut shutdown_signal: Receiver<()>) -> Result<()> {
+    let mut con = database::connect().await?;
+    match con.graph_query(GRAPH_NAME, "CREATE INDEX ON :AuditEvent(timestamp)").await {
+        Err(e) if !e.to_string().contains("already indexed") => return Err(e.into()),
+        _ => {}
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        tokio::select! {
+            _ = shutdown_signal.changed() => break,
+            _ = interval.tick() => {}
+        }
+        let cutoff = now_millis().saturating_sub(retention_from_env() * DAY);
+        let query = format!("MATCH (event:AuditEvent) WHERE event.timestamp < {cutoff} DELETE event");
+        if let Err(e) = con.graph_query(GRAPH_NAME, query).await {
+            tracing::error!("Unable to delete old audit events: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_query, Event, Filter};
+    use crate::auth::Identity;
+
+    #[test]
+    fn filter_query() {
+        let filter = Filter {
+            user: Some("o'brien".to_string()),
+            action: Some("rules".to_string()),
+            start: Some(1),
+            ..Default::default()
+        };
+        let query = filter.to_query();
+        assert!(query.starts_with("MATCH (event:AuditEvent) WHERE event.user = 'o\\'brien' AND event.action STARTS WITH 'rules' AND event.timestamp >= 1 RETURN"));
+        assert!(query.ends_with("ORDER BY timestamp DESC, id DESC LIMIT 1000"));
+    }
+
+    /// The string literal following `key: '` in a query, unescaped as Cypher reads it
+    fn literal(query: &str, key: &str) -> String {
+        let start = query.find(&format!("{key}: '")).unwrap() + key.len() + 3;
+        let mut value = String::new();
+        let mut chars = query[start..].chars();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' => value.push(chars.next().unwrap()),
+                '\'' => break,
+                ch => value.push(ch),
+            }
+        }
+        value
+    }
+
+    #[test]
+    fn create_round_trip() {
+        let identity = Identity { name: "o'brien\\".to_string(), ..Identity::anonymous() };
+        let params = serde_json::json!({ "msg": "C:\\temp\\'x'" });
+        let query = create_query(&Event::new(&identity, None, "search", params.clone())).unwrap();
+        assert_eq!(literal(&query, "user"), "o'brien\\");
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&literal(&query, "params")).unwrap(), params);
+    }
+}
@@ -0,0 +1,570 @@
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    net::IpAddr,
+    str::FromStr,
+    sync::LazyLock,
+};
+
+use crate::{
+    access::{self, Grant, HostGroup, Role, Scope},
+    database,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use blake2::{Blake2b512, Digest};
+use poem::{http::HeaderMap, Request};
+use rand::RngCore;
+use redis::{aio::MultiplexedConnection, AsyncCommands};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use url::Url;
+
+/// Cookie holding the session id of a logged in user
+pub const SESSION_COOKIE: &str = "ezsyslog_session";
+/// Holds the `state` of an OIDC login until the issuer sends the browser back
+pub const OIDC_STATE_COOKIE: &str = "ezsyslog_oidc_state";
+
+/// Seconds an OIDC login may take between leaving for the issuer and coming back
+const OIDC_STATE_TTL: usize = 600;
+
+/// How a request was authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Method {
+    /// Authentication is turned off
+    Anonymous,
+    Token,
+    Password,
+    Proxy,
+    Oidc,
+}
+
+/// Who is making a request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Identity {
+    pub name: String,
+    pub method: Method,
+    /// Looked up from the settings on every request, so changes apply to sessions that already exist
+    #[serde(default)]
+    pub role: Role,
+}
+
+impl Identity {
+    /// Everyone is an admin while authentication is off
+    pub fn anonymous() -> Identity {
+        Identity {
+            name: "anonymous".to_string(),
+            method: Method::Anonymous,
+            role: Role::Admin,
+        }
+    }
+
+    fn new(name: &str, method: Method) -> Identity {
+        Identity {
+            name: name.to_string(),
+            method,
+            role: Role::default(),
+        }
+    }
+}
+
+/// An IPv4 or IPv6 network such as `10.0.0.0/8`, a plain address is a network of one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // Listening on `::` reports IPv4 clients as IPv4 mapped IPv6 addresses
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Cidr> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr.parse().map_err(|_| anyhow!("Invalid network {s}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max,
+            prefix => prefix.parse().ok().filter(|p| *p <= max).ok_or_else(|| anyhow!("Invalid network {s}"))?,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A static API token sent as `Authorization: Bearer <token>`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Token {
+    pub name: String,
+    pub token: String,
+}
+
+/// A local user, `password` is an argon2 hash from `ezsyslog hash-password`
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    pub username: String,
+    pub password: String,
+}
+
+fn default_proxy_header() -> String {
+    "X-Forwarded-User".to_string()
+}
+
+/// A reverse proxy that authenticates users itself and passes on their name in a header
+#[derive(Debug, Clone, Deserialize)]
+pub struct Proxy {
+    #[serde(default = "default_proxy_header")]
+    pub header: String,
+    /// Addresses of the proxies, the header is ignored on requests from anywhere else
+    pub trusted: Vec<Cidr>,
+}
+
+fn default_scopes() -> String {
+    "openid profile email".to_string()
+}
+
+fn default_username_claim() -> String {
+    "preferred_username".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Oidc {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where the issuer sends users back to, `/auth/oidc/callback` on this server
+    pub redirect_url: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: String,
+    /// Userinfo claim used as the user name, `sub` when it is missing
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+}
+
+/// Endpoints from the issuer's `/.well-known/openid-configuration`
+#[derive(Debug, Deserialize)]
+pub struct Discovery {
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+impl Oidc {
+    pub async fn discover(&self, client: &reqwest::Client) -> Result<Discovery> {
+        let url = format!("{}/.well-known/openid-configuration", self.issuer.trim_end_matches('/'));
+        Ok(client.get(url).send().await?.error_for_status()?.json().await?)
+    }
+
+    /// Where to send the user to log in
+    pub fn authorize_url(&self, discovery: &Discovery, state: &str) -> Result<Url> {
+        let mut url = Url::parse(&discovery.authorization_endpoint)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", &self.scopes)
+            .append_pair("state", state);
+        Ok(url)
+    }
+
+    /// Trades the code the issuer sent the user back with for who they are, from the userinfo endpoint
+    pub async fn exchange(&self, client: &reqwest::Client, code: &str) -> Result<Identity> {
+        let discovery = self.discover(client).await?;
+        let token: TokenResponse = client
+            .post(discovery.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_url),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let userinfo: Value = client
+            .get(discovery.userinfo_endpoint)
+            .bearer_auth(token.access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let name = [self.username_claim.as_str(), "sub"]
+            .iter()
+            .find_map(|claim| userinfo.get(claim).and_then(Value::as_str))
+            .ok_or_else(|| anyhow!("Userinfo has neither {} nor sub", self.username_claim))?;
+        Ok(Identity::new(name, Method::Oidc))
+    }
+}
+
+fn default_session_ttl() -> usize {
+    24 * 60 * 60
+}
+
+/// Ways of authenticating to the HTTP API, read from the JSON file in `EZSYSLOG_AUTH`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub tokens: Vec<Token>,
+    #[serde(default)]
+    pub users: Vec<User>,
+    pub proxy: Option<Proxy>,
+    pub oidc: Option<Oidc>,
+    /// Seconds a login lasts
+    #[serde(default = "default_session_ttl")]
+    pub session_ttl: usize,
+    /// Only send the session cookie over HTTPS
+    #[serde(default)]
+    pub secure_cookie: bool,
+    /// Named groups of hosts that access can be limited to
+    #[serde(default)]
+    pub groups: HashMap<String, HostGroup>,
+    /// Role and host groups by user or token name
+    #[serde(default)]
+    pub access: HashMap<String, Grant>,
+    /// For everyone not in `access`, a viewer of no hosts unless set
+    #[serde(default = "default_grant")]
+    pub default_access: Grant,
+}
+
+fn default_grant() -> Grant {
+    Grant {
+        role: Role::Viewer,
+        groups: Some(vec![]),
+    }
+}
+
+/// Compares digests of both without returning early, so the time taken gives away neither how much of a token
+/// was right nor its length
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (Blake2b512::digest(a), Blake2b512::digest(b));
+    a.iter().zip(b.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+impl Config {
+    /// `None` when `EZSYSLOG_AUTH` is not set and the API is open to everyone
+    pub fn from_env() -> Result<Option<Config>> {
+        let path = match env::var("EZSYSLOG_AUTH") {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        let data = fs::read(&path).with_context(|| format!("Unable to read authentication settings from {path}"))?;
+        let config: Config = serde_json::from_slice(&data)?;
+        let grants: Vec<&Grant> = config.access.values().chain([&config.default_access]).collect();
+        access::validate(&config.groups, &grants)?;
+        if !config.users.is_empty() {
+            // Hashed now rather than while answering the first login of an unknown user
+            LazyLock::force(&DUMMY_HASH);
+        }
+        Ok(Some(config))
+    }
+
+    /// Sets the identity's role, returning the hosts it may see or `None` for every host
+    pub fn grant(&self, identity: &mut Identity) -> Option<Scope> {
+        let grant = self.access.get(&identity.name).unwrap_or(&self.default_access);
+        identity.role = grant.role;
+        if grant.role == Role::Admin {
+            return None;
+        }
+        let groups: Vec<&HostGroup> = grant.groups.as_ref()?.iter().filter_map(|g| self.groups.get(g)).collect();
+        Some(Scope::new(&groups))
+    }
+
+    pub fn token(&self, token: &str) -> Option<Identity> {
+        self.tokens
+            .iter()
+            .find(|t| constant_time_eq(t.token.as_bytes(), token.as_bytes()))
+            .map(|t| Identity::new(&t.name, Method::Token))
+    }
+
+    /// Unknown users are checked against a dummy hash, so they take as long to refuse as a wrong password
+    pub fn password(&self, username: &str, password: &str) -> Option<Identity> {
+        let user = self.users.iter().find(|u| u.username == username);
+        let hash = PasswordHash::new(user.map_or(DUMMY_HASH.as_str(), |u| &u.password)).ok()?;
+        let verified = Argon2::default().verify_password(password.as_bytes(), &hash).is_ok();
+        let user = user.filter(|_| verified)?;
+        Some(Identity::new(&user.username, Method::Password))
+    }
+
+    pub fn proxy(&self, headers: &HeaderMap, remote: Option<IpAddr>) -> Option<Identity> {
+        let proxy = self.proxy.as_ref()?;
+        let remote = remote?;
+        if !proxy.trusted.iter().any(|net| net.contains(&remote)) {
+            return None;
+        }
+        let name = headers.get(proxy.header.as_str())?.to_str().ok()?.trim();
+        (!name.is_empty()).then(|| Identity::new(name, Method::Proxy))
+    }
+
+    /// `Set-Cookie` value starting a session for the UI at `path`, or ending it when `id` is empty
+    pub fn session_cookie(&self, id: &str, path: &str) -> String {
+        let max_age = if id.is_empty() { 0 } else { self.session_ttl };
+        let secure = if self.secure_cookie { "; Secure" } else { "" };
+        format!("{SESSION_COOKIE}={id}; Path={path}; HttpOnly; SameSite=Lax; Max-Age={max_age}{secure}")
+    }
+
+    /// `Set-Cookie` value tying an OIDC login to the browser that started it, or clearing it when `state` is empty
+    pub fn oidc_state_cookie(&self, state: &str, path: &str) -> String {
+        let max_age = if state.is_empty() { 0 } else { OIDC_STATE_TTL };
+        let secure = if self.secure_cookie { "; Secure" } else { "" };
+        format!("{OIDC_STATE_COOKIE}={state}; Path={path}; HttpOnly; SameSite=Lax; Max-Age={max_age}{secure}")
+    }
+}
+
+/// Hash of a random password, never matched
+static DUMMY_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password(&random_id()).expect("Unable to hash the dummy password"));
+
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| anyhow!("Unable to hash password: {e}"))
+}
+
+/// A hard to guess id for sessions and OIDC logins
+pub fn random_id() -> String {
+    let mut bytes = [0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all("Cookie")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .find_map(|c| c.trim().strip_prefix(name)?.strip_prefix('='))
+}
+
+fn session_key(id: &str) -> String {
+    format!("{}:session:{id}", database::GRAPH_NAME)
+}
+
+fn oidc_state_key(state: &str) -> String {
+    format!("{}:oidc:{state}", database::GRAPH_NAME)
+}
+
+/// Stores a session in Redis so it is shared by every instance, returning its id
+pub async fn create_session(con: &mut MultiplexedConnection, identity: &Identity, ttl: usize) -> Result<String> {
+    let id = random_id();
+    con.set_ex::<_, _, ()>(session_key(&id), serde_json::to_string(identity)?, ttl).await?;
+    Ok(id)
+}
+
+pub async fn session(con: &mut MultiplexedConnection, id: &str) -> Result<Option<Identity>> {
+    let data: Option<String> = con.get(session_key(id)).await?;
+    Ok(data.and_then(|d| serde_json::from_str(&d).ok()))
+}
+
+pub async fn delete_session(con: &mut MultiplexedConnection, id: &str) -> Result<()> {
+    con.del::<_, ()>(session_key(id)).await?;
+    Ok(())
+}
+
+/// Remembers an OIDC login that was started here, so the callback can't be forged
+pub async fn start_oidc(con: &mut MultiplexedConnection) -> Result<String> {
+    let state = random_id();
+    con.set_ex::<_, _, ()>(oidc_state_key(&state), 1, OIDC_STATE_TTL).await?;
+    Ok(state)
+}
+
+/// The callback's `state` must be the one in the cookie of the browser that started the login, or anyone could
+/// send a victim a callback URL that logs them into the sender's account
+fn check_state(state: &str, cookie: Option<&str>) -> Result<()> {
+    if !cookie.is_some_and(|c| constant_time_eq(c.as_bytes(), state.as_bytes())) {
+        bail!("Login was not started in this browser");
+    }
+    Ok(())
+}
+
+pub async fn finish_oidc(con: &mut MultiplexedConnection, state: &str, cookie: Option<&str>) -> Result<()> {
+    check_state(state, cookie)?;
+    let removed: usize = con.del(oidc_state_key(state)).await?;
+    if removed == 0 {
+        bail!("Unknown or expired login");
+    }
+    Ok(())
+}
+
+/// Who made the request, from a bearer token, a trusted proxy's header or a session cookie
+pub async fn authenticate(config: &Config, con: &mut MultiplexedConnection, req: &Request) -> Result<Option<Identity>> {
+    let headers = req.headers();
+    if let Some(token) = headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+    {
+        return Ok(config.token(token.trim()));
+    }
+    let remote = req.remote_addr().as_socket_addr().map(|addr| addr.ip());
+    if let Some(identity) = config.proxy(headers, remote) {
+        return Ok(Some(identity));
+    }
+    match cookie(headers, SESSION_COOKIE) {
+        Some(id) => session(con, id).await,
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_state, cookie, hash_password, Cidr, Config, Identity, Method};
+    use crate::access::Role;
+    use poem::http::HeaderMap;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn methods() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "tokens": [{ "name": "ci", "token": "s3cret" }],
+            "users": [{ "username": "alice", "password": hash_password("hunter2").unwrap() }],
+            "proxy": { "trusted": ["10.0.0.0/8", "::1"] },
+            "groups": { "network": { "hostnames": ["sw-*"] } },
+            "access": { "ci": { "role": "admin", "groups": [] }, "alice": { "role": "operator", "groups": ["network"] } },
+        }))
+        .unwrap();
+        assert_eq!(config.token("s3cret").unwrap().name, "ci");
+        assert!(config.token("s3cre").is_none());
+        assert!(config.token("s3cret2").is_none());
+        assert!(check_state("abc", Some("abc")).is_ok());
+        assert!(check_state("abc", Some("abd")).is_err());
+        assert!(check_state("abc", None).is_err());
+        assert_eq!(config.password("alice", "hunter2").unwrap().method, Method::Password);
+        assert!(config.password("alice", "hunter3").is_none());
+        assert!(config.password("bob", "hunter2").is_none());
+
+        let mut ci = config.token("s3cret").unwrap();
+        assert!(config.grant(&mut ci).is_none());
+        assert_eq!(ci.role, Role::Admin);
+        let mut alice = config.password("alice", "hunter2").unwrap();
+        let scope = config.grant(&mut alice).unwrap();
+        assert_eq!(alice.role, Role::Operator);
+        assert!(scope.allows(Some("sw-1"), None) && !scope.allows(Some("hr-1"), None));
+        let mut mallory = Identity::new("mallory", Method::Proxy);
+        assert!(!config.grant(&mut mallory).unwrap().allows(Some("sw-1"), Some("10.0.0.1")));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-User", "carol".parse().unwrap());
+        headers.insert("Cookie", "theme=dark; ezsyslog_session=abc".parse().unwrap());
+        assert_eq!(config.proxy(&headers, Some("::ffff:10.1.2.3".parse().unwrap())).unwrap().name, "carol");
+        assert!(config.proxy(&headers, Some("192.168.0.1".parse().unwrap())).is_none());
+        assert_eq!(cookie(&headers, "ezsyslog_session"), Some("abc"));
+
+        let net: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(net.contains(&"192.168.4.1".parse().unwrap()));
+        assert!(!net.contains(&"192.169.0.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+
+    #[tokio::test]
+    async fn oidc() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let base = issuer.clone();
+        let server = tokio::spawn(async move {
+            let mut requests = vec![];
+            for _ in 0..3 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 4096];
+                loop {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..len]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|l| l.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse().unwrap()))
+                            .unwrap_or(0);
+                        if body.len() >= length {
+                            break;
+                        }
+                    }
+                }
+                let request = String::from_utf8(request).unwrap();
+                let body = if request.starts_with("GET /.well-known/openid-configuration") {
+                    format!(
+                        r#"{{"authorization_endpoint": "{base}/authorize", "token_endpoint": "{base}/token", "userinfo_endpoint": "{base}/userinfo"}}"#
+                    )
+                } else if request.starts_with("POST /token") {
+                    r#"{"access_token": "at", "token_type": "Bearer"}"#.to_string()
+                } else {
+                    r#"{"sub": "1234", "preferred_username": "dave"}"#.to_string()
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "oidc": { "issuer": issuer, "client_id": "ezsyslog", "client_secret": "shh", "redirect_url": "http://logs/auth/oidc/callback" },
+        }))
+        .unwrap();
+        let oidc = config.oidc.unwrap();
+        let identity = oidc.exchange(&reqwest::Client::new(), "the-code").await.unwrap();
+        assert_eq!((identity.name.as_str(), identity.method), ("dave", Method::Oidc));
+
+        let requests = server.await.unwrap();
+        assert!(requests[1].contains("code=the-code"));
+        assert!(requests[1].contains("client_secret=shh"));
+        assert!(requests[2].to_lowercase().contains("authorization: bearer at"));
+    }
+}
@@ -0,0 +1,239 @@
+//! Forwards local syslog and log files to a central ezsyslog server, buffering them on disk while the server
+//! can't be reached.
+
+use std::{
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ezsyslog::{file, logging, spool::Spool, syslog, tls};
+use syslog_loose::{parse_message, Message};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpStream, UnixDatagram},
+    sync::{watch, Notify},
+    time::{sleep, timeout},
+};
+use tracing::{info, warn};
+
+/// Most messages sent before waiting for them to be acknowledged
+const BATCH_SIZE: usize = 100;
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+struct Agent {
+    spool: Mutex<Spool>,
+    notify: Notify,
+    hostname: String,
+}
+
+impl Agent {
+    fn push(&self, msg: &Message<&str>) -> Result<()> {
+        let line = syslog::format_message(msg, syslog::Format::Rfc5424, Some(&self.hostname));
+        // The server refuses longer frames, which would otherwise stay at the front of the spool forever
+        let line = syslog::truncate_to_frame(&line);
+        if !self.spool.lock().unwrap().push(line.as_bytes())? {
+            warn!("Spool is full, dropping message");
+        }
+        self.notify.notify_one();
+        Ok(())
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+struct Connection {
+    stream: BufReader<Box<dyn Stream>>,
+    /// Messages sent on this connection, the server acknowledges with a running count
+    sent: u64,
+}
+
+struct Upstream {
+    address: String,
+    tls: Option<tokio_rustls::TlsConnector>,
+}
+
+impl Upstream {
+    async fn connect(&self) -> Result<Connection> {
+        let tcp = TcpStream::connect(&self.address).await?;
+        let stream: Box<dyn Stream> = match &self.tls {
+            Some(connector) => {
+                let host = self.address.rsplit_once(':').map_or(self.address.as_str(), |(h, _)| h);
+                let name = host.trim_matches(|c| c == '[' || c == ']').try_into()?;
+                Box::new(connector.connect(name, tcp).await?)
+            }
+            None => Box::new(tcp),
+        };
+        Ok(Connection {
+            stream: BufReader::new(stream),
+            sent: 0,
+        })
+    }
+}
+
+async fn send(connection: &mut Connection, batch: &[Vec<u8>]) -> Result<()> {
+    let mut out = vec![];
+    for record in batch {
+        out.extend(syslog::frame(std::str::from_utf8(record)?));
+    }
+    connection.stream.get_mut().write_all(&out).await?;
+    connection.stream.get_mut().flush().await?;
+    connection.sent += batch.len() as u64;
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if timeout(ACK_TIMEOUT, connection.stream.read_line(&mut line)).await?? == 0 {
+            bail!("Server closed the connection");
+        }
+        if line.trim().parse::<u64>()? >= connection.sent {
+            return Ok(());
+        }
+    }
+}
+
+async fn ship(agent: Arc<Agent>, upstream: Upstream, mut shutdown: watch::Receiver<()>) -> Result<()> {
+    let mut connection = None;
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let batch = agent.spool.lock().unwrap().peek(BATCH_SIZE)?;
+        if batch.is_empty() {
+            tokio::select! {
+                _ = shutdown.changed() => return Ok(()),
+                _ = agent.notify.notified() => {},
+            }
+            continue;
+        }
+
+        let result = async {
+            let connection = match &mut connection {
+                Some(c) => c,
+                None => connection.insert(upstream.connect().await?),
+            };
+            send(connection, &batch).await
+        }
+        .await;
+        match result {
+            Ok(()) => {
+                agent.spool.lock().unwrap().commit(&batch)?;
+                backoff = Duration::from_secs(1);
+            }
+            Err(e) => {
+                warn!(server = %upstream.address, ?backoff, "Unable to send, retrying: {e}");
+                connection = None;
+                tokio::select! {
+                    _ = shutdown.changed() => return Ok(()),
+                    _ = sleep(backoff) => {},
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+async fn listen_socket(agent: Arc<Agent>, path: PathBuf, mut shutdown: watch::Receiver<()>) -> Result<()> {
+    let _ = fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).with_context(|| format!("Unable to bind {}", path.display()))?;
+    // Every local user may log
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o666))?;
+    info!(socket = %path.display(), "Listening");
+
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            res = socket.recv(&mut buf) => {
+                let len = res?;
+                let line = String::from_utf8_lossy(&buf[..len]);
+                agent.push(&parse_message(line.trim_end()))?;
+            }
+        }
+    }
+    let _ = fs::remove_file(&path);
+    Ok(())
+}
+
+async fn follow_files(
+    agent: Arc<Agent>,
+    mut follower: file::Follower,
+    parser: file::Parser,
+    mut shutdown: watch::Receiver<()>,
+) -> Result<()> {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            _ = interval.tick() => {
+                for line in follower.poll()? {
+                    agent.push(&parser.parse(&line))?;
+                }
+                // Safe to remember the offsets once the lines are in the spool
+                follower.save()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn hostname() -> String {
+    env::var("EZSYSLOG_AGENT_HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .unwrap_or("localhost".to_string())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Only the server can store its own messages
+    logging::init()?;
+    let address = env::var("EZSYSLOG_AGENT_SERVER")
+        .map_err(|_| anyhow!("EZSYSLOG_AGENT_SERVER must be set to the host:port of the central server"))?;
+    let tls = match env::var("EZSYSLOG_AGENT_TLS").as_deref() {
+        Ok("1" | "true") => Some(tls::connector(env::var("EZSYSLOG_AGENT_CA").ok().as_deref().map(AsRef::as_ref))?),
+        _ => None,
+    };
+    let spool_dir: PathBuf = env::var("EZSYSLOG_AGENT_SPOOL")
+        .unwrap_or("/var/spool/ezsyslog-agent".to_string())
+        .into();
+    let spool_max: u64 = env::var("EZSYSLOG_AGENT_SPOOL_MAX")
+        .unwrap_or((256 * 1024 * 1024).to_string())
+        .parse()?;
+    let socket = env::var("EZSYSLOG_AGENT_SOCKET").unwrap_or("/dev/log".to_string());
+
+    let agent = Arc::new(Agent {
+        spool: Mutex::new(Spool::open(&spool_dir, spool_max)?),
+        notify: Notify::new(),
+        hostname: hostname(),
+    });
+    let (shutdown, sigint) = watch::channel(());
+
+    let mut handles = vec![tokio::spawn(ship(agent.clone(), Upstream { address, tls }, sigint.clone()))];
+    if !socket.is_empty() {
+        handles.push(tokio::spawn(listen_socket(agent.clone(), socket.into(), sigint.clone())));
+    }
+    if let Some(patterns) = file::patterns_from_env() {
+        let state = env::var("EZSYSLOG_FILE_STATE")
+            .map(PathBuf::from)
+            .unwrap_or(spool_dir.join("files.json"));
+        let follower = file::Follower::new(patterns, state)?;
+        handles.push(tokio::spawn(follow_files(agent.clone(), follower, file::Parser::from_env()?, sigint)));
+    }
+
+    ctrlc::set_handler(move || {
+        info!("Shutting down");
+        shutdown.send(()).expect("Could not propigate SIGINT");
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    for handle in handles {
+        handle.await?.expect("Thread error");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,534 @@
+use std::{
+    fs::File,
+    io::{self, IsTerminal, Write},
+    net::IpAddr,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    archive::{self, Archive},
+    auth, database, export, import, rollup,
+    search::{self, Filter, Page, Record},
+};
+use anyhow::{anyhow, bail, Result};
+use chrono::{Local, TimeZone, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use futures_util::{StreamExt, TryStreamExt};
+
+#[derive(Parser)]
+#[clap(version, about = "A very simple syslog server that stores all data in a redis graph")]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the syslog and HTTP listeners, the default when no command is given
+    Serve,
+    /// Stream new messages from a running server
+    Tail {
+        #[clap(flatten)]
+        server: ServerArgs,
+        #[clap(flatten)]
+        filter: FilterArgs,
+        #[clap(long, value_enum, default_value = "auto")]
+        color: Color,
+    },
+    /// Search stored messages through a running server
+    Query {
+        #[clap(flatten)]
+        server: ServerArgs,
+        #[clap(flatten)]
+        filter: FilterArgs,
+        #[clap(long, short, value_enum, default_value = "table")]
+        format: Format,
+    },
+    /// Store existing log files, including rotated and gzipped ones, or stdin
+    Import {
+        /// Hostname recorded for every message instead of the one parsed from each line
+        #[clap(long)]
+        hostname: Option<String>,
+        /// Address the messages are recorded as coming from
+        #[clap(long, default_value = "127.0.0.1")]
+        address: IpAddr,
+        /// Number of lines stored concurrently
+        #[clap(long, default_value = "500")]
+        batch_size: usize,
+        /// Files to read, `-` for stdin
+        #[clap(default_value = "-")]
+        files: Vec<PathBuf>,
+    },
+    /// Write stored messages to a file as NDJSON, CSV or syslog lines
+    Export {
+        #[clap(flatten)]
+        filter: FilterArgs,
+        /// File to write, stdout when not given. Ending in .gz or .zst compresses it
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+        #[clap(long, short, value_enum, default_value = "ndjson")]
+        format: export::Format,
+        /// Comma separated CSV columns
+        #[clap(long)]
+        columns: Option<String>,
+        /// Compression, picked from the output file name when not given
+        #[clap(long, value_enum)]
+        compression: Option<export::Compression>,
+    },
+    /// Show or apply database schema migrations
+    Migrate {
+        #[clap(subcommand)]
+        action: Option<MigrateAction>,
+    },
+    /// Maintain the per minute, hour and day message counters used for statistics
+    Rollup {
+        #[clap(subcommand)]
+        action: RollupAction,
+    },
+    /// Hash a password read from stdin for a local user in `EZSYSLOG_AUTH`
+    HashPassword,
+    /// Search messages archived by retention or put them back into the graph for a while
+    Archive {
+        #[clap(subcommand)]
+        action: ArchiveAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ArchiveAction {
+    /// Print archived messages
+    Search {
+        #[clap(flatten)]
+        archive: ArchiveArgs,
+        #[clap(flatten)]
+        filter: FilterArgs,
+        #[clap(long, short, value_enum, default_value = "table")]
+        format: Format,
+    },
+    /// Store archived messages in the graph again, until `EZSYSLOG_RESTORE_HOURS` pass
+    Restore {
+        #[clap(flatten)]
+        archive: ArchiveArgs,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+}
+
+#[derive(Args)]
+pub struct ArchiveArgs {
+    /// Directory messages are archived to
+    #[clap(long, env = "EZSYSLOG_ARCHIVE_DIR")]
+    pub dir: PathBuf,
+}
+
+#[derive(Subcommand)]
+pub enum RollupAction {
+    /// Recount stored messages, e.g. ones stored before counters were kept
+    Rebuild {
+        /// Start of the messages to count, in the same format as --since of other commands
+        #[clap(long, value_parser = parse_time, default_value = "7d")]
+        since: u64,
+        #[clap(long, value_parser = parse_time)]
+        until: Option<u64>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum MigrateAction {
+    /// List applied and pending migrations, the default
+    Status,
+    /// Apply all pending migrations
+    Up,
+}
+
+#[derive(Args)]
+pub struct ServerArgs {
+    /// Base URL of the ezsyslog HTTP server
+    #[clap(long, env = "EZSYSLOG_SERVER", default_value = "http://localhost:8000")]
+    pub server: String,
+    /// API token for a server with authentication turned on
+    #[clap(long, env = "EZSYSLOG_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+}
+
+impl ServerArgs {
+    fn get(&self, client: &reqwest::Client, path: &str) -> reqwest::RequestBuilder {
+        let request = client.get(format!("{}{path}", self.server.trim_end_matches('/')));
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct FilterArgs {
+    /// Only messages received after this, a duration ago (30s, 15m, 2h, 7d) or a unix timestamp in milliseconds
+    #[clap(long, value_parser = parse_time)]
+    pub since: Option<u64>,
+    /// Only messages received before this, in the same format as --since
+    #[clap(long, value_parser = parse_time)]
+    pub until: Option<u64>,
+    /// Full text search on the message body
+    #[clap(long)]
+    pub msg: Option<String>,
+    #[clap(long)]
+    pub severity: Option<String>,
+    #[clap(long)]
+    pub facility: Option<String>,
+    #[clap(long)]
+    pub hostname: Option<String>,
+    #[clap(long)]
+    pub appname: Option<String>,
+    #[clap(long)]
+    pub ip: Option<String>,
+    #[clap(long, short = 'n')]
+    pub limit: Option<usize>,
+}
+
+impl From<FilterArgs> for Filter {
+    fn from(args: FilterArgs) -> Self {
+        Filter {
+            start: args.since,
+            end: args.until,
+            msg: args.msg,
+            severity: args.severity,
+            facility: args.facility,
+            hostname: args.hostname,
+            appname: args.appname,
+            ip: args.ip,
+            limit: args.limit,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+    Csv,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Unable to get system time")
+        .as_millis() as u64
+}
+
+fn parse_time(input: &str) -> Result<u64> {
+    let (value, unit) = input.split_at(input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len()));
+    let value: u64 = value.parse().map_err(|_| anyhow!("Invalid time {input}"))?;
+    let seconds = match unit {
+        "" => return Ok(value),
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("Invalid time unit {unit}, expected one of s, m, h or d"),
+    };
+    let ago = value
+        .checked_mul(seconds * 1000)
+        .ok_or_else(|| anyhow!("Time {input} is too far back"))?;
+    Ok(now_millis().saturating_sub(ago))
+}
+
+/// Local time of a `server_timestamp`, or the raw milliseconds when they are out of range
+fn format_timestamp(millis: u64) -> String {
+    match i64::try_from(millis).ok().and_then(|m| Local.timestamp_millis_opt(m).single()) {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => millis.to_string(),
+    }
+}
+
+fn severity_color(severity: Option<&str>) -> &'static str {
+    match severity {
+        Some("emerg" | "alert" | "crit") => "\x1b[1;31m",
+        Some("err") => "\x1b[31m",
+        Some("warning") => "\x1b[33m",
+        Some("notice") => "\x1b[36m",
+        Some("debug") => "\x1b[35m",
+        _ => "",
+    }
+}
+
+fn print_line(record: &Record, color: bool) {
+    let severity = record.severity.as_deref();
+    let (start, end) = match severity_color(severity) {
+        code if color && !code.is_empty() => (code, "\x1b[0m"),
+        _ => ("", ""),
+    };
+    println!(
+        "{start}{time} {host} {app}[{severity}]: {msg}{end}",
+        time = format_timestamp(record.server_timestamp),
+        host = record.hostname.as_deref().or(record.ip.as_deref()).unwrap_or("-"),
+        app = record.appname.as_deref().unwrap_or("-"),
+        severity = severity.unwrap_or("-"),
+        msg = record.msg,
+    );
+}
+
+fn print_table(records: &[Record]) {
+    let rows: Vec<[String; 5]> = records
+        .iter()
+        .map(|r| {
+            [
+                format_timestamp(r.server_timestamp),
+                r.hostname.clone().or_else(|| r.ip.clone()).unwrap_or_default(),
+                r.appname.clone().unwrap_or_default(),
+                r.severity.clone().unwrap_or_default(),
+                r.msg.clone(),
+            ]
+        })
+        .collect();
+    let header = ["TIME", "HOST", "APP", "SEVERITY", "MESSAGE"];
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print_row = |row: [&str; 5]| {
+        println!(
+            "{:w0$}  {:w1$}  {:w2$}  {:w3$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            row[4],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+        )
+    };
+    print_row(header);
+    for row in &rows {
+        print_row([&row[0], &row[1], &row[2], &row[3], &row[4]]);
+    }
+}
+
+pub fn write_csv(records: &[Record], out: impl Write) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    for record in records {
+        writer.serialize(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+async fn query(server: &ServerArgs, filter: &Filter, format: Format) -> Result<()> {
+    let response = server
+        .get(&reqwest::Client::new(), "/messages")
+        .query(filter)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        bail!("Server returned {}: {}", response.status(), response.text().await?);
+    }
+    let records = response.json::<Page>().await?.records;
+    match format {
+        Format::Table => print_table(&records),
+        Format::Json => println!("{}", serde_json::to_string_pretty(&records)?),
+        Format::Csv => write_csv(&records, io::stdout())?,
+    }
+    Ok(())
+}
+
+/// Splits the first complete server-sent event off the front of `buffer`, decoding it only once it is whole so
+/// characters split across chunks survive
+fn next_event(buffer: &mut Vec<u8>) -> Option<String> {
+    let end = buffer.windows(2).position(|w| w == b"\n\n")?;
+    let event: Vec<u8> = buffer.drain(..end + 2).collect();
+    Some(String::from_utf8_lossy(&event).into_owned())
+}
+
+/// The record carried by a `newRecord` event, `None` for keep-alives and other events
+fn event_record(event: &str) -> Option<Result<Record>> {
+    if !event.lines().any(|l| l.strip_prefix("event:").is_some_and(|t| t.trim() == "newRecord")) {
+        return None;
+    }
+    let data: Vec<&str> = event.lines().filter_map(|l| l.strip_prefix("data:")).map(str::trim_start).collect();
+    Some(serde_json::from_str(&data.join("\n")).map_err(Into::into))
+}
+
+async fn tail(server: &ServerArgs, filter: &Filter, color: bool) -> Result<()> {
+    let client = reqwest::Client::new();
+    loop {
+        let response = server
+            .get(&client, "/events?records=true")
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        let mut stream = match response {
+            Ok(response) => response.bytes_stream(),
+            Err(e) => {
+                eprintln!("Unable to connect to {}: {e}, retrying", server.server);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        let mut buffer = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    eprintln!("Error reading from {}: {e}", server.server);
+                    break;
+                }
+            };
+            buffer.extend_from_slice(&chunk);
+            while let Some(event) = next_event(&mut buffer) {
+                match event_record(&event) {
+                    Some(Ok(record)) if filter.matches(&record) => print_line(&record, color),
+                    Some(Err(e)) => eprintln!("Skipping unreadable event: {e}"),
+                    _ => {}
+                }
+            }
+        }
+        eprintln!("Lost connection to {}, reconnecting", server.server);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn export(filter: &Filter, output: Option<PathBuf>, options: export::Options) -> Result<()> {
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let con = database::connect().await?;
+    let records = Box::pin(search::stream(con, filter.clone()));
+    let mut chunks = Box::pin(export::export(records, export::Exporter::new(&options)?));
+    while let Some(chunk) = chunks.try_next().await? {
+        out.write_all(&chunk)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+async fn migrate(action: MigrateAction) -> Result<()> {
+    let mut con = database::connect().await?;
+    match action {
+        MigrateAction::Status => {
+            let applied = database::applied_migrations(&mut con).await?;
+            for (name, _) in database::MIGRATIONS {
+                let state = if applied.iter().any(|a| a == name) { "applied" } else { "pending" };
+                println!("{state:8} {name}");
+            }
+        }
+        MigrateAction::Up => {
+            let applied = database::migrate(&mut con).await?;
+            for name in &applied {
+                println!("Applied {name}");
+            }
+            if applied.is_empty() {
+                println!("Nothing to migrate");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs every command other than `serve`.
+pub async fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Tail { server, filter, color } => {
+            let color = match color {
+                Color::Auto => io::stdout().is_terminal(),
+                Color::Always => true,
+                Color::Never => false,
+            };
+            tail(&server, &filter.into(), color).await
+        }
+        Command::Query { server, filter, format } => {
+            query(&server, &filter.into(), format).await
+        }
+        Command::Import { hostname, address, batch_size, files } => {
+            let options = import::Options { hostname, address, batch_size };
+            let count = import::import(&files, &options).await?;
+            eprintln!("Imported {count} messages");
+            Ok(())
+        }
+        Command::Export { filter, output, format, columns, compression } => {
+            let compression = compression.unwrap_or_else(|| {
+                let name = output.as_ref().map(|p| p.to_string_lossy().to_string()).unwrap_or_default();
+                export::Compression::from_extension(&name)
+            });
+            let options = export::Options { format, columns, compression };
+            export(&filter.into(), output, options).await
+        }
+        Command::Migrate { action } => migrate(action.unwrap_or(MigrateAction::Status)).await,
+        Command::Rollup { action: RollupAction::Rebuild { since, until } } => {
+            let mut con = database::connect().await?;
+            rollup::rebuild(&mut con, since, until.unwrap_or_else(now_millis), |day| {
+                let date = Utc.timestamp_millis_opt(day as i64).single();
+                eprintln!("Counted messages for {}", date.map_or(day.to_string(), |d| d.format("%Y-%m-%d").to_string()));
+            })
+            .await
+        }
+        Command::HashPassword => {
+            let mut password = String::new();
+            io::stdin().read_line(&mut password)?;
+            println!("{}", auth::hash_password(password.trim_end_matches(['\r', '\n']))?);
+            Ok(())
+        }
+        Command::Archive { action: ArchiveAction::Search { archive, filter, format } } => {
+            let records = Archive::open(&archive.dir)?.search(&filter.into())?;
+            match format {
+                Format::Table => print_table(&records),
+                Format::Json => println!("{}", serde_json::to_string_pretty(&records)?),
+                Format::Csv => write_csv(&records, io::stdout())?,
+            }
+            Ok(())
+        }
+        Command::Archive { action: ArchiveAction::Restore { archive, filter } } => {
+            let records = Archive::open(&archive.dir)?.search(&filter.into())?;
+            let mut con = database::connect().await?;
+            let count = archive::restore(&mut con, &records, now_millis()).await?;
+            eprintln!("Restored {count} messages");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{event_record, format_timestamp, next_event, parse_time};
+
+    #[test]
+    fn server_sent_events() {
+        let event = "event: newRecord\ndata: {\"id\":1,\"server_timestamp\":2,\"msg\":\"café\",\"timestamp\":null,\"msgid\":null,\"severity\":null,\"facility\":null,\"hostname\":null,\"appname\":null,\"ip\":null}\n\n";
+        let (first, second) = event.as_bytes().split_at(event.find('é').unwrap() + 1);
+        let mut buffer = first.to_vec();
+        assert!(next_event(&mut buffer).is_none());
+        buffer.extend_from_slice(second);
+        buffer.extend_from_slice(b": keep-alive\n\n");
+        let record = event_record(&next_event(&mut buffer).unwrap()).unwrap().unwrap();
+        assert_eq!((record.id, record.msg.as_str()), (1, "café"));
+        assert!(event_record(&next_event(&mut buffer).unwrap()).is_none());
+        assert!(buffer.is_empty());
+        assert!(event_record("event: newRecord\ndata: {\n\n").unwrap().is_err());
+        assert!(event_record("event: newMessage\ndata: 1\n\n").is_none());
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("1234").unwrap(), 1234);
+        assert!(parse_time("1h").unwrap() > 0);
+        assert!(parse_time("1w").is_err());
+        assert!(parse_time(&format!("{}d", u64::MAX / 1000)).is_err());
+        assert_eq!(format_timestamp(u64::MAX), u64::MAX.to_string());
+        assert_eq!(format_timestamp(i64::MAX as u64), i64::MAX.to_string());
+    }
+}
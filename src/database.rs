@@ -1,5 +1,6 @@
 use std::env;
 use redis::aio::{MultiplexedConnection};
+use redis_graph::{AsyncGraphCommands, GraphResult};
 
 pub async fn connect() -> redis::RedisResult<MultiplexedConnection> {
   let addr = {
@@ -16,4 +17,58 @@ pub async fn connect() -> redis::RedisResult<MultiplexedConnection> {
     Ok(con)
 }
 
-pub const GRAPH_NAME: &'static str = "syslog";
\ No newline at end of file
+pub const GRAPH_NAME: &str = "syslog";
+
+/// Schema changes in the order they are applied, applied migrations are recorded as `Migration` nodes
+pub const MIGRATIONS: &[(&str, &str)] = &[
+  ("message_server_timestamp_index", "CREATE INDEX ON :Message(server_timestamp)"),
+  ("message_msg_fulltext_index", "CALL db.idx.fulltext.createNodeIndex('Message', 'msg')"),
+  ("address_ip_index", "CREATE INDEX ON :Address(ip)"),
+  ("hostname_name_index", "CREATE INDEX ON :Hostname(name)"),
+  ("appname_name_index", "CREATE INDEX ON :AppName(name)"),
+  ("alert_state_index", "CREATE INDEX ON :Alert(state)"),
+  ("alert_rule_name_index", "CREATE INDEX ON :AlertRule(name)"),
+  // Watchdog alerts used to share the `silent` name with user rules, leave them be if a stored rule has it
+  ("watchdog_rule_prefix", "OPTIONAL MATCH (r:AlertRule {name: 'silent'}) WITH r WHERE r IS NULL MATCH (a:Alert {rule: 'silent'}) SET a.rule = 'ezsyslog:silent'"),
+];
+
+pub async fn applied_migrations(con: &mut MultiplexedConnection) -> redis::RedisResult<Vec<String>> {
+  let result = con
+    .graph_ro_query(GRAPH_NAME, "MATCH (m:Migration) RETURN m.name as name")
+    .await;
+  let result = match result {
+    Ok(result) => result,
+    // A graph that was never written to does not exist yet
+    Err(e) if e.to_string().contains("empty key") => return Ok(vec![]),
+    Err(e) => return Err(e),
+  };
+  Ok(result.data.iter().filter_map(|r| r.get_scalar("name")).collect())
+}
+
+pub async fn pending_migrations(con: &mut MultiplexedConnection) -> redis::RedisResult<Vec<&'static str>> {
+  let applied = applied_migrations(con).await?;
+  Ok(MIGRATIONS
+    .iter()
+    .map(|(name, _)| *name)
+    .filter(|name| !applied.iter().any(|a| a == name))
+    .collect())
+}
+
+/// Applies every pending migration, returning the names of the ones applied
+pub async fn migrate(con: &mut MultiplexedConnection) -> anyhow::Result<Vec<&'static str>> {
+  let pending = pending_migrations(con).await?;
+  for (name, query) in MIGRATIONS.iter().filter(|(name, _)| pending.contains(name)) {
+    match con.graph_query(GRAPH_NAME, *query).await {
+      // Indexes may have been created by hand with test/init_index.txt
+      Err(e) if e.to_string().contains("already indexed") => {}
+      result => { result?; }
+    }
+    con.graph_query(GRAPH_NAME, format!("CREATE (:Migration {{name: '{name}'}})")).await?;
+  }
+  Ok(pending)
+}
+
+/// Reads a boolean property, which comes back from the graph as a string
+pub fn get_bool(result: &GraphResult, key: &str) -> bool {
+  result.get_scalar::<String>(key).is_some_and(|s| s == "true")
+}
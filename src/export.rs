@@ -0,0 +1,266 @@
+use std::{io::Write, mem};
+
+use crate::{
+    search::Record,
+    syslog::{self, Format as Syntax},
+};
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use flate2::{write::GzEncoder, Compression as Level};
+use futures_util::{stream, Stream, TryStreamExt};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Every field of a [`Record`], the default CSV columns
+pub const COLUMNS: [&str; 10] = [
+    "id",
+    "server_timestamp",
+    "timestamp",
+    "msgid",
+    "msg",
+    "severity",
+    "facility",
+    "hostname",
+    "appname",
+    "ip",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Ndjson,
+    Csv,
+    Rfc5424,
+    Rfc3164,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Picks the compression from a file name ending in `.gz` or `.zst`
+    pub fn from_extension(name: &str) -> Compression {
+        if name.ends_with(".gz") {
+            Compression::Gzip
+        } else if name.ends_with(".zst") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Options {
+    pub format: Format,
+    /// Comma separated CSV columns, all of [`COLUMNS`] when not set
+    pub columns: Option<String>,
+    pub compression: Compression,
+}
+
+impl Options {
+    /// File name extension for the output
+    pub fn extension(&self) -> String {
+        let format = match self.format {
+            Format::Ndjson => "ndjson",
+            Format::Csv => "csv",
+            Format::Rfc5424 | Format::Rfc3164 => "log",
+        };
+        match self.compression {
+            Compression::None => format.to_string(),
+            Compression::Gzip => format!("{format}.gz"),
+            Compression::Zstd => format!("{format}.zst"),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match (self.compression, self.format) {
+            (Compression::Gzip, _) => "application/gzip",
+            (Compression::Zstd, _) => "application/zstd",
+            (_, Format::Ndjson) => "application/x-ndjson",
+            (_, Format::Csv) => "text/csv",
+            _ => "text/plain",
+        }
+    }
+}
+
+enum Sink {
+    Plain(Vec<u8>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+}
+
+impl Sink {
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Sink::Plain(buf) => buf,
+            Sink::Gzip(encoder) => encoder,
+            Sink::Zstd(encoder) => encoder,
+        }
+    }
+
+    /// Takes what has been written out so far
+    fn take(&mut self) -> Vec<u8> {
+        mem::take(match self {
+            Sink::Plain(buf) => buf,
+            Sink::Gzip(encoder) => encoder.get_mut(),
+            Sink::Zstd(encoder) => encoder.get_mut(),
+        })
+    }
+}
+
+/// Turns records into the bytes of an export, one chunk at a time so it can be streamed
+pub struct Exporter {
+    format: Format,
+    columns: Vec<String>,
+    sink: Sink,
+}
+
+impl Exporter {
+    pub fn new(options: &Options) -> Result<Exporter> {
+        let columns: Vec<String> = match &options.columns {
+            Some(columns) => columns.split(',').map(|c| c.trim().to_string()).collect(),
+            None => COLUMNS.iter().map(|c| c.to_string()).collect(),
+        };
+        if let Some(column) = columns.iter().find(|c| !COLUMNS.contains(&c.as_str())) {
+            bail!("Unknown column {column}, expected some of {}", COLUMNS.join(","));
+        }
+        let sink = match options.compression {
+            Compression::None => Sink::Plain(vec![]),
+            Compression::Gzip => Sink::Gzip(GzEncoder::new(vec![], Level::default())),
+            Compression::Zstd => Sink::Zstd(zstd::Encoder::new(vec![], 0)?),
+        };
+        let mut exporter = Exporter {
+            format: options.format,
+            columns,
+            sink,
+        };
+        if exporter.format == Format::Csv {
+            let header = exporter.columns.clone();
+            exporter.write_csv(header)?;
+        }
+        Ok(exporter)
+    }
+
+    fn write_csv(&mut self, row: Vec<String>) -> Result<()> {
+        let mut writer = csv::Writer::from_writer(self.sink.writer());
+        writer.write_record(row)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<Vec<u8>> {
+        match self.format {
+            Format::Ndjson => {
+                let out = self.sink.writer();
+                serde_json::to_writer(&mut *out, record)?;
+                out.write_all(b"\n")?;
+            }
+            Format::Csv => {
+                let Value::Object(fields) = serde_json::to_value(record)? else {
+                    unreachable!("records serialize as objects")
+                };
+                let row = self
+                    .columns
+                    .iter()
+                    .map(|c| match fields.get(c.as_str()).unwrap_or(&Value::Null) {
+                        Value::Null => String::new(),
+                        Value::String(s) => s.clone(),
+                        value => value.to_string(),
+                    })
+                    .collect();
+                self.write_csv(row)?;
+            }
+            Format::Rfc5424 | Format::Rfc3164 => {
+                let syntax = match self.format {
+                    Format::Rfc3164 => Syntax::Rfc3164,
+                    _ => Syntax::Rfc5424,
+                };
+                let line = syslog::format_message(&record.to_message(), syntax, record.ip.as_deref());
+                writeln!(self.sink.writer(), "{line}")?;
+            }
+        }
+        Ok(self.sink.take())
+    }
+
+    /// The rest of the output, such as the end of a compressed stream
+    pub fn finish(self) -> Result<Vec<u8>> {
+        Ok(match self.sink {
+            Sink::Plain(buf) => buf,
+            Sink::Gzip(encoder) => encoder.finish()?,
+            Sink::Zstd(encoder) => encoder.finish()?,
+        })
+    }
+}
+
+/// Chunks of the export of every record in the stream
+pub fn export<S>(records: S, exporter: Exporter) -> impl Stream<Item = Result<Vec<u8>>>
+where
+    S: Stream<Item = Result<Record>> + Unpin,
+{
+    stream::try_unfold((records, Some(exporter)), |(mut records, exporter)| async move {
+        let Some(mut exporter) = exporter else {
+            return Ok(None);
+        };
+        match records.try_next().await? {
+            Some(record) => {
+                let chunk = exporter.write(&record)?;
+                Ok(Some((chunk, (records, Some(exporter)))))
+            }
+            None => Ok(Some((exporter.finish()?, (records, None)))),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compression, Exporter, Format, Options};
+    use crate::search::Record;
+    use std::io::Read;
+
+    #[test]
+    fn formats() {
+        let record = Record {
+            id: 1,
+            server_timestamp: 1_700_000_000_000,
+            timestamp: None,
+            msgid: None,
+            msg: "Accepted, \"publickey\"".to_string(),
+            severity: Some("info".to_string()),
+            facility: Some("auth".to_string()),
+            hostname: Some("web1".to_string()),
+            appname: Some("sshd".to_string()),
+            ip: Some("10.0.0.1".to_string()),
+        };
+        let export = |options: Options| {
+            let mut exporter = Exporter::new(&options).unwrap();
+            let mut out = exporter.write(&record).unwrap();
+            out.extend(exporter.finish().unwrap());
+            out
+        };
+
+        let csv = export(Options { format: Format::Csv, columns: Some("hostname,msg,timestamp".to_string()), ..Default::default() });
+        assert_eq!(String::from_utf8(csv).unwrap(), "hostname,msg,timestamp\nweb1,\"Accepted, \"\"publickey\"\"\",\n");
+
+        let line = export(Options { format: Format::Rfc5424, ..Default::default() });
+        assert_eq!(
+            String::from_utf8(line).unwrap(),
+            "<38>1 2023-11-14T22:13:20.000Z web1 sshd - - - Accepted, \"publickey\"\n"
+        );
+
+        let gzip = export(Options { compression: Compression::Gzip, ..Default::default() });
+        let mut ndjson = String::new();
+        flate2::read::GzDecoder::new(&gzip[..]).read_to_string(&mut ndjson).unwrap();
+        assert_eq!(ndjson, format!("{}\n", serde_json::to_string(&record).unwrap()));
+
+        assert!(Exporter::new(&Options { columns: Some("nope".to_string()), ..Default::default() }).is_err());
+    }
+}
@@ -0,0 +1,440 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    net::{IpAddr, Ipv4Addr},
+    os::unix::fs::{FileExt, MetadataExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::{broadcast::Sender, watch::Receiver};
+
+use crate::{database, health, metrics::METRICS, relay::Relay, syslog};
+use anyhow::{bail, Result};
+use blake2::{Blake2b512, Digest};
+use serde::{Deserialize, Serialize};
+use syslog_loose::{parse_message, Message, Protocol};
+use tracing::{info, warn};
+
+/// Most bytes read from a single file per poll so one busy file can't starve the others
+const READ_LIMIT: u64 = 1024 * 1024;
+
+pub struct Line {
+    pub path: PathBuf,
+    pub text: String,
+}
+
+/// Bytes at the start of a file that identify it, together with its device and inode
+const FINGERPRINT_LEN: u64 = 1024;
+
+/// A file by device and inode, inodes alone are only unique within one filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FileId {
+    dev: u64,
+    ino: u64,
+}
+
+impl FileId {
+    fn of(metadata: &fs::Metadata) -> FileId {
+        FileId { dev: metadata.dev(), ino: metadata.ino() }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedOffset {
+    path: PathBuf,
+    /// Missing from state files written before devices were saved
+    #[serde(default)]
+    dev: Option<u64>,
+    ino: u64,
+    offset: u64,
+    /// Digest of the first [`FINGERPRINT_LEN`] bytes, or of the first `offset` bytes when there are fewer, to tell
+    /// a reused inode from the file the offset belongs to
+    #[serde(default)]
+    fingerprint: Option<String>,
+}
+
+impl SavedOffset {
+    /// Whether the offset still belongs to `file` at `path`, which has the same device and inode
+    fn matches(&self, path: &Path, file: &File, len: u64) -> bool {
+        (self.dev.is_some() || self.path == path)
+            && self.offset <= len
+            && self.fingerprint.as_ref().is_none_or(|f| fingerprint(file, self.offset).is_ok_and(|g| *f == g))
+    }
+}
+
+/// State files used to map inodes to offsets
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum State {
+    Saved(Vec<SavedOffset>),
+    /// Keyed by the inode as a string, which untagged enums can't turn into a number
+    ByInode(HashMap<String, OldOffset>),
+}
+
+#[derive(Deserialize)]
+struct OldOffset {
+    path: PathBuf,
+    offset: u64,
+}
+
+/// Digest of the start of `file`, up to `offset` bytes
+fn fingerprint(file: &File, offset: u64) -> std::io::Result<String> {
+    let mut buf = vec![0; offset.min(FINGERPRINT_LEN) as usize];
+    file.read_exact_at(&mut buf, 0)?;
+    Ok(hex::encode(&Blake2b512::digest(&buf)[..16]))
+}
+
+struct Tracked {
+    path: PathBuf,
+    file: File,
+    offset: u64,
+    /// Dropping the rest of a line that was too long to read whole
+    skipping: bool,
+}
+
+/// Follows every file matching a list of glob patterns like `tail -F`.
+///
+/// Files are tracked by device and inode, so a rotated file that was renamed keeps its offset and a file that was
+/// removed from the patterns is read to the end before it is dropped. Offsets are persisted in a state file
+/// so a restart resumes where the last [`Follower::save`] left off.
+pub struct Follower {
+    patterns: Vec<String>,
+    state_path: PathBuf,
+    saved: Vec<SavedOffset>,
+    tracked: HashMap<FileId, Tracked>,
+    first_poll: bool,
+}
+
+impl Follower {
+    pub fn new(patterns: Vec<String>, state_path: PathBuf) -> Result<Follower> {
+        let saved = match fs::read(&state_path) {
+            Ok(data) => match serde_json::from_slice(&data)? {
+                State::Saved(saved) => saved,
+                State::ByInode(saved) => saved
+                    .into_iter()
+                    .filter_map(|(ino, s)| {
+                        Some(SavedOffset { path: s.path, dev: None, ino: ino.parse().ok()?, offset: s.offset, fingerprint: None })
+                    })
+                    .collect(),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Follower {
+            patterns,
+            state_path,
+            saved,
+            tracked: HashMap::new(),
+            first_poll: true,
+        })
+    }
+
+    /// Reads every complete line written since the last poll.
+    pub fn poll(&mut self) -> Result<Vec<Line>> {
+        let mut seen = HashSet::new();
+        for pattern in &self.patterns {
+            for path in glob::glob(pattern)?.flatten() {
+                let metadata = match fs::metadata(&path) {
+                    Ok(m) if m.is_file() => m,
+                    _ => continue,
+                };
+                let id = FileId::of(&metadata);
+                seen.insert(id);
+                if let Some(tracked) = self.tracked.get_mut(&id) {
+                    tracked.path = path;
+                    // Truncated in place, e.g. by logrotate's copytruncate
+                    if metadata.len() < tracked.offset {
+                        tracked.offset = 0;
+                    }
+                    continue;
+                }
+                // Files that already exist when we first start without any saved state are followed from the end,
+                // use `ezsyslog import` to backfill them
+                let file = File::open(&path)?;
+                let saved = self.saved.iter().find(|s| s.ino == id.ino && s.dev.is_none_or(|dev| dev == id.dev));
+                let offset = match saved {
+                    Some(saved) if saved.matches(&path, &file, metadata.len()) => saved.offset,
+                    Some(_) => 0,
+                    None if self.first_poll && self.saved.is_empty() => metadata.len(),
+                    None => 0,
+                };
+                self.tracked.insert(id, Tracked { path, file, offset, skipping: false });
+            }
+        }
+        self.first_poll = false;
+
+        let mut lines = vec![];
+        for (id, tracked) in self.tracked.iter_mut() {
+            read_lines(tracked, &mut lines, !seen.contains(id))?;
+        }
+        // Anything no longer matched was rotated away or deleted, and is dropped once it has been read to the end
+        self.tracked.retain(|id, t| {
+            seen.contains(id) || t.file.metadata().is_ok_and(|m| t.offset < m.len())
+        });
+        Ok(lines)
+    }
+
+    /// Persists the offsets of every line returned by [`Follower::poll`] so far.
+    pub fn save(&mut self) -> Result<()> {
+        self.saved = self
+            .tracked
+            .iter()
+            .map(|(id, t)| SavedOffset {
+                path: t.path.clone(),
+                dev: Some(id.dev),
+                ino: id.ino,
+                offset: t.offset,
+                fingerprint: fingerprint(&t.file, t.offset).ok(),
+            })
+            .collect();
+        let tmp = self.state_path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.saved)?)?;
+        fs::rename(tmp, &self.state_path)?;
+        Ok(())
+    }
+}
+
+/// Reads up to [`READ_LIMIT`] bytes of lines. Only complete lines are consumed and a partial line is read again
+/// once it is finished, except at the end of a file that was rotated away, which won't be finished. A line that
+/// fills the whole limit is cut off there and the rest of it dropped.
+fn read_lines(tracked: &mut Tracked, lines: &mut Vec<Line>, rotated: bool) -> Result<()> {
+    let mut buf = vec![];
+    tracked.file.seek(SeekFrom::Start(tracked.offset))?;
+    (&tracked.file).take(READ_LIMIT).read_to_end(&mut buf)?;
+    let mut start = 0;
+    if tracked.skipping {
+        match buf.iter().position(|b| *b == b'\n') {
+            Some(i) => {
+                start = i + 1;
+                tracked.skipping = false;
+            }
+            None => {
+                tracked.offset += buf.len() as u64;
+                return Ok(());
+            }
+        }
+    }
+    let full = buf.len() as u64 == READ_LIMIT;
+    let end = match buf[start..].iter().rposition(|b| *b == b'\n') {
+        _ if rotated && !full => buf.len(),
+        Some(i) => start + i + 1,
+        None if full && start == 0 => {
+            warn!(path = %tracked.path.display(), "Cutting off a line longer than {READ_LIMIT} bytes");
+            tracked.skipping = true;
+            buf.len()
+        }
+        None => start,
+    };
+    for line in buf[start..end].split(|b| *b == b'\n') {
+        let text = String::from_utf8_lossy(line).trim_end().to_string();
+        if !text.is_empty() {
+            lines.push(Line {
+                path: tracked.path.clone(),
+                text,
+            });
+        }
+    }
+    tracked.offset += end as u64;
+    Ok(())
+}
+
+/// How lines read from files become messages
+#[derive(Clone)]
+pub enum Parser {
+    /// RFC 5424 or RFC 3164 syslog lines
+    Syslog,
+    /// Unstructured lines, stored as the message body with the configured hostname and the file name as the app name
+    Raw { hostname: Option<String> },
+}
+
+impl Parser {
+    pub fn from_env() -> Result<Parser> {
+        match env::var("EZSYSLOG_FILE_PARSER").as_deref() {
+            Err(_) | Ok("syslog") => Ok(Parser::Syslog),
+            Ok("raw") => Ok(Parser::Raw {
+                hostname: env::var("EZSYSLOG_FILE_HOSTNAME").ok(),
+            }),
+            Ok(other) => bail!("Unknown file parser {other}, expected syslog or raw"),
+        }
+    }
+
+    pub fn parse<'a>(&'a self, line: &'a Line) -> Message<&'a str> {
+        match self {
+            Parser::Syslog => parse_message(&line.text),
+            Parser::Raw { hostname } => Message {
+                protocol: Protocol::RFC3164,
+                facility: None,
+                severity: None,
+                timestamp: None,
+                hostname: hostname.as_deref(),
+                appname: line.path.file_name().and_then(|n| n.to_str()),
+                procid: None,
+                msgid: None,
+                structured_data: vec![],
+                msg: &line.text,
+            },
+        }
+    }
+}
+
+pub fn patterns_from_env() -> Option<Vec<String>> {
+    let paths = env::var("EZSYSLOG_FILE_PATHS").ok()?;
+    Some(paths.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect())
+}
+
+pub async fn listen(
+    mut shutdown_signal: Receiver<()>,
+    sender: Sender<crate::Signal>,
+    relay: Relay,
+) -> Result<()> {
+    info!("File listener started");
+    let mut con = database::connect().await?;
+
+    let patterns = patterns_from_env().unwrap_or_default();
+    let state: PathBuf = env::var("EZSYSLOG_FILE_STATE")
+        .unwrap_or("ezsyslog-files.json".to_string())
+        .into();
+    let mut follower = Follower::new(patterns, state)?;
+    let parser = Parser::from_env()?;
+    health::listening("file", follower.patterns.join(","));
+    let local = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = shutdown_signal.changed() => {
+                break;
+            },
+            _ = interval.tick() => {
+                let lines = follower.poll()?;
+                if !lines.is_empty() {
+                    METRICS.batch_size.with_label_values(&["file"]).observe(lines.len() as f64);
+                }
+                for line in lines {
+                    METRICS.received.with_label_values(&["file"]).inc();
+                    METRICS.received_bytes.with_label_values(&["file"]).inc_by(line.text.len() as u64);
+                    METRICS.parsed.with_label_values(&["file"]).inc();
+                    syslog::ingest(&mut con, parser.parse(&line), &local, &sender, &relay).await?;
+                }
+                follower.save()?;
+            }
+        };
+    }
+
+    info!("File listener stopped");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Follower, READ_LIMIT};
+    use std::{fs, io::Write, os::unix::fs::MetadataExt};
+
+    fn texts(follower: &mut Follower) -> Vec<String> {
+        follower.poll().unwrap().into_iter().map(|l| l.text).collect()
+    }
+
+    #[test]
+    fn follow_rotation_and_truncation() {
+        let dir = std::env::temp_dir().join(format!("ezsyslog-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("app.log");
+        let state = dir.join("state.json");
+        fs::write(&log, "existing\n").unwrap();
+
+        let mut follower = Follower::new(vec![log.to_string_lossy().to_string()], state.clone()).unwrap();
+        assert!(texts(&mut follower).is_empty());
+
+        let mut file = fs::OpenOptions::new().append(true).open(&log).unwrap();
+        write!(file, "one\ntw").unwrap();
+        assert_eq!(texts(&mut follower), ["one"]);
+        writeln!(file, "o").unwrap();
+        assert_eq!(texts(&mut follower), ["two"]);
+        follower.save().unwrap();
+
+        // Rotated away, the rest of the old file is still read
+        writeln!(file, "three").unwrap();
+        fs::rename(&log, dir.join("app.log.1")).unwrap();
+        fs::write(&log, "four\n").unwrap();
+        let mut lines = texts(&mut follower);
+        lines.sort();
+        assert_eq!(lines, ["four", "three"]);
+        follower.save().unwrap();
+
+        // Restarting resumes from the saved offset
+        let mut follower = Follower::new(vec![log.to_string_lossy().to_string()], state).unwrap();
+        fs::OpenOptions::new().append(true).open(&log).unwrap().write_all(b"five\n").unwrap();
+        assert_eq!(texts(&mut follower), ["five"]);
+
+        fs::write(&log, "six\n").unwrap();
+        assert_eq!(texts(&mut follower), ["six"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn saved_offsets_of_other_files() {
+        let dir = std::env::temp_dir().join(format!("ezsyslog-file-saved-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("app.log");
+        let state = dir.join("state.json");
+        let patterns = vec![log.to_string_lossy().to_string()];
+        fs::write(&log, "").unwrap();
+        let mut follower = Follower::new(patterns.clone(), state.clone()).unwrap();
+        assert!(texts(&mut follower).is_empty());
+        fs::write(&log, "alpha\n").unwrap();
+        assert_eq!(texts(&mut follower), ["alpha"]);
+        follower.save().unwrap();
+
+        // Same inode with different contents while stopped, e.g. a new file that reused it
+        fs::write(&log, "omega\nnext\n").unwrap();
+        let mut follower = Follower::new(patterns.clone(), state.clone()).unwrap();
+        assert_eq!(texts(&mut follower), ["omega", "next"]);
+
+        // State files that only have inodes still resume
+        let ino = fs::metadata(&log).unwrap().ino();
+        fs::write(&state, format!(r#"{{"{ino}": {{"path": {:?}, "offset": 6}}}}"#, log.to_string_lossy())).unwrap();
+        let mut follower = Follower::new(patterns, state).unwrap();
+        assert_eq!(texts(&mut follower), ["next"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn long_lines_and_large_rotations() {
+        let dir = std::env::temp_dir().join(format!("ezsyslog-file-long-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("app.log");
+        fs::write(&log, "").unwrap();
+        let mut follower = Follower::new(vec![log.to_string_lossy().to_string()], dir.join("state.json")).unwrap();
+        assert!(texts(&mut follower).is_empty());
+
+        let mut file = fs::OpenOptions::new().append(true).open(&log).unwrap();
+        file.write_all(&vec![b'x'; READ_LIMIT as usize + 100]).unwrap();
+        writeln!(file, "\nshort").unwrap();
+        let lines = texts(&mut follower);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].len(), READ_LIMIT as usize);
+        assert_eq!(texts(&mut follower), ["short"]);
+
+        // More than one poll's worth left in the rotated file, ending without a newline
+        for i in 0..30_000 {
+            writeln!(file, "line {i:0>60}").unwrap();
+        }
+        write!(file, "last").unwrap();
+        fs::rename(&log, dir.join("app.log.1")).unwrap();
+        fs::write(&log, "").unwrap();
+        let mut lines = vec![];
+        for _ in 0..5 {
+            lines.extend(texts(&mut follower));
+        }
+        assert_eq!(lines.len(), 30_001);
+        assert_eq!(lines.last().unwrap(), "last");
+        assert_eq!(follower.tracked.len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Display,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{database, relay::Queue};
+use anyhow::{anyhow, bail, Result};
+use redis::aio::MultiplexedConnection;
+use serde::Serialize;
+use tracing::error;
+
+/// What a listener is doing, as reported on `/readyz`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum Listener {
+    Starting,
+    Listening { address: String },
+    /// A background task without an address, such as the alert engine
+    Running,
+    Stopped,
+    Failed { error: String },
+}
+
+impl Listener {
+    fn is_up(&self) -> bool {
+        matches!(self, Listener::Listening { .. } | Listener::Running)
+    }
+}
+
+static LISTENERS: Mutex<BTreeMap<&str, Listener>> = Mutex::new(BTreeMap::new());
+
+fn set(name: &'static str, state: Listener) {
+    LISTENERS.lock().unwrap().insert(name, state);
+}
+
+pub fn listeners() -> BTreeMap<&'static str, Listener> {
+    LISTENERS.lock().unwrap().clone()
+}
+
+/// Marks a listener as bound to `address`
+pub fn listening(name: &'static str, address: impl Display) {
+    set(name, Listener::Listening { address: address.to_string() });
+}
+
+/// Marks a background task as started
+pub fn running(name: &'static str) {
+    set(name, Listener::Running);
+}
+
+/// Runs a listener, recording when it stops or fails so `/readyz` can report it. The listener counts as starting
+/// from the call, before the returned future is first polled.
+pub fn supervise(
+    name: &'static str,
+    listener: impl Future<Output = Result<()>>,
+) -> impl Future<Output = Result<()>> {
+    set(name, Listener::Starting);
+    async move {
+        let result = listener.await;
+        match &result {
+            Ok(()) => set(name, Listener::Stopped),
+            Err(e) => {
+                error!(listener = name, "Listener failed: {e:#}");
+                set(name, Listener::Failed { error: format!("{e:#}") });
+            }
+        }
+        result
+    }
+}
+
+/// Waits until every listener is listening, failing as soon as one of them fails
+pub async fn all_listening() -> Result<()> {
+    loop {
+        let listeners = listeners();
+        if let Some((name, Listener::Failed { error })) =
+            listeners.iter().find(|(_, l)| matches!(l, Listener::Failed { .. }))
+        {
+            bail!("Listener {name} failed: {error}");
+        }
+        if listeners.values().all(Listener::is_up) {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Database {
+    pub connected: bool,
+    pub latency_ms: Option<f64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub listeners: BTreeMap<&'static str, Listener>,
+    pub database: Database,
+    /// Schema migrations not applied yet, unknown when the database can't be reached
+    pub pending_migrations: Option<Vec<&'static str>>,
+    /// Messages waiting to be relayed, the server's only backlog
+    pub relay: Vec<Queue>,
+}
+
+/// Slowest database round trip that still counts as ready, from `EZSYSLOG_READY_MAX_LATENCY_MS`
+fn max_latency_from_env() -> f64 {
+    env::var("EZSYSLOG_READY_MAX_LATENCY_MS").ok().and_then(|l| l.parse().ok()).unwrap_or(1000.0)
+}
+
+impl Readiness {
+    /// Ready when every listener is listening, the database answers quickly and is migrated, and no relay queue
+    /// is more than 90% full
+    fn is_ready(&self, max_latency_ms: f64) -> bool {
+        self.listeners.values().all(Listener::is_up)
+            && self.database.connected
+            && self.database.latency_ms.is_some_and(|l| l <= max_latency_ms)
+            && self.pending_migrations.as_ref().is_some_and(Vec::is_empty)
+            && self.relay.iter().all(|q| q.queued * 10 <= q.capacity * 9)
+    }
+}
+
+/// Round trip to the database in milliseconds
+pub async fn ping(con: &mut MultiplexedConnection) -> Result<f64> {
+    let started = Instant::now();
+    let ping = redis::cmd("PING");
+    tokio::time::timeout(Duration::from_secs(5), ping.query_async::<_, String>(con))
+        .await
+        .map_err(|_| anyhow!("Timed out"))??;
+    Ok(started.elapsed().as_secs_f64() * 1000.0)
+}
+
+pub async fn check(con: &mut MultiplexedConnection, relay: Vec<Queue>) -> Readiness {
+    let database = match ping(con).await {
+        Ok(latency) => Database { connected: true, latency_ms: Some(latency), error: None },
+        Err(e) => Database { connected: false, latency_ms: None, error: Some(e.to_string()) },
+    };
+    let pending_migrations = match database.connected {
+        true => database::pending_migrations(con).await.ok(),
+        false => None,
+    };
+    let mut readiness = Readiness {
+        ready: false,
+        listeners: listeners(),
+        database,
+        pending_migrations,
+        relay,
+    };
+    readiness.ready = readiness.is_ready(max_latency_from_env());
+    readiness
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Database, Listener, Readiness};
+    use crate::relay::Queue;
+
+    #[test]
+    fn readiness() {
+        let mut readiness = Readiness {
+            ready: false,
+            listeners: [("udp", Listener::Listening { address: "[::]:514".to_string() })].into(),
+            database: Database { connected: true, latency_ms: Some(2.0), error: None },
+            pending_migrations: Some(vec![]),
+            relay: vec![Queue { upstream: "siem:514".to_string(), queued: 900, capacity: 1000 }],
+        };
+        readiness.listeners.insert("alert", Listener::Running);
+        assert!(readiness.is_ready(1000.0));
+        assert!(!readiness.is_ready(1.0));
+        readiness.relay[0].queued = 901;
+        assert!(!readiness.is_ready(1000.0));
+        readiness.relay.clear();
+        readiness.listeners.insert("agent", Listener::Failed { error: "Address in use".to_string() });
+        assert!(!readiness.is_ready(1000.0));
+        assert_eq!(
+            serde_json::to_value(&readiness.listeners["agent"]).unwrap(),
+            serde_json::json!({ "state": "failed", "error": "Address in use" })
+        );
+    }
+}
@@ -1,24 +1,45 @@
-use std::{collections::HashMap, env, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::database;
-use futures_util::FutureExt;
+use crate::{
+    access::{Role, Scope},
+    alert::{self, AlertRecord, Firing, Rule, Silence, StoredRule},
+    audit,
+    auth::{self, Identity},
+    database, export, health,
+    matcher::Matcher,
+    metrics::METRICS,
+    relay::Relay,
+    search::{ContextParams, Filter, Record},
+    stats::{self, Count, Histogram, HistogramParams, TopParams},
+    systemd,
+    tls,
+    watchdog::{self, HostStatus},
+};
+use futures_util::{FutureExt, TryStreamExt};
 use poem::{
     endpoint::EmbeddedFilesEndpoint,
-    get, handler,
+    delete, get, handler,
     http::{Method, StatusCode},
-    listener::TcpListener,
-    middleware::{AddData, Cors},
+    listener::{Acceptor, AcceptorExt, Listener, TcpAcceptor, TcpListener},
+    middleware::{AddData, Cors, Tracing},
     web::{
         sse::{Event, SSE},
-        Data, Json,
+        Data, Html, Json, Path, Query, Redirect,
     },
-    EndpointExt, Request, Result, Route, Server,
+    post, put, Body, Endpoint, EndpointExt, IntoResponse, Request, Response, Result, Route, Server,
 };
 use redis::aio::MultiplexedConnection;
 use redis_graph::{AsyncGraphCommands, GraphValue};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::{broadcast::Sender, watch};
-use tokio_stream::wrappers::BroadcastStream;
+use tracing::{debug, info, warn};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, IntervalStream};
 use tokio_stream::StreamExt;
 
 #[derive(Deserialize)]
@@ -30,6 +51,49 @@ struct Params {
 #[folder = "app/dist/"]
 struct Files;
 
+/// Where the server is mounted behind a reverse proxy, e.g. `/syslog`, empty when it is at `/`
+#[derive(Debug, Clone, Default, PartialEq)]
+struct BasePath(String);
+
+impl BasePath {
+    /// From `EZSYSLOG_HTTP_BASE_PATH`, with a leading and without a trailing `/`
+    fn from_env() -> BasePath {
+        BasePath::new(&env::var("EZSYSLOG_HTTP_BASE_PATH").unwrap_or_default())
+    }
+
+    fn new(path: &str) -> BasePath {
+        let path = path.trim_matches('/');
+        match path.is_empty() {
+            true => BasePath::default(),
+            false => BasePath(format!("/{path}")),
+        }
+    }
+
+    /// Path of the UI, which cookies are scoped to
+    fn root(&self) -> String {
+        format!("{}/", self.0)
+    }
+}
+
+/// `index.html` with a `<base>` so the UI finds its assets and the API under the base path
+fn index_html(html: &str, base: &BasePath) -> String {
+    let tag = format!("<base href=\"{}\">", base.root());
+    let at = match html.find("<head>") {
+        Some(head) => head + "<head>".len(),
+        None if html.to_ascii_lowercase().starts_with("<!doctype") => html.find('>').map_or(0, |end| end + 1),
+        None => 0,
+    };
+    format!("{}{tag}{}", &html[..at], &html[at..])
+}
+
+#[handler]
+fn index(base: Data<&BasePath>) -> Response {
+    match Files::get("index.html") {
+        Some(file) => Html(index_html(&String::from_utf8_lossy(&file.data), &base)).into_response(),
+        None => StatusCode::NOT_FOUND.into(),
+    }
+}
+
 // TODO: Remove all instances of clone for redis values
 
 #[handler]
@@ -43,18 +107,16 @@ async fn search(
     let results = con
         .graph_ro_query(database::GRAPH_NAME, &query_phrase)
         .await;
-    #[cfg(debug_assertions)]
-    dbg!(&results);
     match results {
         Err(e) => {
-            println!("Error returned from redis: {e:?}");
+            warn!(query = %query_phrase, "Error returned from redis: {e}");
             Err(poem::Error::from((
                 StatusCode::BAD_REQUEST,
                 anyhow::anyhow!(e.to_string()),
             )))
         }
         Ok(r) => {
-            println!("{query_phrase}: {metadata:#?}", metadata = r.metadata);
+            debug!(query = %query_phrase, metadata = ?r.metadata, "Search finished");
             let json: Vec<HashMap<String, serde_redis_graph::SerializeGraphValue>> = r
                 .data
                 .into_iter()
@@ -73,6 +135,618 @@ async fn search(
     }
 }
 
+#[derive(Deserialize)]
+struct MessagesParams {
+    /// `ndjson` streams every matching record instead of returning a page
+    format: Option<String>,
+}
+
+#[handler]
+async fn messages(db: Data<&MultiplexedConnection>, scope: Data<&Option<Scope>>, req: &Request) -> Result<Response> {
+    let mut con = db.clone();
+    let filter = Filter { scope: scope.clone(), ..req.params::<Filter>()? };
+    if req.params::<MessagesParams>()?.format.as_deref() == Some("ndjson") {
+        let lines = crate::search::stream(con, filter).map(|record| {
+            let mut line = serde_json::to_vec(&record.map_err(io::Error::other)?)?;
+            line.push(b'\n');
+            Ok::<_, io::Error>(line)
+        });
+        return Ok(Response::builder()
+            .content_type("application/x-ndjson")
+            .body(Body::from_bytes_stream(lines)));
+    }
+    crate::search::page(&mut con, &filter)
+        .await
+        .map(|page| Json(page).into_response())
+        .map_err(|e| poem::Error::from((StatusCode::BAD_REQUEST, e)))
+}
+
+/// Streams every matching message as a file download
+#[handler]
+async fn download(db: Data<&MultiplexedConnection>, scope: Data<&Option<Scope>>, req: &Request) -> Result<Response> {
+    let filter = Filter { scope: scope.clone(), ..req.params::<Filter>()? };
+    let options = req.params::<export::Options>()?;
+    let exporter = export::Exporter::new(&options).map_err(|e| poem::Error::from((StatusCode::BAD_REQUEST, e)))?;
+    let records = Box::pin(crate::search::stream(db.clone(), filter));
+    let chunks = export::export(records, exporter).map_err(io::Error::other);
+    Ok(Response::builder()
+        .content_type(options.content_type())
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"ezsyslog-export.{}\"", options.extension()),
+        )
+        .body(Body::from_bytes_stream(chunks)))
+}
+
+#[handler]
+async fn message(
+    db: Data<&MultiplexedConnection>,
+    scope: Data<&Option<Scope>>,
+    Path(id): Path<u64>,
+) -> Result<Json<Record>> {
+    let mut con = db.clone();
+    match crate::search::get(&mut con, id).await? {
+        Some(record) if scope.as_ref().is_none_or(|s| s.matches(&record)) => Ok(Json(record)),
+        _ => Err(poem::Error::from_status(StatusCode::NOT_FOUND)),
+    }
+}
+
+/// The message with neighbouring messages from the same host
+#[handler]
+async fn context(
+    db: Data<&MultiplexedConnection>,
+    scope: Data<&Option<Scope>>,
+    Path(id): Path<u64>,
+    req: &Request,
+) -> Result<Json<Vec<Record>>> {
+    let mut con = db.clone();
+    let params = req.params::<ContextParams>()?;
+    let mut records = crate::search::context(&mut con, id, &params).await?.unwrap_or_default();
+    // Messages from the same hostname can come from addresses outside the user's host groups
+    if let Some(scope) = scope.as_ref() {
+        records.retain(|r| scope.matches(r));
+    }
+    match records.iter().any(|r| r.id == id) {
+        true => Ok(Json(records)),
+        false => Err(poem::Error::from_status(StatusCode::NOT_FOUND)),
+    }
+}
+
+#[handler]
+async fn histogram(
+    db: Data<&MultiplexedConnection>,
+    scope: Data<&Option<Scope>>,
+    req: &Request,
+) -> Result<Json<Histogram>> {
+    let mut con = db.clone();
+    let filter = Filter { scope: scope.clone(), ..req.params::<Filter>()? };
+    let params = req.params::<HistogramParams>()?;
+    stats::histogram(&mut con, filter, &params, alert::now_millis())
+        .await
+        .map(Json)
+        .map_err(|e| poem::Error::from((StatusCode::BAD_REQUEST, e)))
+}
+
+#[handler]
+async fn top(db: Data<&MultiplexedConnection>, scope: Data<&Option<Scope>>, req: &Request) -> Result<Json<Vec<Count>>> {
+    let mut con = db.clone();
+    let filter = Filter { scope: scope.clone(), ..req.params::<Filter>()? };
+    let params = req.params::<TopParams>()?;
+    stats::top(&mut con, filter, params.by, alert::now_millis())
+        .await
+        .map(Json)
+        .map_err(|e| poem::Error::from((StatusCode::BAD_REQUEST, e)))
+}
+
+#[handler]
+async fn hosts(db: Data<&MultiplexedConnection>, scope: Data<&Option<Scope>>) -> Result<Json<Vec<HostStatus>>> {
+    let mut con = db.clone();
+    let mut hosts = watchdog::hosts(&mut con).await?;
+    if let Some(scope) = scope.as_ref() {
+        hosts.retain(|h| scope.allows_host(&h.name));
+    }
+    Ok(Json(hosts))
+}
+
+/// Whether an operator limited to some host groups may manage a rule or silence with this matcher
+fn manages(scope: &Option<Scope>, matcher: &Matcher) -> bool {
+    scope.as_ref().is_none_or(|s| s.allows_matcher(matcher))
+}
+
+fn forbidden() -> poem::Error {
+    poem::Error::from_status(StatusCode::FORBIDDEN)
+}
+
+/// The stored rule called `name` if the user may manage it
+async fn scoped_rule(con: &mut MultiplexedConnection, scope: &Option<Scope>, name: &str) -> Result<StoredRule> {
+    let existing = alert::stored_rules(con).await?;
+    match existing.into_iter().find(|r| r.rule.name == name) {
+        Some(rule) if manages(scope, &rule.rule.matcher) => Ok(rule),
+        Some(_) => Err(forbidden()),
+        None => Err(not_found()),
+    }
+}
+
+#[handler]
+async fn rules(db: Data<&MultiplexedConnection>, scope: Data<&Option<Scope>>) -> Result<Json<Vec<StoredRule>>> {
+    let mut con = db.clone();
+    let mut rules = alert::stored_rules(&mut con).await?;
+    rules.retain(|r| manages(&scope, &r.rule.matcher));
+    Ok(Json(rules))
+}
+
+#[handler]
+async fn create_rule(
+    db: Data<&MultiplexedConnection>,
+    scope: Data<&Option<Scope>>,
+    Json(rule): Json<StoredRule>,
+) -> Result<Json<StoredRule>> {
+    alert::check_rule_name(&rule.rule.name).map_err(|e| poem::Error::from((StatusCode::BAD_REQUEST, e)))?;
+    if !manages(&scope, &rule.rule.matcher) {
+        return Err(forbidden());
+    }
+    let mut con = db.clone();
+    let existing = alert::stored_rules(&mut con).await?;
+    if existing.iter().any(|r| r.rule.name == rule.rule.name) {
+        return Err(poem::Error::from_status(StatusCode::CONFLICT));
+    }
+    alert::save_rule(&mut con, &rule).await?;
+    Ok(Json(rule))
+}
+
+#[handler]
+async fn update_rule(
+    db: Data<&MultiplexedConnection>,
+    scope: Data<&Option<Scope>>,
+    Path(name): Path<String>,
+    Json(mut rule): Json<StoredRule>,
+) -> Result<Json<StoredRule>> {
+    alert::check_rule_name(&name).map_err(|e| poem::Error::from((StatusCode::BAD_REQUEST, e)))?;
+    let mut con = db.clone();
+    scoped_rule(&mut con, &scope, &name).await?;
+    if !manages(&scope, &rule.rule.matcher) {
+        return Err(forbidden());
+    }
+    rule.rule.name = name;
+    alert::save_rule(&mut con, &rule).await?;
+    Ok(Json(rule))
+}
+
+#[handler]
+async fn delete_rule(
+    db: Data<&MultiplexedConnection>,
+    scope: Data<&Option<Scope>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode> {
+    let mut con = db.clone();
+    scoped_rule(&mut con, &scope, &name).await?;
+    match alert::delete_rule(&mut con, &name).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(not_found()),
+    }
+}
+
+async fn enable_rule(db: &MultiplexedConnection, scope: &Option<Scope>, name: &str, enabled: bool) -> Result<StatusCode> {
+    let mut con = db.clone();
+    scoped_rule(&mut con, scope, name).await?;
+    match alert::enable_rule(&mut con, name, enabled).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(not_found()),
+    }
+}
+
+#[handler]
+async fn enable(db: Data<&MultiplexedConnection>, scope: Data<&Option<Scope>>, Path(name): Path<String>) -> Result<StatusCode> {
+    enable_rule(&db, &scope, &name, true).await
+}
+
+#[handler]
+async fn disable(db: Data<&MultiplexedConnection>, scope: Data<&Option<Scope>>, Path(name): Path<String>) -> Result<StatusCode> {
+    enable_rule(&db, &scope, &name, false).await
+}
+
+#[derive(Deserialize)]
+struct TestParams {
+    /// How far back to run the rule
+    #[serde(default = "default_test_hours")]
+    hours: u64,
+}
+
+fn default_test_hours() -> u64 {
+    24
+}
+
+/// Most messages a dry run reads, the oldest first
+const TEST_LIMIT: usize = 100_000;
+
+#[derive(Serialize)]
+struct TestResult {
+    firings: Vec<Firing>,
+    /// Messages the rule was run over
+    messages: usize,
+    /// Set when there were more than [`TEST_LIMIT`] messages, so only the oldest part of the window was tested
+    truncated: bool,
+}
+
+/// Dry run of a rule over recent messages, showing what would have fired
+#[handler]
+async fn test_rule(
+    db: Data<&MultiplexedConnection>,
+    scope: Data<&Option<Scope>>,
+    req: &Request,
+    Json(rule): Json<Rule>,
+) -> Result<Json<TestResult>> {
+    let mut con = db.clone();
+    let params = req.params::<TestParams>()?;
+    let filter = Filter {
+        start: Some(alert::now_millis().saturating_sub(params.hours * 3_600_000)),
+        limit: Some(TEST_LIMIT + 1),
+        scope: scope.clone(),
+        ..Default::default()
+    };
+    let mut records = crate::search::run(&mut con, &filter)
+        .await
+        .map_err(|e| poem::Error::from((StatusCode::BAD_REQUEST, e)))?;
+    let truncated = records.len() > TEST_LIMIT;
+    records.truncate(TEST_LIMIT);
+    Ok(Json(TestResult {
+        firings: alert::dry_run(rule, &records),
+        messages: records.len(),
+        truncated,
+    }))
+}
+
+#[handler]
+async fn silences(db: Data<&MultiplexedConnection>, scope: Data<&Option<Scope>>) -> Result<Json<Vec<Silence>>> {
+    let mut con = db.clone();
+    let mut silences = alert::silences(&mut con, alert::now_millis()).await?;
+    silences.retain(|s| manages(&scope, &s.matcher));
+    Ok(Json(silences))
+}
+
+#[handler]
+async fn create_silence(
+    db: Data<&MultiplexedConnection>,
+    scope: Data<&Option<Scope>>,
+    Json(mut silence): Json<Silence>,
+) -> Result<Json<Silence>> {
+    if !manages(&scope, &silence.matcher) {
+        return Err(forbidden());
+    }
+    let mut con = db.clone();
+    silence
+        .normalize(alert::now_millis())
+        .map_err(|e| poem::Error::from((StatusCode::BAD_REQUEST, e)))?;
+    silence.id = alert::create_silence(&mut con, &silence).await?;
+    Ok(Json(silence))
+}
+
+#[handler]
+async fn delete_silence(
+    db: Data<&MultiplexedConnection>,
+    scope: Data<&Option<Scope>>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode> {
+    let mut con = db.clone();
+    match alert::silence(&mut con, id).await? {
+        Some(silence) if !manages(&scope, &silence.matcher) => return Err(forbidden()),
+        Some(_) => {}
+        None => return Err(not_found()),
+    }
+    match alert::delete_silence(&mut con, id).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(not_found()),
+    }
+}
+
+#[derive(Deserialize)]
+struct AlertParams {
+    state: Option<String>,
+    limit: Option<usize>,
+}
+
+#[handler]
+async fn alerts(
+    db: Data<&MultiplexedConnection>,
+    scope: Data<&Option<Scope>>,
+    req: &Request,
+) -> Result<Json<Vec<AlertRecord>>> {
+    let mut con = db.clone();
+    let params = req.params::<AlertParams>()?;
+    let limit = params.limit.unwrap_or(1000);
+    let mut alerts = alert::alerts(&mut con, params.state.as_deref(), limit).await?;
+    if let Some(scope) = scope.as_ref() {
+        alerts.retain(|a| scope.allows_host(&a.host));
+    }
+    Ok(Json(alerts))
+}
+
+#[derive(Deserialize)]
+struct AckParams {
+    by: Option<String>,
+}
+
+#[handler]
+async fn acknowledge(
+    db: Data<&MultiplexedConnection>,
+    identity: Data<&Identity>,
+    scope: Data<&Option<Scope>>,
+    Path(id): Path<u64>,
+    req: &Request,
+) -> Result<StatusCode> {
+    let mut con = db.clone();
+    match alert::alert(&mut con, id).await? {
+        Some(alert) if !scope.as_ref().is_none_or(|s| s.allows_host(&alert.host)) => return Err(forbidden()),
+        Some(alert) if alert.state != "firing" => return Err(poem::Error::from_status(StatusCode::CONFLICT)),
+        Some(_) => {}
+        None => return Err(not_found()),
+    }
+    let params = req.params::<AckParams>()?;
+    let by = match identity.method {
+        auth::Method::Anonymous => params.by,
+        _ => Some(identity.name.clone()),
+    };
+    match alert::acknowledge(&mut con, id, by.as_deref(), alert::now_millis()).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        // Resolved since it was looked up
+        false => Err(poem::Error::from_status(StatusCode::CONFLICT)),
+    }
+}
+
+/// Authentication settings, `None` when the API is open to everyone
+type AuthConfig = Option<Arc<auth::Config>>;
+
+fn not_found() -> poem::Error {
+    poem::Error::from_status(StatusCode::NOT_FOUND)
+}
+
+#[derive(Deserialize)]
+struct Login {
+    username: String,
+    password: String,
+}
+
+#[handler]
+async fn login(
+    db: Data<&MultiplexedConnection>,
+    config: Data<&AuthConfig>,
+    base: Data<&BasePath>,
+    req: &Request,
+    Json(login): Json<Login>,
+) -> Result<Response> {
+    let config = config.as_deref().ok_or_else(not_found)?;
+    let mut con = db.clone();
+    let identity = config.password(&login.username, &login.password);
+    let attempt = Identity {
+        name: login.username.clone(),
+        method: auth::Method::Password,
+        role: Default::default(),
+    };
+    let mut event = audit::Event::new(&attempt, remote_ip(req), "login", serde_json::json!({}));
+    event.status = Some(match identity {
+        Some(_) => StatusCode::OK,
+        None => StatusCode::UNAUTHORIZED,
+    }
+    .as_u16());
+    audit::record(&mut con, &event).await?;
+    let Some(identity) = identity else {
+        return Err(poem::Error::from_status(StatusCode::UNAUTHORIZED));
+    };
+    let session = auth::create_session(&mut con, &identity, config.session_ttl).await?;
+    Ok(Json(identity)
+        .with_header("Set-Cookie", config.session_cookie(&session, &base.root()))
+        .into_response())
+}
+
+#[handler]
+async fn logout(
+    db: Data<&MultiplexedConnection>,
+    config: Data<&AuthConfig>,
+    base: Data<&BasePath>,
+    req: &Request,
+) -> Result<Response> {
+    let config = config.as_deref().ok_or_else(not_found)?;
+    if let Some(session) = auth::cookie(req.headers(), auth::SESSION_COOKIE) {
+        let mut con = db.clone();
+        auth::delete_session(&mut con, session).await?;
+    }
+    Ok(StatusCode::NO_CONTENT
+        .with_header("Set-Cookie", config.session_cookie("", &base.root()))
+        .into_response())
+}
+
+#[handler]
+fn me(identity: Data<&Identity>) -> Json<Identity> {
+    Json(identity.clone())
+}
+
+fn oidc_config(config: &AuthConfig) -> Option<(&auth::Config, &auth::Oidc)> {
+    let config = config.as_deref()?;
+    Some((config, config.oidc.as_ref()?))
+}
+
+/// Sends the user to the OIDC issuer to log in
+#[handler]
+async fn oidc_login(
+    db: Data<&MultiplexedConnection>,
+    config: Data<&AuthConfig>,
+    client: Data<&reqwest::Client>,
+    base: Data<&BasePath>,
+) -> Result<Response> {
+    let (config, oidc) = oidc_config(&config).ok_or_else(not_found)?;
+    let discovery = oidc
+        .discover(&client)
+        .await
+        .map_err(|e| poem::Error::from((StatusCode::BAD_GATEWAY, e)))?;
+    let mut con = db.clone();
+    let state = auth::start_oidc(&mut con).await?;
+    let url = oidc
+        .authorize_url(&discovery, &state)
+        .map_err(|e| poem::Error::from((StatusCode::BAD_GATEWAY, e)))?;
+    Ok(Redirect::see_other(url)
+        .with_header("Set-Cookie", config.oidc_state_cookie(&state, &base.root()))
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct OidcCallback {
+    code: String,
+    state: String,
+}
+
+#[handler]
+async fn oidc_callback(
+    db: Data<&MultiplexedConnection>,
+    config: Data<&AuthConfig>,
+    client: Data<&reqwest::Client>,
+    base: Data<&BasePath>,
+    req: &Request,
+) -> Result<Response> {
+    let (config, oidc) = oidc_config(&config).ok_or_else(not_found)?;
+    let params = req.params::<OidcCallback>()?;
+    let mut con = db.clone();
+    let cookie = auth::cookie(req.headers(), auth::OIDC_STATE_COOKIE);
+    auth::finish_oidc(&mut con, &params.state, cookie)
+        .await
+        .map_err(|e| poem::Error::from((StatusCode::BAD_REQUEST, e)))?;
+    let identity = oidc
+        .exchange(&client, &params.code)
+        .await
+        .map_err(|e| poem::Error::from((StatusCode::UNAUTHORIZED, e)))?;
+    info!(user = %identity.name, "Logged in with OIDC");
+    let event = audit::Event {
+        status: Some(StatusCode::OK.as_u16()),
+        ..audit::Event::new(&identity, remote_ip(req), "login", serde_json::json!({}))
+    };
+    audit::record(&mut con, &event).await?;
+    let session = auth::create_session(&mut con, &identity, config.session_ttl).await?;
+    Ok(Redirect::see_other(base.root())
+        .with_header("Set-Cookie", config.session_cookie(&session, &base.root()))
+        .with_header("Set-Cookie", config.oidc_state_cookie("", &base.root()))
+        .into_response())
+}
+
+/// Only lets users with at least `role` through
+fn require<E: Endpoint + 'static>(role: Role, ep: E) -> impl Endpoint {
+    ep.around(move |ep, req| async move {
+        match req.extensions().get::<Identity>() {
+            Some(identity) if identity.role >= role => Ok(ep.call(req).await?.into_response()),
+            _ => Err(poem::Error::from_status(StatusCode::FORBIDDEN)),
+        }
+    })
+}
+
+fn remote_ip(req: &Request) -> Option<String> {
+    req.remote_addr()
+        .as_socket_addr()
+        .map(|addr| addr.ip().to_canonical().to_string())
+}
+
+/// Records an `AuditEvent` for every request, with the query string, path and any JSON body as its parameters
+fn audited<E: Endpoint + 'static>(action: &'static str, ep: E) -> impl Endpoint {
+    ep.around(move |ep, mut req| async move {
+        let body = req.take_body().into_bytes().await?;
+        let query: BTreeMap<String, String> = serde_urlencoded::from_str(req.uri().query().unwrap_or_default()).unwrap_or_default();
+        let mut params = serde_json::json!({ "path": req.uri().path(), "query": query });
+        if let Ok(body) = serde_json::from_slice::<serde_json::Value>(&body) {
+            params["body"] = body;
+        }
+        req.set_body(body);
+        let identity = req.extensions().get::<Identity>().cloned().unwrap_or_else(Identity::anonymous);
+        let mut event = audit::Event::new(&identity, remote_ip(&req), action, params);
+        let mut con = req.extensions().get::<MultiplexedConnection>().cloned();
+
+        let response = match ep.call(req).await {
+            Ok(response) => response.into_response(),
+            Err(e) => e.into_response(),
+        };
+        event.status = Some(response.status().as_u16());
+        if let Some(con) = con.as_mut() {
+            audit::record(con, &event).await?;
+        }
+        Ok::<_, poem::Error>(response)
+    })
+}
+
+/// Records how long a search took in `ezsyslog_search_seconds`, and counts it as failed when it returns an error
+fn timed<E: Endpoint + 'static>(endpoint: &'static str, ep: E) -> impl Endpoint {
+    ep.around(move |ep, req| async move {
+        let started = Instant::now();
+        let response = match ep.call(req).await {
+            Ok(response) => response.into_response(),
+            Err(e) => e.into_response(),
+        };
+        METRICS.search_seconds.with_label_values(&[endpoint]).observe(started.elapsed().as_secs_f64());
+        if response.status().is_client_error() || response.status().is_server_error() {
+            METRICS.search_errors.with_label_values(&[endpoint]).inc();
+        }
+        Ok::<_, poem::Error>(response)
+    })
+}
+
+#[handler]
+fn metrics() -> String {
+    METRICS.render()
+}
+
+/// The process is up and serving HTTP
+#[handler]
+fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Listeners, database and relay backlog, with a 503 when any of them keeps the server from doing its job
+#[handler]
+async fn readyz(db: Data<&MultiplexedConnection>, relay: Data<&Relay>) -> Response {
+    let mut con = db.clone();
+    let readiness = health::check(&mut con, relay.queues()).await;
+    let status = match readiness.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    Json(readiness).with_status(status).into_response()
+}
+
+#[handler]
+async fn audit_events(db: Data<&MultiplexedConnection>, req: &Request) -> Result<Json<Vec<audit::Event>>> {
+    let mut con = db.clone();
+    let filter = req.params::<audit::Filter>()?;
+    Ok(Json(audit::events(&mut con, &filter).await?))
+}
+
+/// Routes anyone may use, the rest need a login when authentication is on
+fn is_public(path: &str) -> bool {
+    matches!(path, "/" | "/index.html" | "/healthz" | "/readyz" | "/login" | "/logout" | "/auth/oidc/login" | "/auth/oidc/callback")
+        || Files::get(path.trim_start_matches('/')).is_some()
+}
+
+/// Works out who made the request and makes them available to handlers as `Data<&Identity>`, along with the
+/// hosts they may see as `Data<&Option<Scope>>`
+async fn authenticate<E: Endpoint>(
+    ep: Arc<E>,
+    mut req: Request,
+    config: AuthConfig,
+    mut con: MultiplexedConnection,
+) -> Result<Response> {
+    let (identity, scope) = match &config {
+        None => (Some(Identity::anonymous()), None),
+        Some(config) => {
+            let mut identity = auth::authenticate(config, &mut con, &req).await?;
+            let scope = identity.as_mut().and_then(|i| config.grant(i));
+            (identity, scope)
+        }
+    };
+    match identity {
+        Some(identity) => {
+            req.extensions_mut().insert(identity);
+            req.extensions_mut().insert(scope);
+        }
+        None if is_public(req.uri().path()) => {}
+        None => return Err(poem::Error::from_status(StatusCode::UNAUTHORIZED)),
+    }
+    Ok(ep.call(req).await?.into_response())
+}
+
 mod serde_redis_graph {
     use std::{collections::HashMap, ops::Deref};
 
@@ -87,7 +761,7 @@ mod serde_redis_graph {
     impl Deref for SerializeProperties<'_> {
         type Target = HashMap<String, Value>;
         fn deref(&self) -> &Self::Target {
-            &self.0
+            self.0
         }
     }
     impl Serialize for SerializeProperties<'_> {
@@ -103,27 +777,6 @@ mod serde_redis_graph {
         }
     }
 
-    pub struct GraphNode<'a>(pub &'a NodeValue);
-    impl Deref for GraphNode<'_> {
-        type Target = NodeValue;
-        fn deref(&self) -> &Self::Target {
-            &self.0
-        }
-    }
-
-    impl Serialize for GraphNode<'_> {
-        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-        where
-            S: Serializer,
-        {
-            let mut state = serializer.serialize_struct("GraphNode", 3)?;
-            state.serialize_field("id", &self.id)?;
-            state.serialize_field("labels", &self.labels)?;
-            state.serialize_field("properties", &SerializeProperties(&self.properties))?;
-            state.end()
-        }
-    }
-
     pub enum SerializeGraphValue {
         Node(NodeValue),
         Scalar(Value),
@@ -180,30 +833,79 @@ mod serde_redis_graph {
     }
 }
 
+/// Counts an open `/events` stream in `ezsyslog_sse_subscribers` until it is dropped
+struct Subscriber;
+
+impl Subscriber {
+    fn new() -> Subscriber {
+        METRICS.sse_subscribers.inc();
+        Subscriber
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        METRICS.sse_subscribers.dec();
+    }
+}
+
+#[derive(Deserialize)]
+struct EventsParams {
+    /// Sends each new message's record instead of only its id
+    #[serde(default)]
+    records: bool,
+}
+
 #[handler]
-fn events(mut sender: Data<&Sender<crate::Signal>>, _req: &Request) -> SSE {
-    println!("New server event stream started");
-    let stream = BroadcastStream::new(sender.subscribe()).filter_map(move |m| {
+fn events(
+    Query(EventsParams { records }): Query<EventsParams>,
+    mut sender: Data<&Sender<crate::Signal>>,
+    scope: Data<&Option<Scope>>,
+) -> SSE {
+    debug!("New server event stream started");
+    let scope = scope.clone();
+    let subscriber = Subscriber::new();
+    let signals = BroadcastStream::new(sender.subscribe())
+        .take_while(|m| !matches!(m, Ok(crate::Signal::Stop)));
+    let events = signals.filter_map(move |m| {
+        let _subscriber = &subscriber;
         let signal = match m {
             Ok(s) => s,
-            Err(e) => {
-                eprintln!("{:?}", e);
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                METRICS.sse_lagged.inc_by(skipped);
+                warn!(skipped, "Event stream lagged");
                 return None;
             }
         };
 
-        println!("Sending new message");
-
         match signal {
-            crate::Signal::NewMessage(m) => {
-                Some(Event::message(m.to_string()).event_type("newMessage"))
+            crate::Signal::NewMessage(stored) => {
+                let ip = stored.ip.to_string();
+                if !scope.as_ref().is_none_or(|s| s.allows(stored.msg.hostname.as_deref(), Some(&ip))) {
+                    return None;
+                }
+                if records {
+                    let record = serde_json::to_string(&Record::from_stored(&stored)).ok()?;
+                    return Some(Event::message(record).event_type("newRecord"));
+                }
+                Some(Event::message(stored.id.to_string()).event_type("newMessage"))
             }
-            crate::Signal::Stop => todo!(),
+            // Ended by `take_while` above
+            crate::Signal::Stop => None,
         }
     });
-    // let stream = tokio_stream::wrappers::IntervalStream::new(tokio::time::interval(Duration::from_secs(1)))
-    //     .map(move |_| Event::message(1.to_string()));
-    SSE::new(stream).keep_alive(Duration::from_secs(15))
+    // poem's own keep-alive would keep the response open after the events end, so they are sent from here and
+    // the stream ends with a `None` after the last event
+    let every = Duration::from_secs(15);
+    let keep_alive = IntervalStream::new(tokio::time::interval_at(tokio::time::Instant::now() + every, every))
+        .map(|_| Some(Event::message("").event_type("keepAlive")));
+    let stream = events
+        .map(Some)
+        .chain(tokio_stream::once(None))
+        .merge(keep_alive)
+        .take_while(Option::is_some)
+        .filter_map(|event| event);
+    SSE::new(stream)
 }
 
 fn extract_data(data: GraphValue) -> serde_redis_graph::SerializeGraphValue {
@@ -219,41 +921,237 @@ fn extract_data(data: GraphValue) -> serde_redis_graph::SerializeGraphValue {
     }
 }
 
+/// CORS for UIs served from elsewhere, from `EZSYSLOG_CORS_ORIGINS`, `EZSYSLOG_CORS_METHODS` and
+/// `EZSYSLOG_CORS_CREDENTIALS`, `None` when no origins are allowed
+fn cors_from_env() -> anyhow::Result<Option<Cors>> {
+    cors(
+        &env::var("EZSYSLOG_CORS_ORIGINS").unwrap_or("http://localhost:3000,https://localhost:3000".to_string()),
+        &env::var("EZSYSLOG_CORS_METHODS").unwrap_or("GET,POST,PUT,DELETE".to_string()),
+        env::var("EZSYSLOG_CORS_CREDENTIALS").map_or(Ok(false), |c| c.parse())?,
+    )
+}
+
+/// CORS for comma separated `origins` and `methods`, sending cookies along when `credentials` is set.
+/// Refuses credentials for any origin (`*`), which would let every site act as a signed in user.
+fn cors(origins: &str, methods: &str, credentials: bool) -> anyhow::Result<Option<Cors>> {
+    let list = |value: &str| -> Vec<String> {
+        value.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+    };
+    let origins = list(origins);
+    if origins.is_empty() {
+        return Ok(None);
+    }
+    if credentials && origins.iter().any(|o| o == "*") {
+        anyhow::bail!("EZSYSLOG_CORS_CREDENTIALS can't be enabled when EZSYSLOG_CORS_ORIGINS allows any origin");
+    }
+    let mut cors = Cors::new().allow_credentials(credentials);
+    for method in list(methods) {
+        cors = cors.allow_method(Method::from_bytes(method.to_uppercase().as_bytes())?);
+    }
+    // Any origin is allowed when none are listed
+    if origins != ["*"] {
+        cors = cors.allow_origins(origins);
+    }
+    Ok(Some(cors))
+}
+
+/// `https://` URL for a request made over plain HTTP to `host`, on `port` unless it is the default
+fn https_url(host: &str, path_and_query: &str, port: u16) -> Option<String> {
+    let mut url = url::Url::parse(&format!("http://{host}{path_and_query}")).ok()?;
+    url.set_scheme("https").ok()?;
+    url.set_port((port != 443).then_some(port)).ok()?;
+    Some(url.to_string())
+}
+
+#[handler]
+fn redirect_to_https(req: &Request, port: Data<&u16>) -> Response {
+    let host = req.header("host").unwrap_or_default();
+    let path_and_query = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    match https_url(host, path_and_query, *port.0) {
+        Some(url) => Redirect::permanent(url).into_response(),
+        None => StatusCode::BAD_REQUEST.into(),
+    }
+}
+
 pub async fn listen(
     mut shutdown: watch::Receiver<()>,
     event_stream: Sender<crate::Signal>,
+    relay: Relay,
 ) -> anyhow::Result<()> {
-    println!("HTTP listener started!");
+    info!("HTTP listener started");
     let con = database::connect().await?;
+    let auth_config: AuthConfig = auth::Config::from_env()?.map(Arc::new);
     let host: String = env::var("EZSYSLOG_HTTP_HOST").unwrap_or("::".to_string());
     let port: String = env::var("EZSYSLOG_HTTP_PORT").unwrap_or("8000".to_string());
     let addr = format!("{host}:{port}");
     let static_files_endpoint = EmbeddedFilesEndpoint::<Files>::new();
-    let cors = Cors::new()
-        .allow_method(Method::GET)
-        .allow_method(Method::POST)
-        .allow_origins(["http://localhost:3000", "https://localhost:3000"])
-        .allow_credentials(true);
+    let base = BasePath::from_env();
+    let cors = cors_from_env()?;
     let app = Route::new()
         .at("*", static_files_endpoint)
-        .at("/search", get(search))
-        .at("/events", get(events))
-        .with(cors)
-        .with(AddData::new(con))
-        .with(AddData::new(event_stream));
-
-    Server::new(TcpListener::bind(addr))
-        .run_with_graceful_shutdown(
-            app,
-            shutdown.changed().map(|_| {
-                println!("HTTP server shutting down...");
-                ()
-            }),
-            None,
+        .at("/", get(index))
+        .at("/index.html", get(index))
+        .at("/search", get(require(Role::Admin, audited("search.cypher", timed("search", search)))))
+        .at("/messages", get(audited("messages.search", timed("messages", messages))))
+        .at("/messages/:id", get(audited("messages.view", message)))
+        .at("/messages/:id/context", get(audited("messages.context", timed("context", context))))
+        .at("/export", get(audited("export", timed("export", download))))
+        .at("/stats/histogram", get(audited("stats.histogram", timed("histogram", histogram))))
+        .at("/stats/top", get(audited("stats.top", timed("top", top))))
+        .at("/hosts", get(hosts))
+        .at("/rules", get(rules).post(require(Role::Operator, audited("rules.create", create_rule))))
+        .at("/rules/test", post(require(Role::Operator, audited("rules.test", test_rule))))
+        .at(
+            "/rules/:name",
+            put(require(Role::Operator, audited("rules.update", update_rule)))
+                .delete(require(Role::Operator, audited("rules.delete", delete_rule))),
+        )
+        .at("/rules/:name/enable", post(require(Role::Operator, audited("rules.enable", enable))))
+        .at("/rules/:name/disable", post(require(Role::Operator, audited("rules.disable", disable))))
+        .at(
+            "/silences",
+            get(silences).post(require(Role::Operator, audited("silences.create", create_silence))),
         )
-        .await?;
+        .at("/silences/:id", delete(require(Role::Operator, audited("silences.delete", delete_silence))))
+        .at("/alerts", get(alerts))
+        .at("/alerts/:id/ack", post(require(Role::Operator, audited("alerts.ack", acknowledge))))
+        .at("/events", get(audited("events", events)))
+        .at("/metrics", get(metrics))
+        .at("/healthz", get(healthz))
+        .at("/readyz", get(readyz))
+        .at("/audit", get(require(Role::Admin, audited("audit.search", audit_events))))
+        .at("/login", post(login))
+        .at("/logout", post(audited("logout", logout)))
+        .at("/me", get(me))
+        .at("/auth/oidc/login", get(oidc_login))
+        .at("/auth/oidc/callback", get(oidc_callback))
+        .around({
+            let (config, con) = (auth_config.clone(), con.clone());
+            move |ep, req| authenticate(ep, req, config.clone(), con.clone())
+        })
+        .with(AddData::new(con))
+        .with(AddData::new(event_stream))
+        .with(AddData::new(auth_config))
+        .with(AddData::new(reqwest::Client::new()))
+        .with(AddData::new(base.clone()))
+        .with(AddData::new(relay));
+    let app = match base.0.is_empty() {
+        true => app.boxed(),
+        false => Route::new().nest(&base.0, app).boxed(),
+    }
+    .with_if(cors.is_some(), cors.unwrap_or_default())
+    .with(Tracing);
 
-    println!("HTTP listener stopped.");
+    let tls = match (env::var("EZSYSLOG_HTTP_TLS_CERT"), env::var("EZSYSLOG_HTTP_TLS_KEY")) {
+        (Ok(cert), Ok(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+        _ => None,
+    };
+    let acceptor = match systemd::tcp_listener("http")? {
+        Some(listener) => TcpAcceptor::from_std(listener)?.boxed(),
+        None => TcpListener::bind(addr).into_acceptor().await?.boxed(),
+    };
+    let acceptor = match tls {
+        Some((cert, key)) => {
+            // Fail at startup rather than on the first connection
+            tls::acceptor(&cert, &key)?;
+            acceptor
+                .rustls(Box::pin(tls::reloading_config(cert, key, Duration::from_secs(30))))
+                .boxed()
+        }
+        None => acceptor,
+    };
+    let addresses: Vec<String> = acceptor.local_addr().iter().map(ToString::to_string).collect();
+    health::listening("http", addresses.join(","));
+    let mut redirect_shutdown = shutdown.clone();
+    let server = Server::new_with_acceptor(acceptor).run_with_graceful_shutdown(
+        app,
+        shutdown.changed().map(|_| {
+            info!("HTTP server shutting down");
+        }),
+        None,
+    );
+
+    match env::var("EZSYSLOG_HTTP_REDIRECT_PORT") {
+        Ok(redirect_port) => {
+            let port: u16 = port.parse()?;
+            let redirect_acceptor = match systemd::tcp_listener("http-redirect")? {
+                Some(listener) => TcpAcceptor::from_std(listener)?.boxed(),
+                None => TcpListener::bind(format!("{host}:{redirect_port}")).into_acceptor().await?.boxed(),
+            };
+            let redirect = Server::new_with_acceptor(redirect_acceptor)
+                .run_with_graceful_shutdown(
+                    redirect_to_https.data(port),
+                    redirect_shutdown.changed().map(|_| ()),
+                    None,
+                );
+            tokio::try_join!(server, redirect)?;
+        }
+        Err(_) => server.await?,
+    }
+
+    info!("HTTP listener stopped");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{cors, events, https_url, index_html, BasePath};
+    use crate::{access::Scope, syslog::Stored, Signal};
+    use poem::{endpoint::make_sync, get, http::header, Endpoint, EndpointExt, Request, Route};
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn redirect_urls() {
+        assert_eq!(https_url("logs.example.com", "/messages?q=a", 443).unwrap(), "https://logs.example.com/messages?q=a");
+        assert_eq!(https_url("logs.example.com:80", "/", 8443).unwrap(), "https://logs.example.com:8443/");
+        assert_eq!(https_url("[::1]", "/", 8443).unwrap(), "https://[::1]:8443/");
+        assert!(https_url("", "/", 443).is_none());
+    }
+
+    #[test]
+    fn base_paths() {
+        assert_eq!(BasePath::new("/"), BasePath::default());
+        assert_eq!(BasePath::new("syslog/"), BasePath("/syslog".to_string()));
+        assert_eq!(BasePath::new("/syslog").root(), "/syslog/");
+        assert_eq!(BasePath::default().root(), "/");
+        let base = BasePath::new("/syslog");
+        assert_eq!(index_html("<html><head><title>", &base), "<html><head><base href=\"/syslog/\"><title>");
+        assert_eq!(index_html("<!doctype html><title>", &base), "<!doctype html><base href=\"/syslog/\"><title>");
+    }
+
+    #[tokio::test]
+    async fn cors_origins() {
+        assert!(cors("", "GET", true).unwrap().is_none());
+        assert!(cors("*", "GET", true).is_err());
+        assert!(cors("https://ui.example.com, *", "GET", true).is_err());
+
+        let request = |origin: &str| Request::builder().header(header::ORIGIN, origin).finish();
+        let any = make_sync(|_| "ok").with(cors("*", "GET", false).unwrap().unwrap());
+        let response = any.call(request("https://evil.example.com")).await.unwrap();
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+
+        let listed = make_sync(|_| "ok").with(cors("https://ui.example.com", "GET", true).unwrap().unwrap());
+        let response = listed.call(request("https://ui.example.com")).await.unwrap();
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://ui.example.com");
+        assert!(listed.call(request("https://evil.example.com")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn events_end_on_stop() {
+        let (sender, _) = tokio::sync::broadcast::channel(4);
+        let app = Route::new().at("/events", get(events)).data(sender.clone()).data(None::<Scope>);
+        let response = app.call(Request::builder().uri_str("/events").finish()).await.unwrap();
+        let stored = Stored {
+            id: 7,
+            ip: "10.0.0.1".parse().unwrap(),
+            msg: syslog_loose::parse_message("<38>Oct 11 22:14:15 web sshd[1]: hello").into(),
+            server_timestamp: 0,
+        };
+        sender.send(Signal::NewMessage(Arc::new(stored))).unwrap();
+        sender.send(Signal::Stop).unwrap();
+        let body = tokio::time::timeout(Duration::from_secs(5), response.into_body().into_string()).await.unwrap();
+        assert_eq!(body.unwrap(), "event: newMessage\ndata: 7\n\n");
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{database, syslog};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, FixedOffset, Local};
+use flate2::read::MultiGzDecoder;
+use futures_util::future::try_join_all;
+use redis::aio::MultiplexedConnection;
+use syslog_loose::{parse_message_with_year, IncompleteDate};
+
+pub struct Options {
+    /// Hostname recorded for every message instead of the one parsed from the line
+    pub hostname: Option<String>,
+    /// Address the messages are recorded as coming from
+    pub address: IpAddr,
+    /// Number of lines stored concurrently
+    pub batch_size: usize,
+}
+
+/// RFC 3164 timestamps have no year, so take it from when the file was last written to. A month after the
+/// modification time can only be from the year before.
+fn resolve_year(modified: DateTime<Local>) -> impl Fn(IncompleteDate) -> i32 + Copy {
+    move |(month, ..)| {
+        if month > modified.month() {
+            modified.year() - 1
+        } else {
+            modified.year()
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<(Box<dyn BufRead>, SystemTime)> {
+    if path.as_os_str() == "-" {
+        return Ok((Box::new(BufReader::new(io::stdin())), SystemTime::now()));
+    }
+    let file = File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+    let modified = file.metadata()?.modified()?;
+    let reader: Box<dyn Read> = if path.extension().is_some_and(|e| e == "gz") {
+        Box::new(MultiGzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    Ok((Box::new(BufReader::new(reader)), modified))
+}
+
+async fn store_batch(
+    con: &MultiplexedConnection,
+    lines: &[String],
+    modified: SystemTime,
+    options: &Options,
+) -> Result<()> {
+    let year = resolve_year(modified.into());
+    let fallback_timestamp = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Unable to get file modification time")
+        .as_millis();
+    try_join_all(lines.iter().map(|line| {
+        let mut con = con.clone();
+        async move {
+            let mut msg = parse_message_with_year(line, year);
+            if let Some(hostname) = &options.hostname {
+                msg.hostname = Some(hostname);
+            }
+            // Backfilled messages are placed at the time they were logged rather than the time of the import
+            let server_timestamp = server_timestamp(msg.timestamp, fallback_timestamp);
+            syslog::store_msg_at(&mut con, msg, &options.address, server_timestamp).await
+        }
+    }))
+    .await?;
+    Ok(())
+}
+
+/// When a line was logged, or `fallback` when it has no timestamp or one before 1970, e.g. from a wrongly inferred
+/// year, which would otherwise wrap around to the far future
+fn server_timestamp(logged: Option<DateTime<FixedOffset>>, fallback: u128) -> u128 {
+    logged.and_then(|t| u128::try_from(t.timestamp_millis()).ok()).unwrap_or(fallback)
+}
+
+async fn import_file(con: &MultiplexedConnection, path: &Path, options: &Options) -> Result<usize> {
+    let (mut reader, modified) = open(path)?;
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(options.batch_size);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        let eof = reader.read_until(b'\n', &mut buf)? == 0;
+        let line = String::from_utf8_lossy(&buf);
+        if !line.trim().is_empty() {
+            batch.push(line.trim_end().to_string());
+        }
+        if batch.len() >= options.batch_size || (eof && !batch.is_empty()) {
+            store_batch(con, &batch, modified, options).await?;
+            count += batch.len();
+            batch.clear();
+            eprint!("\r{}: {count} messages", path.display());
+            io::stderr().flush()?;
+        }
+        if eof {
+            break;
+        }
+    }
+    eprintln!();
+    Ok(count)
+}
+
+/// Stores every line of the given files, oldest first so rotated files (`syslog.2.gz`, `syslog.1`, `syslog`)
+/// are loaded in the order they were written. `-` reads from stdin.
+pub async fn import(files: &[PathBuf], options: &Options) -> Result<usize> {
+    let mut files = files.to_vec();
+    files.sort_by_key(|f| f.metadata().and_then(|m| m.modified()).ok());
+
+    let con = database::connect().await?;
+    let mut total = 0;
+    for file in &files {
+        total += import_file(&con, file, options).await?;
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeZone};
+
+    #[test]
+    fn year_from_modification_time() {
+        let modified = Local.with_ymd_and_hms(2022, 1, 3, 12, 0, 0).unwrap();
+        let year = super::resolve_year(modified);
+        assert_eq!(year((1, 2, 10, 0, 0)), 2022);
+        assert_eq!(year((12, 31, 23, 59, 59)), 2021);
+    }
+
+    #[test]
+    fn timestamps_before_1970() {
+        let logged = |year| Some(Local.with_ymd_and_hms(year, 6, 1, 0, 0, 0).unwrap().fixed_offset());
+        assert_eq!(super::server_timestamp(logged(1969), 42), 42);
+        assert_eq!(super::server_timestamp(None, 42), 42);
+        assert!(super::server_timestamp(logged(2022), 42) > 1_600_000_000_000);
+    }
+}
@@ -0,0 +1,34 @@
+pub mod access;
+pub mod alert;
+pub mod archive;
+pub mod audit;
+pub mod auth;
+pub mod cli;
+pub mod syslog;
+// pub mod netconsole;
+pub mod database;
+pub mod export;
+pub mod file;
+pub mod health;
+pub mod http;
+pub mod import;
+pub mod logging;
+pub mod matcher;
+pub mod metrics;
+pub mod notify;
+pub mod privileges;
+pub mod relay;
+pub mod rollup;
+pub mod search;
+pub mod spool;
+pub mod stats;
+pub mod systemd;
+pub mod tls;
+pub mod utils;
+pub mod watchdog;
+
+#[derive(Debug, Clone)]
+pub enum Signal {
+    NewMessage(std::sync::Arc<syslog::Stored>),
+    Stop
+}
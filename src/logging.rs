@@ -0,0 +1,178 @@
+use std::{
+    env,
+    fmt::{self, Write},
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
+
+use crate::{database, relay::Relay, syslog};
+use anyhow::Result;
+use chrono::Local;
+use syslog_loose::{Message, ProcId, Protocol, SyslogFacility, SyslogSeverity};
+use tokio::sync::{broadcast, mpsc, watch::Receiver};
+use tracing::{
+    field::{Field, Visit},
+    info_span, Event, Instrument, Level, Subscriber,
+};
+use tracing_subscriber::{
+    filter::LevelFilter, layer::Context, prelude::*, registry::LookupSpan, EnvFilter, Layer,
+};
+
+/// Appname of the server's own messages when `EZSYSLOG_SELF_LOG` is set
+pub const APPNAME: &str = "ezsyslog";
+
+/// Span the self log task runs in, its own events are never sent back into the graph
+const SELF_LOG_SPAN: &str = "self_log";
+
+/// Self log lines waiting to be stored, more are dropped
+const QUEUE_SIZE: usize = 1000;
+
+/// One of the server's own log events on its way into the graph
+#[derive(Debug)]
+pub struct Line {
+    severity: SyslogSeverity,
+    text: String,
+}
+
+fn severity(level: &Level) -> SyslogSeverity {
+    match *level {
+        Level::ERROR => SyslogSeverity::SEV_ERR,
+        Level::WARN => SyslogSeverity::SEV_WARNING,
+        Level::INFO => SyslogSeverity::SEV_INFO,
+        _ => SyslogSeverity::SEV_DEBUG,
+    }
+}
+
+/// Formats an event's message followed by its other fields as `key=value`
+#[derive(Default)]
+struct Text {
+    message: String,
+    fields: String,
+}
+
+impl Visit for Text {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => write!(self.message, "{value:?}").unwrap(),
+            name => write!(self.fields, " {name}={value:?}").unwrap(),
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message.push_str(value),
+            name => write!(self.fields, " {name}={value:?}").unwrap(),
+        }
+    }
+}
+
+/// Queues events for [`listen`] to store as messages from [`APPNAME`]
+struct SelfLog {
+    queue: mpsc::Sender<Line>,
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SelfLog {
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if ctx
+            .event_scope(event)
+            .is_some_and(|mut scope| scope.any(|span| span.name() == SELF_LOG_SPAN))
+        {
+            return;
+        }
+        let mut text = Text::default();
+        event.record(&mut text);
+        let line = Line {
+            severity: severity(event.metadata().level()),
+            text: format!("{}: {}{}", event.metadata().target(), text.message, text.fields),
+        };
+        // Dropped rather than slowing down whatever is logging
+        let _ = self.queue.try_send(line);
+    }
+}
+
+/// Sets up logging to stderr at the levels in `EZSYSLOG_LOG` (e.g. `info` or `warn,ezsyslog::http=debug`), as
+/// JSON lines when `EZSYSLOG_LOG_FORMAT=json`. With `EZSYSLOG_SELF_LOG` set to a level, events at that level and
+/// above are also returned to be stored by [`listen`].
+pub fn init() -> Result<Option<mpsc::Receiver<Line>>> {
+    let filter = EnvFilter::try_new(env::var("EZSYSLOG_LOG").unwrap_or("info".to_string()))?;
+    let json = env::var("EZSYSLOG_LOG_FORMAT").is_ok_and(|f| f == "json");
+    let stderr = match json {
+        true => tracing_subscriber::fmt::layer().json().with_writer(std::io::stderr).boxed(),
+        false => tracing_subscriber::fmt::layer().with_writer(std::io::stderr).boxed(),
+    };
+    let (self_log, receiver) = match env::var("EZSYSLOG_SELF_LOG") {
+        Ok(level) => {
+            let (queue, receiver) = mpsc::channel(QUEUE_SIZE);
+            (Some(SelfLog { queue }.with_filter(LevelFilter::from_str(&level)?)), Some(receiver))
+        }
+        Err(_) => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(stderr.with_filter(filter))
+        .with(self_log)
+        .try_init()?;
+    Ok(receiver)
+}
+
+/// Stores the server's own log events as messages from [`APPNAME`] on `localhost`
+pub async fn listen(
+    mut shutdown_signal: Receiver<()>,
+    mut lines: mpsc::Receiver<Line>,
+    sender: broadcast::Sender<crate::Signal>,
+    relay: Relay,
+) -> Result<()> {
+    async move {
+        let mut con = database::connect().await?;
+        let local = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let procid = std::process::id().to_string();
+        loop {
+            let line = tokio::select! {
+                _ = shutdown_signal.changed() => break,
+                line = lines.recv() => match line {
+                    Some(line) => line,
+                    None => break,
+                },
+            };
+            let msg = Message {
+                protocol: Protocol::RFC5424(1),
+                facility: Some(SyslogFacility::LOG_DAEMON),
+                severity: Some(line.severity),
+                timestamp: Some(Local::now().into()),
+                hostname: None,
+                appname: Some(APPNAME),
+                procid: Some(ProcId::Name(procid.as_str())),
+                msgid: None,
+                structured_data: vec![],
+                msg: line.text.as_str(),
+            };
+            if let Err(e) = syslog::ingest(&mut con, msg, &local, &sender, &relay).await {
+                tracing::error!("Unable to store own log message: {e}");
+            }
+        }
+        Ok(())
+    }
+    .instrument(info_span!(SELF_LOG_SPAN))
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SelfLog, SELF_LOG_SPAN};
+    use syslog_loose::SyslogSeverity;
+    use tokio::sync::mpsc;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn self_log() {
+        let (queue, mut lines) = mpsc::channel(10);
+        let subscriber = tracing_subscriber::registry().with(SelfLog { queue });
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(listener = "udp", "Unable to parse syslog message");
+            tracing::info_span!(SELF_LOG_SPAN).in_scope(|| tracing::error!("Not stored again"));
+        });
+        let line = lines.try_recv().unwrap();
+        assert_eq!(line.severity, SyslogSeverity::SEV_WARNING);
+        assert_eq!(line.text, "ezsyslog::logging::tests: Unable to parse syslog message listener=\"udp\"");
+        assert!(lines.try_recv().is_err());
+    }
+}
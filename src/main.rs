@@ -1,29 +1,63 @@
 use anyhow::Result;
+use clap::Parser;
+use ezsyslog::{alert, archive, audit, cli, database, file, health, http, logging, privileges, relay::Relay, syslog, systemd, watchdog};
 use tokio::sync::{broadcast, watch};
-
-mod syslog;
-// mod netconsole;
-mod database;
-mod http;
-mod utils;
-
-#[derive(Debug, Clone)]
-pub enum Signal {
-    NewMessage(usize),
-    Stop
-}
+use tracing::{info, info_span, Instrument};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let (tx, _rx) = broadcast::channel(16);
+    let cli = cli::Cli::parse();
+    match cli.command.unwrap_or(cli::Command::Serve) {
+        cli::Command::Serve => serve().await,
+        command => cli::run(command).await,
+    }
+}
+
+async fn serve() -> Result<()> {
+    let self_log = logging::init()?;
+    systemd::init()?;
+    let privileges = privileges::Config::from_env()?;
+    let mut con = database::connect().await?;
+    for name in database::migrate(&mut con).await? {
+        info!(migration = %name, "Applied migration");
+    }
+
+    let relay = Relay::from_env()?;
+    let (tx, _rx) = broadcast::channel(1024);
     let (shutdown, sigint) = watch::channel(());
-    let handles = [
-        tokio::spawn(syslog::listen(sigint.clone(), tx.clone())),
-        tokio::spawn(http::listen(sigint, tx)),
-    ];
+    let listener = |kind: &str| info_span!("listener", kind);
+    let udp = syslog::listen(sigint.clone(), tx.clone(), relay.clone());
+    let mut handles = vec![tokio::spawn(health::supervise("udp", udp).instrument(listener("udp")))];
+    if syslog::agent_port_from_env().is_some() {
+        let agent = syslog::listen_agent(sigint.clone(), tx.clone(), relay.clone());
+        handles.push(tokio::spawn(health::supervise("agent", agent).instrument(listener("agent"))));
+    }
+    if file::patterns_from_env().is_some() {
+        let files = file::listen(sigint.clone(), tx.clone(), relay.clone());
+        handles.push(tokio::spawn(health::supervise("file", files).instrument(listener("file"))));
+    }
+    if let Some(lines) = self_log {
+        handles.push(tokio::spawn(logging::listen(sigint.clone(), lines, tx.clone(), relay.clone())));
+    }
+    handles.push(tokio::spawn(health::supervise("alert", alert::listen(sigint.clone(), tx.clone()))));
+    handles.push(tokio::spawn(audit::listen(sigint.clone())));
+    if watchdog::config_path_from_env().is_some() {
+        let watchdog = watchdog::listen(sigint.clone(), tx.clone());
+        handles.push(tokio::spawn(health::supervise("watchdog", watchdog)));
+    }
+    if archive::retention_from_env().is_some() {
+        handles.push(tokio::spawn(archive::listen(sigint.clone())));
+    }
+    handles.push(tokio::spawn(systemd::watchdog(sigint.clone())));
+    let server = http::listen(sigint, tx, relay);
+    handles.push(tokio::spawn(health::supervise("http", server).instrument(listener("http"))));
+
+    privileges::drop_after_listening(privileges).await?;
+    tokio::spawn(systemd::notify_ready());
 
     ctrlc::set_handler(move || {
-        println!("Shutting down");
+        info!("Shutting down");
+        systemd::notify_stopping();
         shutdown.send(()).expect("Could not propigate SIGINT");
     })
     .expect("Error setting Ctrl-C handler");
@@ -0,0 +1,141 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use syslog_loose::Message;
+
+/// Conditions on an incoming message, every condition that is set has to match.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Matcher {
+    /// Matches this severity or anything more severe, e.g. `warning` also matches `err`
+    #[serde(deserialize_with = "known_severity")]
+    pub severity: Option<String>,
+    pub facility: Option<String>,
+    pub hostname: Option<String>,
+    pub appname: Option<String>,
+    pub ip: Option<IpAddr>,
+    /// Regular expression searched for in the message body
+    pub msg: Option<Pattern>,
+    /// Structured data params that must be present with these values, in any element
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, String>,
+}
+
+/// A compiled regular expression that (de)serializes as its source
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Regex::new(&source)
+            .map(Pattern)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Severity names from most to least severe, as stored on `Severity` nodes
+pub const SEVERITIES: [&str; 8] = [
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+pub fn severity_level(name: &str) -> Option<usize> {
+    SEVERITIES.iter().position(|s| *s == name)
+}
+
+/// A misspelled severity would otherwise match nothing without any error
+fn known_severity<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let severity = Option::<String>::deserialize(deserializer)?;
+    match &severity {
+        Some(name) if severity_level(name).is_none() => Err(serde::de::Error::custom(format!(
+            "Unknown severity {name}, expected one of {}",
+            SEVERITIES.join(", ")
+        ))),
+        _ => Ok(severity),
+    }
+}
+
+fn equals<S: AsRef<str>>(value: &Option<S>, expected: &Option<String>) -> bool {
+    match expected {
+        None => true,
+        Some(expected) => value.as_ref().is_some_and(|v| v.as_ref() == expected),
+    }
+}
+
+impl Matcher {
+    pub fn matches<S: AsRef<str> + Ord + Clone>(&self, msg: &Message<S>, ip: &IpAddr) -> bool {
+        let severity = match (&self.severity, msg.severity) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(max), Some(severity)) => {
+                severity_level(severity.as_str()) <= severity_level(max)
+            }
+        };
+        severity
+            && equals(&msg.facility.map(|f| f.as_str()), &self.facility)
+            && equals(&msg.hostname, &self.hostname)
+            && equals(&msg.appname, &self.appname)
+            // IPv4 senders of the default `::` listener arrive as IPv4-mapped IPv6 addresses
+            && self.ip.is_none_or(|i| i.to_canonical() == ip.to_canonical())
+            && self.msg.as_ref().is_none_or(|p| p.0.is_match(msg.msg.as_ref()))
+            && self.params.iter().all(|(name, value)| {
+                msg.structured_data.iter().any(|element| {
+                    element
+                        .params
+                        .iter()
+                        .any(|(k, v)| k.as_ref() == name && v.as_ref() == value)
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Matcher, Pattern};
+    use regex::Regex;
+    use syslog_loose::parse_message;
+
+    #[test]
+    fn matches() {
+        let ip = "10.0.0.1".parse().unwrap();
+        let msg = parse_message("<11>Oct 11 22:14:15 router1 sshd[12]: Failed password");
+        let matcher = Matcher {
+            severity: Some("warning".to_string()),
+            hostname: Some("router1".to_string()),
+            msg: Some(Pattern(Regex::new("^Failed").unwrap())),
+            ..Default::default()
+        };
+        assert!(matcher.matches(&msg, &ip));
+        assert!(!Matcher { severity: Some("crit".to_string()), ..matcher.clone() }.matches(&msg, &ip));
+        assert!(!Matcher { appname: Some("cron".to_string()), ..matcher }.matches(&msg, &ip));
+
+        let by_ip: Matcher = serde_json::from_str(r#"{"ip": "10.0.0.1"}"#).unwrap();
+        assert!(by_ip.matches(&msg, &"::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!by_ip.matches(&msg, &"::ffff:10.0.0.2".parse().unwrap()));
+        assert!(serde_json::from_str::<Matcher>(r#"{"severity": "warn"}"#).is_err());
+    }
+
+    #[test]
+    fn structured_data_params() {
+        let ip = "10.0.0.1".parse().unwrap();
+        let msg = parse_message("<165>1 2003-10-11T22:14:15.003Z host app - ID47 [exampleSDID@32473 iut=\"3\" eventID=\"1011\"] entry");
+        let mut matcher = Matcher::default();
+        matcher.params.insert("eventID".to_string(), "1011".to_string());
+        assert!(matcher.matches(&msg, &ip));
+        matcher.params.insert("iut".to_string(), "4".to_string());
+        assert!(!matcher.matches(&msg, &ip));
+    }
+}
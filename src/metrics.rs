@@ -0,0 +1,147 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+/// Ingest and query health, served in the Prometheus text format on `/metrics`
+pub struct Metrics {
+    registry: Registry,
+    /// Datagrams, agent frames and file lines per listener
+    pub received: IntCounterVec,
+    pub received_bytes: IntCounterVec,
+    pub parsed: IntCounterVec,
+    pub failed: IntCounterVec,
+    /// Time to store a message in the graph
+    pub store_seconds: Histogram,
+    /// Messages per agent read or file poll
+    pub batch_size: HistogramVec,
+    /// Messages waiting to be relayed, per upstream
+    pub relay_queue: IntGaugeVec,
+    pub relay_dropped: IntCounterVec,
+    pub sse_subscribers: IntGauge,
+    /// New message events an SSE subscriber fell too far behind to get
+    pub sse_lagged: IntCounter,
+    pub search_seconds: HistogramVec,
+    pub search_errors: IntCounterVec,
+    pub retention_deleted: IntCounter,
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(registry: &Registry, metric: T) -> T {
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("Metric registered twice");
+    metric
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("ezsyslog".to_string()), None).unwrap();
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            register(
+                &registry,
+                IntCounterVec::new(Opts::new(name, help), labels).unwrap(),
+            )
+        };
+        Metrics {
+            received: counter("received_total", "Messages received", &["listener"]),
+            received_bytes: counter(
+                "received_bytes_total",
+                "Bytes of messages received",
+                &["listener"],
+            ),
+            parsed: counter("parsed_total", "Messages parsed", &["listener"]),
+            failed: counter(
+                "parse_failures_total",
+                "Messages that could not be parsed",
+                &["listener"],
+            ),
+            store_seconds: register(
+                &registry,
+                Histogram::with_opts(
+                    HistogramOpts::new("store_seconds", "Time to store a message")
+                        .buckets(exponential_buckets(0.0005, 2.0, 14).unwrap()),
+                )
+                .unwrap(),
+            ),
+            batch_size: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new("batch_size", "Messages per agent read or file poll")
+                        .buckets(exponential_buckets(1.0, 4.0, 8).unwrap()),
+                    &["listener"],
+                )
+                .unwrap(),
+            ),
+            relay_queue: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("relay_queue_depth", "Messages waiting to be relayed"),
+                    &["upstream"],
+                )
+                .unwrap(),
+            ),
+            relay_dropped: counter(
+                "relay_dropped_total",
+                "Messages dropped because a relay queue was full",
+                &["upstream"],
+            ),
+            sse_subscribers: register(
+                &registry,
+                IntGauge::new("sse_subscribers", "Open /events streams").unwrap(),
+            ),
+            sse_lagged: register(
+                &registry,
+                IntCounter::new(
+                    "sse_lagged_total",
+                    "Events skipped by /events streams that fell behind",
+                )
+                .unwrap(),
+            ),
+            search_seconds: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new("search_seconds", "Time to answer a search")
+                        .buckets(exponential_buckets(0.001, 2.0, 15).unwrap()),
+                    &["endpoint"],
+                )
+                .unwrap(),
+            ),
+            search_errors: counter("search_errors_total", "Searches that failed", &["endpoint"]),
+            retention_deleted: register(
+                &registry,
+                IntCounter::new("retention_deleted_total", "Messages deleted by retention")
+                    .unwrap(),
+            ),
+            registry,
+        }
+    }
+
+    /// Every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+        metrics.received.with_label_values(&["udp"]).inc();
+        metrics.store_seconds.observe(0.002);
+        let text = metrics.render();
+        assert!(text.contains("ezsyslog_received_total{listener=\"udp\"} 1"));
+        assert!(text.contains("ezsyslog_store_seconds_count 1"));
+        assert!(text.contains("# TYPE ezsyslog_sse_subscribers gauge"));
+    }
+}
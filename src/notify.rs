@@ -0,0 +1,428 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    env, fs,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message as Email, Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, process::Command, time::sleep};
+use tracing::{error, warn};
+
+/// What is sent to every notifier when an alert fires or resolves
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub alert: u64,
+    pub rule: String,
+    pub host: String,
+    /// `firing` or `resolved`
+    pub state: String,
+    /// Milliseconds since the epoch
+    pub timestamp: u64,
+    /// Body of the message that triggered the alert
+    pub message: Option<String>,
+}
+
+impl Notification {
+    /// Notifications with the same key within a notifier's dedup window are only sent once
+    fn dedup_key(&self) -> String {
+        format!("{}\0{}\0{}", self.rule, self.host, self.state)
+    }
+
+    fn field(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "alert" => self.alert.to_string(),
+            "rule" => self.rule.clone(),
+            "host" => self.host.clone(),
+            "state" => self.state.clone(),
+            "timestamp" => self.timestamp.to_string(),
+            "message" => self.message.clone().unwrap_or_default(),
+            _ => return None,
+        })
+    }
+}
+
+const SUMMARY: &str = "[{{state}}] {{rule}} on {{host}}: {{message}}";
+
+/// Replaces `{{field}}` with the notification's fields. With `json` the values are escaped to be placed inside
+/// a JSON string.
+fn render(template: &str, notification: &Notification, json: bool) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = rest[start + 2..start + end].trim();
+        match notification.field(name) {
+            Some(value) if json => {
+                let quoted = serde_json::to_string(&value).unwrap();
+                out.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            Some(value) => out.push_str(&value),
+            None => out.push_str(&rest[start..start + end + 2]),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    Slack,
+    Mattermost,
+    Teams,
+}
+
+impl Preset {
+    fn template(self) -> &'static str {
+        match self {
+            Preset::Slack | Preset::Mattermost => r#"{"text": "[{{state}}] {{rule}} on {{host}}: {{message}}"}"#,
+            Preset::Teams => r#"{"@type": "MessageCard", "summary": "{{rule}} {{state}}", "title": "[{{state}}] {{rule}} on {{host}}", "text": "{{message}}"}"#,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    #[default]
+    None,
+    Starttls,
+    Tls,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Kind {
+    /// POSTs JSON, the notification itself unless a `preset` or `template` is given
+    Webhook {
+        url: String,
+        preset: Option<Preset>,
+        template: Option<String>,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+    /// Sends an email through a relay
+    Smtp {
+        relay: String,
+        port: Option<u16>,
+        #[serde(default)]
+        security: SmtpSecurity,
+        username: Option<String>,
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+    },
+    /// Runs a command with the notification as JSON on stdin
+    Exec {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RateLimit {
+    pub count: usize,
+    /// Seconds
+    pub per: u64,
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_dedup() -> u64 {
+    300
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    pub kind: Kind,
+    /// Extra attempts after a failed delivery
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    pub rate_limit: Option<RateLimit>,
+    /// Seconds during which a repeat of the same rule, host and state is not sent again
+    #[serde(default = "default_dedup")]
+    pub dedup: u64,
+}
+
+#[derive(Default)]
+struct Limits {
+    /// When each notification within the rate limit period was sent
+    sent: VecDeque<u64>,
+    /// When each dedup key was last sent
+    seen: HashMap<String, u64>,
+}
+
+pub struct Notifier {
+    config: Config,
+    limits: Mutex<Limits>,
+    http: reqwest::Client,
+}
+
+impl Notifier {
+    pub fn new(config: Config) -> Notifier {
+        Notifier {
+            config,
+            limits: Mutex::default(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Whether a notification should go out now, recording it if so
+    fn allow(&self, notification: &Notification, now: u64) -> bool {
+        let mut limits = self.limits.lock().unwrap();
+        let key = notification.dedup_key();
+        let dedup = self.config.dedup * 1000;
+        if limits.seen.get(&key).is_some_and(|t| now.saturating_sub(*t) < dedup) {
+            return false;
+        }
+        if let Some(limit) = self.config.rate_limit {
+            let start = now.saturating_sub(limit.per * 1000);
+            while limits.sent.front().is_some_and(|t| *t < start) {
+                limits.sent.pop_front();
+            }
+            if limits.sent.len() >= limit.count {
+                return false;
+            }
+            limits.sent.push_back(now);
+        }
+        limits.seen.retain(|_, t| now.saturating_sub(*t) < dedup);
+        limits.seen.insert(key, now);
+        true
+    }
+
+    pub async fn send(&self, notification: &Notification) -> Result<()> {
+        match &self.config.kind {
+            Kind::Webhook { url, preset, template, headers } => {
+                let body = match (template, preset) {
+                    (Some(template), _) => render(template, notification, true),
+                    (None, Some(preset)) => render(preset.template(), notification, true),
+                    (None, None) => serde_json::to_string(notification)?,
+                };
+                let mut request = self
+                    .http
+                    .post(url)
+                    .header("Content-Type", "application/json")
+                    .body(body);
+                for (name, value) in headers {
+                    request = request.header(name, value);
+                }
+                request.send().await?.error_for_status()?;
+            }
+            Kind::Smtp { relay, port, security, username, password, from, to } => {
+                let mut email = Email::builder()
+                    .from(from.parse::<Mailbox>()?)
+                    .subject(render("[{{state}}] {{rule}} on {{host}}", notification, false));
+                for to in to {
+                    email = email.to(to.parse::<Mailbox>()?);
+                }
+                let email = email.body(format!(
+                    "{}\n\n{}\n",
+                    render(SUMMARY, notification, false),
+                    serde_json::to_string_pretty(notification)?
+                ))?;
+                let mut transport = match security {
+                    SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(relay),
+                    SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(relay)?,
+                    SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(relay)?,
+                };
+                if let Some(port) = port {
+                    transport = transport.port(*port);
+                }
+                if let (Some(username), Some(password)) = (username, password) {
+                    transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+                }
+                transport.build().send(email).await?;
+            }
+            Kind::Exec { command, args } => {
+                let mut child = Command::new(command)
+                    .args(args)
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .with_context(|| format!("Unable to run {command}"))?;
+                let mut stdin = child.stdin.take().unwrap();
+                stdin.write_all(&serde_json::to_vec(notification)?).await?;
+                drop(stdin);
+                let status = child.wait().await?;
+                if !status.success() {
+                    bail!("{command} exited with {status}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn deliver(&self, notification: &Notification) {
+        let mut backoff = Duration::from_secs(1);
+        for attempt in 0..=self.config.retries {
+            match self.send(notification).await {
+                Ok(()) => return,
+                Err(e) if attempt < self.config.retries => {
+                    warn!(rule = %notification.rule, ?backoff, "Unable to send notification, retrying: {e}");
+                    sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => error!(rule = %notification.rule, "Giving up on notification: {e}"),
+            }
+        }
+    }
+}
+
+/// Every configured notifier, read from the JSON file in `EZSYSLOG_NOTIFIERS`
+#[derive(Clone, Default)]
+pub struct Notifiers {
+    notifiers: Vec<Arc<Notifier>>,
+}
+
+impl Notifiers {
+    pub fn from_env() -> Result<Notifiers> {
+        let path = match env::var("EZSYSLOG_NOTIFIERS") {
+            Ok(path) => path,
+            Err(_) => return Ok(Notifiers::default()),
+        };
+        let data = fs::read(&path).with_context(|| format!("Unable to read notifiers from {path}"))?;
+        let configs: Vec<Config> = serde_json::from_slice(&data)?;
+        Ok(Notifiers {
+            notifiers: configs.into_iter().map(|c| Arc::new(Notifier::new(c))).collect(),
+        })
+    }
+
+    /// Sends in the background so a slow notifier doesn't hold up the alert engine
+    pub fn notify(&self, notification: Notification) {
+        for notifier in &self.notifiers {
+            if !notifier.allow(&notification, notification.timestamp) {
+                continue;
+            }
+            let notifier = notifier.clone();
+            let notification = notification.clone();
+            tokio::spawn(async move { notifier.deliver(&notification).await });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, Config, Notification, Notifier};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    fn notification() -> Notification {
+        Notification {
+            alert: 7,
+            rule: "ssh".to_string(),
+            host: "router1".to_string(),
+            state: "firing".to_string(),
+            timestamp: 0,
+            message: Some("Failed \"password\"".to_string()),
+        }
+    }
+
+    fn notifier(config: &str) -> Notifier {
+        Notifier::new(serde_json::from_str::<Config>(config).unwrap())
+    }
+
+    #[test]
+    fn templates() {
+        let n = notification();
+        assert_eq!(render("{{rule}} {{ host }} {{nope}}", &n, false), "ssh router1 {{nope}}");
+        assert_eq!(render("a {{x", &n, false), "a {{x");
+        assert_eq!(render(r#"{"text": "{{message}}"}"#, &n, true), r#"{"text": "Failed \"password\""}"#);
+    }
+
+    #[test]
+    fn dedup_and_rate_limit() {
+        let notifier = notifier(r#"{"type": "exec", "command": "true", "dedup": 60, "rate_limit": {"count": 2, "per": 600}}"#);
+        let mut n = notification();
+        assert!(notifier.allow(&n, 0));
+        assert!(!notifier.allow(&n, 30_000));
+        assert!(notifier.allow(&n, 60_000));
+        n.host = "router2".to_string();
+        assert!(!notifier.allow(&n, 61_000));
+        assert!(notifier.allow(&n, 601_000));
+    }
+
+    #[tokio::test]
+    async fn webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 4096];
+            while !String::from_utf8_lossy(&request).contains("}") {
+                let len = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let notifier = notifier(&format!(r#"{{"type": "webhook", "url": "{url}", "preset": "slack"}}"#));
+        notifier.send(&notification()).await.unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hook"));
+        assert!(request.ends_with(r#"{"text": "[firing] ssh on router1: Failed \"password\""}"#));
+    }
+
+    #[tokio::test]
+    async fn smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream.get_mut().write_all(b"220 mock ESMTP\r\n").await.unwrap();
+            let mut transcript = String::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                transcript.push_str(&line);
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    stream.get_mut().write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                stream.get_mut().write_all(reply).await.unwrap();
+            }
+            transcript
+        });
+
+        let notifier = notifier(&format!(
+            r#"{{"type": "smtp", "relay": "127.0.0.1", "port": {port}, "from": "ezsyslog@example.com", "to": ["ops@example.com"]}}"#
+        ));
+        notifier.send(&notification()).await.unwrap();
+        let transcript = server.await.unwrap();
+        assert!(transcript.contains("RCPT TO:<ops@example.com>"));
+        assert!(transcript.contains("Subject: [firing] ssh on router1"));
+    }
+}
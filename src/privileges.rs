@@ -0,0 +1,304 @@
+use std::{
+    env, fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::health;
+use anyhow::{bail, Context, Result};
+use nix::{
+    libc,
+    unistd::{self, Gid, Group, Uid, User},
+};
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+use tracing::{info, warn};
+
+/// Syscalls the server never needs once it is running, refused with `EPERM` when `EZSYSLOG_SECCOMP=true`
+const DENIED: &[i64] = &[
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_adjtimex,
+    libc::SYS_bpf,
+    libc::SYS_chroot,
+    libc::SYS_clock_settime,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_init_module,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_mount,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_quotactl,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_setdomainname,
+    libc::SYS_setgid,
+    libc::SYS_setgroups,
+    libc::SYS_sethostname,
+    libc::SYS_setns,
+    libc::SYS_setregid,
+    libc::SYS_setresgid,
+    libc::SYS_setresuid,
+    libc::SYS_setreuid,
+    libc::SYS_settimeofday,
+    libc::SYS_setuid,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+    libc::SYS_userfaultfd,
+];
+
+/// Settings naming files the server opens again while running, out of reach once `chroot`ed:
+/// the reloaded TLS certificate and alert rules, followed logs and their state, archives and the database socket
+const REOPENED: &[&str] = &[
+    "EZSYSLOG_HTTP_TLS_CERT",
+    "EZSYSLOG_HTTP_TLS_KEY",
+    "EZSYSLOG_ALERT_RULES",
+    "EZSYSLOG_FILE_PATHS",
+    "EZSYSLOG_ARCHIVE_DIR",
+    "EZSYSLOG_DB_SOCKET",
+];
+
+/// What to give up once every listener is bound
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+    chroot: Option<PathBuf>,
+    seccomp: bool,
+}
+
+/// The uid and primary group of the passwd entry for a name or uid
+fn passwd(spec: &str) -> Result<Option<(Uid, Gid)>> {
+    let user = match spec.parse() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid))?,
+        Err(_) => User::from_name(spec)?,
+    };
+    Ok(user.map(|user| (user.uid, user.gid)))
+}
+
+/// A user by name or uid, with their primary group when they have a passwd `entry`
+fn user(spec: &str, entry: Option<(Uid, Gid)>) -> Result<(Uid, Option<Gid>)> {
+    match (entry, spec.parse()) {
+        (Some((uid, gid)), _) => Ok((uid, Some(gid))),
+        (None, Ok(uid)) => Ok((Uid::from_raw(uid), None)),
+        (None, Err(_)) => bail!("No user {spec}"),
+    }
+}
+
+/// A group by gid, or by name with its `entry` from the group database
+fn group(spec: &str, entry: impl FnOnce() -> Result<Option<Gid>>) -> Result<Gid> {
+    if let Ok(gid) = spec.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+    entry()?.with_context(|| format!("No group {spec}"))
+}
+
+/// Permission bits as they apply to others, shifted over for a file's owner or group
+const READ: u32 = 0o4;
+const WRITE: u32 = 0o2;
+const SEARCH: u32 = 0o1;
+
+/// Whether `uid` and `gid` have all `wanted` permissions on a file with `mode`, `owner` and `group`
+fn permits(mode: u32, owner: u32, group: u32, uid: Uid, gid: Gid, wanted: u32) -> bool {
+    let shift = if owner == uid.as_raw() {
+        6
+    } else if group == gid.as_raw() {
+        3
+    } else {
+        0
+    };
+    uid.is_root() || (mode >> shift) & wanted == wanted
+}
+
+/// Fails unless `uid` and `gid` have all `wanted` permissions on `path`
+fn check_access(path: &Path, uid: Uid, gid: Gid, wanted: u32) -> Result<()> {
+    let meta = fs::metadata(path).with_context(|| format!("Unable to check {}", path.display()))?;
+    if !permits(meta.mode(), meta.uid(), meta.gid(), uid, gid, wanted) {
+        bail!("User {uid} in group {gid} lacks access to {} (mode {:o})", path.display(), meta.mode() & 0o777);
+    }
+    Ok(())
+}
+
+/// The closest existing directory `path` would be created in
+fn existing_parent(path: &Path) -> &Path {
+    path.ancestors()
+        .skip(1)
+        .map(|dir| if dir.as_os_str().is_empty() { Path::new(".") } else { dir })
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("/"))
+}
+
+impl Config {
+    /// Reads `EZSYSLOG_USER` and `EZSYSLOG_GROUP` (names or ids, the group defaults to the user's primary group),
+    /// `EZSYSLOG_CHROOT` and `EZSYSLOG_SECCOMP`. Names are looked up now, while `/etc/passwd` is still reachable.
+    pub fn from_env() -> Result<Config> {
+        let user = env::var("EZSYSLOG_USER").ok().map(|u| user(&u, passwd(&u)?)).transpose()?;
+        let gid = match env::var("EZSYSLOG_GROUP") {
+            Ok(g) => Some(group(&g, || Ok(Group::from_name(&g)?.map(|group| group.gid)))?),
+            Err(_) => match user {
+                Some((uid, None)) => bail!("User {uid} has no primary group, set EZSYSLOG_GROUP"),
+                Some((_, gid)) => gid,
+                None => None,
+            },
+        };
+        let config = Config {
+            uid: user.map(|(uid, _)| uid),
+            gid,
+            chroot: env::var_os("EZSYSLOG_CHROOT").map(PathBuf::from),
+            seccomp: env::var("EZSYSLOG_SECCOMP").is_ok_and(|s| s == "true"),
+        };
+        config.check_reopened()?;
+        Ok(config)
+    }
+
+    /// Refuses a chroot together with any of the [`REOPENED`] settings, and checks that the user switched to can
+    /// still read or write the files they name
+    fn check_reopened(&self) -> Result<()> {
+        if self.chroot.is_some() {
+            if let Some(name) = REOPENED.iter().find(|name| env::var_os(name).is_some()) {
+                bail!("{name} names a file opened after EZSYSLOG_CHROOT hides it, unset one of them");
+            }
+        }
+        let Some(uid) = self.uid.filter(|uid| !uid.is_root()) else {
+            return Ok(());
+        };
+        let gid = self.gid.unwrap_or_else(Gid::current);
+        for name in ["EZSYSLOG_HTTP_TLS_CERT", "EZSYSLOG_HTTP_TLS_KEY", "EZSYSLOG_ALERT_RULES"] {
+            if let Some(path) = env::var_os(name) {
+                check_access(Path::new(&path), uid, gid, READ)?;
+            }
+        }
+        if let Some(socket) = env::var_os("EZSYSLOG_DB_SOCKET") {
+            // Connecting to a unix socket takes write permission on it
+            check_access(Path::new(&socket), uid, gid, WRITE)?;
+        }
+        if let Some(dir) = env::var_os("EZSYSLOG_ARCHIVE_DIR") {
+            let dir = Path::new(&dir);
+            check_access(if dir.exists() { dir } else { existing_parent(dir) }, uid, gid, WRITE | SEARCH)?;
+        }
+        if let Some(patterns) = crate::file::patterns_from_env() {
+            let state = env::var_os("EZSYSLOG_FILE_STATE").unwrap_or("ezsyslog-files.json".into());
+            check_access(existing_parent(Path::new(&state)), uid, gid, WRITE | SEARCH)?;
+            for pattern in &patterns {
+                for path in glob::glob(pattern)?.flatten() {
+                    check_access(&path, uid, gid, READ)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&self) -> Result<()> {
+        if let Some(dir) = &self.chroot {
+            unistd::chroot(dir).with_context(|| format!("Unable to chroot to {}", dir.display()))?;
+            unistd::chdir("/")?;
+            info!(dir = %dir.display(), "Changed root directory");
+        }
+        if let Some(gid) = self.gid {
+            unistd::setgroups(&[gid]).context("Unable to drop supplementary groups")?;
+            unistd::setgid(gid).with_context(|| format!("Unable to switch to group {gid}"))?;
+        }
+        if let Some(uid) = self.uid {
+            unistd::setuid(uid).with_context(|| format!("Unable to switch to user {uid}"))?;
+            if !uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+                bail!("Still able to switch back to root");
+            }
+        }
+        if self.uid.is_some() || self.gid.is_some() {
+            info!(uid = %Uid::current(), gid = %Gid::current(), "Dropped privileges");
+        }
+        if self.seccomp {
+            seccompiler::apply_filter_all_threads(&filter()?).context("Unable to install seccomp filter")?;
+            info!("Installed seccomp filter");
+        }
+        Ok(())
+    }
+}
+
+/// Refuses the [`DENIED`] syscalls and allows everything else
+fn filter() -> Result<BpfProgram> {
+    let rules = DENIED.iter().map(|&call| (call, vec![])).collect();
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        std::env::consts::ARCH.try_into()?,
+    )?;
+    Ok(filter.try_into()?)
+}
+
+/// Waits for every listener to be bound, then `chroot`s, switches group and user and installs the seccomp filter.
+/// Applies to every thread of the process, and fails when a listener fails first.
+pub async fn drop_after_listening(config: Config) -> Result<()> {
+    if config == Config::default() {
+        if Uid::effective().is_root() {
+            warn!("Running as root, set EZSYSLOG_USER to switch user once listening");
+        }
+        return Ok(());
+    }
+    health::all_listening().await.context("Not dropping privileges")?;
+    config.apply()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{existing_parent, filter, group, permits, user, DENIED, READ, SEARCH, WRITE};
+    use nix::{
+        libc,
+        unistd::{Gid, Uid},
+    };
+    use std::path::Path;
+
+    #[test]
+    fn lookups() {
+        let (uid, gid) = (Uid::from_raw(1000), Gid::from_raw(100));
+        assert_eq!(user("app", Some((uid, gid))).unwrap(), (uid, Some(gid)));
+        assert_eq!(user("1000", Some((uid, gid))).unwrap(), (uid, Some(gid)));
+        assert_eq!(user("4000000", None).unwrap(), (Uid::from_raw(4000000), None));
+        assert!(user("no-such-user", None).is_err());
+        assert_eq!(group("app", || Ok(Some(gid))).unwrap(), gid);
+        assert_eq!(group("65534", || panic!("looked up a gid")).unwrap(), Gid::from_raw(65534));
+        assert!(group("no-such-group", || Ok(None)).is_err());
+    }
+
+    #[test]
+    fn access() {
+        let (uid, gid) = (Uid::from_raw(1000), Gid::from_raw(100));
+        assert!(permits(0o600, 1000, 0, uid, gid, READ));
+        assert!(!permits(0o600, 0, 100, uid, gid, READ));
+        assert!(permits(0o640, 0, 100, uid, gid, READ));
+        assert!(!permits(0o640, 0, 100, uid, gid, WRITE));
+        assert!(!permits(0o754, 0, 0, uid, gid, WRITE | SEARCH));
+        assert!(permits(0o757, 0, 0, uid, gid, WRITE | SEARCH));
+        // The owner's bits apply even when others have more
+        assert!(!permits(0o077, 1000, 100, uid, gid, READ));
+        assert!(permits(0o000, 1, 1, Uid::from_raw(0), gid, WRITE));
+        assert_eq!(existing_parent(Path::new("state.json")), Path::new("."));
+        assert_eq!(existing_parent(Path::new("/no/such/dir/state.json")), Path::new("/"));
+    }
+
+    #[test]
+    fn filter_allows_late_opens() {
+        assert!(!filter().unwrap().is_empty());
+        // Reloading certificates and rules, following logs, archiving and reconnecting to the database
+        for call in [
+            libc::SYS_openat,
+            libc::SYS_newfstatat,
+            libc::SYS_statx,
+            libc::SYS_getdents64,
+            libc::SYS_renameat,
+            libc::SYS_mkdirat,
+            libc::SYS_socket,
+            libc::SYS_connect,
+        ] {
+            assert!(!DENIED.contains(&call));
+        }
+    }
+}
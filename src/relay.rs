@@ -0,0 +1,246 @@
+use std::{env, net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    matcher::Matcher,
+    metrics::METRICS,
+    syslog::{self, Format},
+    tls,
+};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use syslog_loose::Message;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    sync::mpsc,
+    time::sleep,
+};
+use tracing::{info, warn};
+use url::Url;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Options {
+    format: Option<String>,
+    /// PEM file with the CA to trust for `tls://` upstreams
+    ca: Option<PathBuf>,
+    #[serde(flatten)]
+    matcher: Matcher,
+}
+
+#[derive(Debug)]
+struct Destination {
+    transport: Transport,
+    address: String,
+    host: String,
+    format: Format,
+    ca: Option<PathBuf>,
+    matcher: Matcher,
+}
+
+/// Parses an upstream such as `udp://siem:514`, `tcp://collector:601?format=rfc3164` or
+/// `tls://collector:6514?severity=warning&ca=/etc/ssl/ca.pem`. Any other query parameters are a [`Matcher`].
+fn parse_destination(input: &str) -> Result<Destination> {
+    let url = Url::parse(input)?;
+    let transport = match url.scheme() {
+        "udp" => Transport::Udp,
+        "tcp" => Transport::Tcp,
+        "tls" => Transport::Tls,
+        scheme => bail!("Unknown relay scheme {scheme}, expected udp, tcp or tls"),
+    };
+    let host = match url.host_str() {
+        Some(host) => host.trim_matches(|c| c == '[' || c == ']').to_string(),
+        None => bail!("Relay {input} has no host"),
+    };
+    let port = url.port().unwrap_or(match transport {
+        Transport::Tls => 6514,
+        _ => 514,
+    });
+    let options: Options = serde_urlencoded::from_str(url.query().unwrap_or(""))?;
+    let format = match options.format.as_deref() {
+        None | Some("rfc5424") => Format::Rfc5424,
+        Some("rfc3164") => Format::Rfc3164,
+        Some(format) => bail!("Unknown relay format {format}, expected rfc3164 or rfc5424"),
+    };
+    Ok(Destination {
+        transport,
+        address: format!("{}:{port}", url.host_str().unwrap()),
+        host,
+        format,
+        ca: options.ca,
+        matcher: options.matcher,
+    })
+}
+
+trait Stream: AsyncWrite + Unpin + Send {}
+impl<T: AsyncWrite + Unpin + Send> Stream for T {}
+
+enum Connection {
+    Udp(UdpSocket),
+    Stream(Box<dyn Stream>),
+}
+
+impl Destination {
+    async fn connect(&self) -> Result<Connection> {
+        Ok(match self.transport {
+            Transport::Udp => {
+                let socket = UdpSocket::bind(if self.host.contains(':') { "[::]:0" } else { "0.0.0.0:0" }).await?;
+                socket.connect(&self.address).await?;
+                Connection::Udp(socket)
+            }
+            Transport::Tcp => Connection::Stream(Box::new(TcpStream::connect(&self.address).await?)),
+            Transport::Tls => {
+                let connector = tls::connector(self.ca.as_deref())?;
+                let tcp = TcpStream::connect(&self.address).await?;
+                let name = self.host.as_str().try_into()?;
+                Connection::Stream(Box::new(connector.connect(name, tcp).await?))
+            }
+        })
+    }
+}
+
+async fn send(connection: &mut Connection, line: &str) -> Result<()> {
+    match connection {
+        Connection::Udp(socket) => {
+            socket.send(line.as_bytes()).await?;
+        }
+        Connection::Stream(stream) => {
+            stream.write_all(&syslog::frame(line)).await?;
+            stream.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends queued lines to one upstream, holding on to a line and reconnecting until it has been sent.
+async fn forward(destination: Arc<Destination>, mut queue: mpsc::Receiver<String>) {
+    let mut connection = None;
+    let mut backoff = Duration::from_secs(1);
+    let depth = METRICS.relay_queue.with_label_values(&[&destination.address]);
+    while let Some(line) = queue.recv().await {
+        depth.dec();
+        loop {
+            let result = async {
+                let connection = match &mut connection {
+                    Some(c) => c,
+                    None => connection.insert(destination.connect().await?),
+                };
+                send(connection, &line).await
+            }
+            .await;
+            match result {
+                Ok(()) => {
+                    backoff = Duration::from_secs(1);
+                    break;
+                }
+                Err(e) => {
+                    warn!(upstream = %destination.address, ?backoff, "Unable to relay, retrying: {e}");
+                    connection = None;
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+/// How full an upstream's queue is
+#[derive(Debug, Clone, Serialize)]
+pub struct Queue {
+    pub upstream: String,
+    pub queued: usize,
+    pub capacity: usize,
+}
+
+struct Upstream {
+    destination: Arc<Destination>,
+    queue: mpsc::Sender<String>,
+}
+
+/// Copies stored messages to other syslog collectors, configured by `EZSYSLOG_RELAY`
+#[derive(Clone, Default)]
+pub struct Relay {
+    upstreams: Arc<Vec<Upstream>>,
+}
+
+impl Relay {
+    /// Starts a queue and sender for every comma separated upstream in `EZSYSLOG_RELAY`
+    pub fn from_env() -> Result<Relay> {
+        let destinations = match env::var("EZSYSLOG_RELAY") {
+            Ok(relay) => relay,
+            Err(_) => return Ok(Relay::default()),
+        };
+        let queue_size: usize = env::var("EZSYSLOG_RELAY_QUEUE")
+            .unwrap_or("10000".to_string())
+            .parse()?;
+        let mut upstreams = vec![];
+        for destination in destinations.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let destination = Arc::new(parse_destination(destination)?);
+            let (queue, receiver) = mpsc::channel(queue_size);
+            tokio::spawn(forward(destination.clone(), receiver));
+            info!(upstream = %destination.address, "Relaying");
+            upstreams.push(Upstream { destination, queue });
+        }
+        Ok(Relay {
+            upstreams: Arc::new(upstreams),
+        })
+    }
+
+    pub fn queues(&self) -> Vec<Queue> {
+        self.upstreams
+            .iter()
+            .map(|u| Queue {
+                upstream: u.destination.address.clone(),
+                queued: u.queue.max_capacity() - u.queue.capacity(),
+                capacity: u.queue.max_capacity(),
+            })
+            .collect()
+    }
+
+    /// Queues a message for every upstream it matches. Messages without a hostname are sent with the address
+    /// they came from so the upstream can still tell them apart.
+    pub fn forward(&self, msg: &Message<&str>, ip: &IpAddr) {
+        let fallback_hostname = ip.to_string();
+        for upstream in self.upstreams.iter() {
+            let destination = &upstream.destination;
+            if !destination.matcher.matches(msg, ip) {
+                continue;
+            }
+            let line = syslog::format_message(msg, destination.format, Some(&fallback_hostname));
+            match upstream.queue.try_send(line) {
+                Ok(()) => METRICS.relay_queue.with_label_values(&[&destination.address]).inc(),
+                Err(_) => {
+                    METRICS.relay_dropped.with_label_values(&[&destination.address]).inc();
+                    warn!(upstream = %destination.address, "Relay queue is full, dropping message");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_destination, Transport};
+    use crate::syslog::Format;
+
+    #[test]
+    fn destination() {
+        let d = parse_destination("tls://[::1]?format=rfc3164&severity=err&hostname=fw1").unwrap();
+        assert_eq!(d.transport, Transport::Tls);
+        assert_eq!(d.address, "[::1]:6514");
+        assert_eq!(d.host, "::1");
+        assert!(matches!(d.format, Format::Rfc3164));
+        assert_eq!(d.matcher.severity.as_deref(), Some("err"));
+        assert_eq!(d.matcher.hostname.as_deref(), Some("fw1"));
+        assert!(parse_destination("http://siem").is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use std::{collections::HashMap, env};
+
+use crate::{
+    database,
+    search::Filter,
+    stats::{Count, Field},
+};
+use anyhow::Result;
+use redis::aio::MultiplexedConnection;
+use redis_graph::AsyncGraphCommands;
+
+const MINUTE: u64 = 60_000;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
+/// Value counted for messages without a hostname, severity or appname
+const NONE: &str = "-";
+
+/// Separates the hostname, severity and appname in a counter's field
+const SEPARATOR: char = '\x1f';
+
+/// Message counts are kept per minute, hour and day in Redis hashes named `syslog:rollup:{m,h,d}:{bucket}`,
+/// with a field per hostname, severity and appname
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Minute,
+    Hour,
+    Day,
+}
+
+const RESOLUTIONS: [Resolution; 3] = [Resolution::Minute, Resolution::Hour, Resolution::Day];
+
+impl Resolution {
+    fn millis(self) -> u64 {
+        match self {
+            Resolution::Minute => MINUTE,
+            Resolution::Hour => HOUR,
+            Resolution::Day => DAY,
+        }
+    }
+
+    fn key(self, bucket: u64) -> String {
+        let tag = match self {
+            Resolution::Minute => "m",
+            Resolution::Hour => "h",
+            Resolution::Day => "d",
+        };
+        format!("{}:rollup:{tag}:{bucket}", database::GRAPH_NAME)
+    }
+
+    /// Days counters are kept for, from `EZSYSLOG_ROLLUP_{MINUTE,HOUR,DAY}_RETENTION`
+    fn retention(self) -> u64 {
+        let (var, default) = match self {
+            Resolution::Minute => ("EZSYSLOG_ROLLUP_MINUTE_RETENTION", 7),
+            Resolution::Hour => ("EZSYSLOG_ROLLUP_HOUR_RETENTION", 90),
+            Resolution::Day => ("EZSYSLOG_ROLLUP_DAY_RETENTION", 3650),
+        };
+        env::var(var).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+    }
+
+    /// Unix time in seconds at which the counter for `bucket` expires
+    fn expires_at(self, bucket: u64) -> usize {
+        ((bucket + self.millis() + self.retention() * DAY) / 1000) as usize
+    }
+}
+
+fn field(hostname: Option<&str>, severity: Option<&str>, appname: Option<&str>) -> String {
+    [hostname, severity, appname]
+        .map(|v| v.unwrap_or(NONE))
+        .join(&SEPARATOR.to_string())
+}
+
+/// Counts a stored message
+pub async fn record(
+    con: &mut MultiplexedConnection,
+    hostname: Option<&str>,
+    severity: Option<&str>,
+    appname: Option<&str>,
+    server_timestamp: u64,
+) -> Result<()> {
+    let field = field(hostname, severity, appname);
+    let mut pipe = redis::pipe();
+    for resolution in RESOLUTIONS {
+        let bucket = server_timestamp - server_timestamp % resolution.millis();
+        let key = resolution.key(bucket);
+        pipe.hincr(&key, &field, 1)
+            .ignore()
+            .expire_at(&key, resolution.expires_at(bucket))
+            .ignore();
+    }
+    pipe.query_async::<_, ()>(con).await?;
+    Ok(())
+}
+
+/// Whether counters can answer for a filter, they only know the hostname, severity and appname and can't be
+/// limited to a user's hosts
+pub fn supports(filter: &Filter, field: Option<Field>) -> bool {
+    filter.msg.is_none()
+        && filter.scope.is_none()
+        && filter.facility.is_none()
+        && filter.ip.is_none()
+        && field.is_none_or(|f| matches!(f, Field::Host | Field::Severity | Field::App))
+}
+
+/// The largest counters that together cover `[start, end)`, rounded out to whole minutes
+fn cover(start: u64, end: u64) -> Vec<(Resolution, u64)> {
+    let mut t = start - start % MINUTE;
+    let end = end.div_ceil(MINUTE).saturating_mul(MINUTE);
+    let mut buckets = vec![];
+    while t < end {
+        let resolution = [Resolution::Day, Resolution::Hour]
+            .into_iter()
+            .find(|r| t.is_multiple_of(r.millis()) && t.saturating_add(r.millis()) <= end)
+            .unwrap_or(Resolution::Minute);
+        buckets.push((resolution, t));
+        let Some(next) = t.checked_add(resolution.millis()) else {
+            break;
+        };
+        t = next;
+    }
+    buckets
+}
+
+/// Sums the counters covering each range, keeping the ones that pass the filter, by the value of `group`
+async fn sum(
+    con: &mut MultiplexedConnection,
+    ranges: &[(u64, u64)],
+    filter: &Filter,
+    group: Option<Field>,
+) -> Result<Vec<HashMap<Option<String>, u64>>> {
+    let covers: Vec<_> = ranges.iter().map(|(start, end)| cover(*start, *end)).collect();
+    if covers.iter().all(Vec::is_empty) {
+        return Ok(vec![HashMap::new(); ranges.len()]);
+    }
+    let mut pipe = redis::pipe();
+    for (resolution, bucket) in covers.iter().flatten() {
+        pipe.hgetall(resolution.key(*bucket));
+    }
+    let counters: Vec<HashMap<String, u64>> = pipe.query_async(con).await?;
+    let mut counters = counters.into_iter();
+
+    fn contains(value: &str, pattern: &Option<String>) -> bool {
+        pattern.as_ref().is_none_or(|p| value != NONE && value.contains(p.as_str()))
+    }
+    Ok(covers
+        .iter()
+        .map(|cover| {
+            let mut sums = HashMap::new();
+            for counter in counters.by_ref().take(cover.len()) {
+                for (field, count) in counter {
+                    let mut parts = field.splitn(3, SEPARATOR);
+                    let (host, severity, app) = (
+                        parts.next().unwrap_or(NONE),
+                        parts.next().unwrap_or(NONE),
+                        parts.next().unwrap_or(NONE),
+                    );
+                    if !(contains(host, &filter.hostname)
+                        && contains(severity, &filter.severity)
+                        && contains(app, &filter.appname))
+                    {
+                        continue;
+                    }
+                    let key = match group {
+                        Some(Field::Host) => Some(host),
+                        Some(Field::Severity) => Some(severity),
+                        Some(Field::App) => Some(app),
+                        _ => None,
+                    };
+                    let key = key.filter(|k| *k != NONE).map(str::to_string);
+                    *sums.entry(key).or_default() += count;
+                }
+            }
+            sums
+        })
+        .collect())
+}
+
+/// `[start, end)` of each histogram bucket from `start` to `end` inclusive, cut to that range
+fn ranges(start: u64, end: u64, interval: u64) -> Vec<(u64, u64)> {
+    (start - start % interval..=end)
+        .step_by(interval as usize)
+        .map(|bucket| (bucket.max(start), bucket.saturating_add(interval).min(end.saturating_add(1))))
+        .collect()
+}
+
+/// `(bucket, group, count)` rows like the histogram query over the graph returns
+pub async fn histogram(
+    con: &mut MultiplexedConnection,
+    filter: &Filter,
+    start: u64,
+    end: u64,
+    interval: u64,
+    group: Option<Field>,
+) -> Result<Vec<(u64, Option<String>, u64)>> {
+    let ranges = ranges(start, end, interval);
+    let sums = sum(con, &ranges, filter, group).await?;
+    Ok(ranges
+        .iter()
+        .zip(sums)
+        .flat_map(|((bucket, _), sums)| {
+            let bucket = bucket - bucket % interval;
+            sums.into_iter().map(move |(group, count)| (bucket, group, count))
+        })
+        .collect())
+}
+
+pub async fn top(
+    con: &mut MultiplexedConnection,
+    filter: &Filter,
+    by: Field,
+    start: u64,
+    end: u64,
+    limit: usize,
+) -> Result<Vec<Count>> {
+    let sums = sum(con, &[(start, end.saturating_add(1))], filter, Some(by)).await?;
+    let mut counts: Vec<Count> = sums
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, count)| Some(Count { key: key?, count }))
+        .collect();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+    counts.truncate(limit);
+    Ok(counts)
+}
+
+/// Recounts every message received between `since` and `until` from the graph, a day at a time, for messages
+/// stored before counters were kept. `counted` is called with the start of each day once it is done.
+pub async fn rebuild(
+    con: &mut MultiplexedConnection,
+    since: u64,
+    until: u64,
+    mut counted: impl FnMut(u64),
+) -> Result<()> {
+    let mut day = since - since % DAY;
+    while day < until {
+        let query = format!(
+            "
+            MATCH (node:Message) WHERE node.server_timestamp >= {day} AND node.server_timestamp < {end}
+            OPTIONAL MATCH (node)-[:host]->(hostname:Hostname)
+            OPTIONAL MATCH (node)-[:severity]->(severity:Severity)
+            OPTIONAL MATCH (node)-[:appname]->(appname:AppName)
+            RETURN node.server_timestamp - node.server_timestamp % {MINUTE} as bucket, hostname.name as hostname, severity.name as severity, appname.name as appname, count(node) as count
+            ",
+            end = day.saturating_add(DAY)
+        );
+        let results = con.graph_ro_query(database::GRAPH_NAME, query).await?;
+        let mut counters: HashMap<String, (Resolution, u64, HashMap<String, u64>)> = HashMap::new();
+        for result in &results.data {
+            let (Some(minute), Some(count)) = (result.get_scalar::<u64>("bucket"), result.get_scalar::<u64>("count")) else {
+                continue;
+            };
+            let field = field(
+                result.get_scalar::<String>("hostname").as_deref(),
+                result.get_scalar::<String>("severity").as_deref(),
+                result.get_scalar::<String>("appname").as_deref(),
+            );
+            for resolution in RESOLUTIONS {
+                let bucket = minute - minute % resolution.millis();
+                let (_, _, counter) = counters
+                    .entry(resolution.key(bucket))
+                    .or_insert_with(|| (resolution, bucket, HashMap::new()));
+                *counter.entry(field.clone()).or_default() += count;
+            }
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for resolution in RESOLUTIONS {
+            for bucket in (day..day.saturating_add(DAY)).step_by(resolution.millis() as usize) {
+                pipe.del(resolution.key(bucket)).ignore();
+            }
+        }
+        for (key, (resolution, bucket, counter)) in counters {
+            let fields: Vec<_> = counter.into_iter().collect();
+            pipe.hset_multiple(&key, &fields)
+                .ignore()
+                .expire_at(&key, resolution.expires_at(bucket))
+                .ignore();
+        }
+        pipe.query_async::<_, ()>(con).await?;
+        counted(day);
+        let Some(next) = day.checked_add(DAY) else {
+            break;
+        };
+        day = next;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cover, ranges, Resolution, DAY, HOUR, MINUTE};
+
+    #[test]
+    fn largest_covering_counters() {
+        let start = DAY - HOUR - 2 * MINUTE - 30_000;
+        let buckets = cover(start, 2 * DAY + HOUR + MINUTE);
+        assert_eq!(
+            buckets,
+            [
+                (Resolution::Minute, DAY - HOUR - 3 * MINUTE),
+                (Resolution::Minute, DAY - HOUR - 2 * MINUTE),
+                (Resolution::Minute, DAY - HOUR - MINUTE),
+                (Resolution::Hour, DAY - HOUR),
+                (Resolution::Day, DAY),
+                (Resolution::Hour, 2 * DAY),
+                (Resolution::Minute, 2 * DAY + HOUR),
+            ]
+        );
+    }
+
+    #[test]
+    fn ranges_up_to_the_last_millisecond() {
+        assert_eq!(ranges(90_000, 200_000, MINUTE), [(90_000, 120_000), (120_000, 180_000), (180_000, 200_001)]);
+        let start = u64::MAX - 90_000;
+        let last = ranges(start, u64::MAX, MINUTE).pop().unwrap();
+        assert_eq!(last.1, u64::MAX);
+        assert!(!cover(start, u64::MAX).is_empty());
+    }
+}
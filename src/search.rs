@@ -0,0 +1,576 @@
+use std::{
+    fmt::{self, Write},
+    str::FromStr,
+};
+
+use crate::{access::Scope, database, matcher::severity_level, syslog::Stored, utils::escape};
+use anyhow::{anyhow, Result};
+use chrono::{TimeZone, Utc};
+use futures_util::{stream, Stream, TryStreamExt};
+use redis::aio::MultiplexedConnection;
+use redis_graph::{AsyncGraphCommands, GraphResult};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use syslog_loose::{decompose_pri, Message, Protocol};
+
+/// Records per query when streaming results
+const STREAM_PAGE_SIZE: usize = 1000;
+
+/// Structured message search, the server side equivalent of `getMessagesQuery` in the web UI.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Filter {
+    /// Earliest `server_timestamp` in milliseconds (inclusive)
+    pub start: Option<u64>,
+    /// Latest `server_timestamp` in milliseconds (inclusive)
+    pub end: Option<u64>,
+    /// Full text search on the message body
+    pub msg: Option<String>,
+    pub severity: Option<String>,
+    pub facility: Option<String>,
+    pub hostname: Option<String>,
+    pub appname: Option<String>,
+    pub ip: Option<String>,
+    pub limit: Option<usize>,
+    /// Only records after this position, a page's `next` cursor
+    pub after: Option<Cursor>,
+    /// Only records before this position, a page's `prev` cursor
+    pub before: Option<Cursor>,
+    /// Hosts the user may see, set by the server from their role rather than taken from the request
+    #[serde(skip)]
+    pub scope: Option<Scope>,
+}
+
+/// Position of a record in `(server_timestamp, id)` order, written as `{server_timestamp}-{id}`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+    pub server_timestamp: u64,
+    pub id: u64,
+}
+
+impl From<&Record> for Cursor {
+    fn from(record: &Record) -> Self {
+        Cursor {
+            server_timestamp: record.server_timestamp,
+            id: record.id,
+        }
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.server_timestamp, self.id)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (server_timestamp, id) = s.split_once('-').ok_or_else(|| anyhow!("Invalid cursor {s}"))?;
+        Ok(Cursor {
+            server_timestamp: server_timestamp.parse()?,
+            id: id.parse()?,
+        })
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cursor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// One page of records in `(server_timestamp, id)` order, with cursors for the neighbouring pages when there are any
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Page {
+    pub records: Vec<Record>,
+    pub next: Option<Cursor>,
+    pub prev: Option<Cursor>,
+}
+
+/// A stored `Message` node flattened together with the nodes it is related to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub id: u64,
+    pub server_timestamp: u64,
+    /// Timestamp reported by the sender in seconds
+    pub timestamp: Option<i64>,
+    pub msgid: Option<String>,
+    pub msg: String,
+    pub severity: Option<String>,
+    pub facility: Option<String>,
+    pub hostname: Option<String>,
+    pub appname: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Columns returned by every query that is read back into a [`Record`].
+pub const RETURN_RECORD: &str = "RETURN DISTINCT ID(node) as id, node.server_timestamp as server_timestamp, node.timestamp as timestamp, node.id as msgid, node.msg as msg, severity.name as severity, facility.name as facility, hostname.name as hostname, appname.name as appname, address.ip as ip";
+
+impl Record {
+    /// Rebuilds the parts of the syslog message a record keeps, structured data and the process id are not stored
+    pub fn to_message(&self) -> Message<&str> {
+        let severity = self
+            .severity
+            .as_deref()
+            .and_then(severity_level)
+            .and_then(|level| decompose_pri(level as u8).1);
+        let facility = self
+            .facility
+            .as_deref()
+            .and_then(|name| (0..24).filter_map(|f| decompose_pri(f << 3).0).find(|f| f.as_str() == name));
+        let timestamp = match self.timestamp {
+            Some(seconds) => Utc.timestamp_opt(seconds, 0).single(),
+            None => Utc.timestamp_millis_opt(self.server_timestamp as i64).single(),
+        };
+        Message {
+            protocol: Protocol::RFC5424(1),
+            facility,
+            severity,
+            timestamp: timestamp.map(Into::into),
+            hostname: self.hostname.as_deref(),
+            appname: self.appname.as_deref(),
+            procid: None,
+            msgid: self.msgid.as_deref(),
+            structured_data: vec![],
+            msg: &self.msg,
+        }
+    }
+
+    /// The record a message that was just stored will be read back as
+    pub fn from_stored(stored: &Stored) -> Record {
+        let msg = &stored.msg;
+        Record {
+            id: stored.id as u64,
+            server_timestamp: stored.server_timestamp,
+            timestamp: msg.timestamp.map(|t| t.timestamp()),
+            msgid: msg.msgid.clone(),
+            msg: msg.msg.clone(),
+            severity: msg.severity.map(|s| s.as_str().to_string()),
+            facility: msg.facility.map(|f| f.as_str().to_string()),
+            hostname: msg.hostname.clone(),
+            appname: msg.appname.clone(),
+            ip: Some(stored.ip.to_string()),
+        }
+    }
+
+    pub fn from_result(result: &GraphResult) -> Option<Record> {
+        Some(Record {
+            id: result.get_scalar("id")?,
+            server_timestamp: result.get_scalar("server_timestamp")?,
+            timestamp: result.get_scalar("timestamp"),
+            msgid: result.get_scalar("msgid"),
+            msg: result.get_scalar("msg").unwrap_or_default(),
+            severity: result.get_scalar("severity"),
+            facility: result.get_scalar("facility"),
+            hostname: result.get_scalar("hostname"),
+            appname: result.get_scalar("appname"),
+            ip: result.get_scalar("ip"),
+        })
+    }
+}
+
+impl Filter {
+    /// The MATCH clauses of the query, binding `node` and the related `severity`, `facility`, `hostname`,
+    /// `appname` and `address` nodes so they can be returned or aggregated.
+    pub fn match_clause(&self) -> String {
+        // These are split because you cannot have an OPTIONAL MATCH before a MATCH
+        let mut mandatory = match &self.msg {
+            Some(msg) => format!(
+                "CALL db.idx.fulltext.queryNodes('Message', '{}') YIELD node",
+                escape(msg)
+            ),
+            None => "MATCH (node:Message)".to_string(),
+        };
+        let mut conditions = vec![];
+        if let Some(start) = self.start {
+            conditions.push(format!("node.server_timestamp >= {start}"));
+        }
+        if let Some(end) = self.end {
+            conditions.push(format!("node.server_timestamp <= {end}"));
+        }
+        for (cursor, op) in [(self.after, ">"), (self.before, "<")] {
+            if let Some(Cursor { server_timestamp, id }) = cursor {
+                conditions.push(format!(
+                    "(node.server_timestamp {op} {server_timestamp} OR (node.server_timestamp = {server_timestamp} AND ID(node) {op} {id}))"
+                ));
+            }
+        }
+        if !conditions.is_empty() {
+            write!(mandatory, " WHERE {}", conditions.join(" AND ")).unwrap();
+        }
+
+        let mut optional = String::new();
+        for (alias, label, rel, property, value) in [
+            ("severity", "Severity", "severity", "name", &self.severity),
+            ("facility", "Facility", "facility", "name", &self.facility),
+            ("hostname", "Hostname", "host", "name", &self.hostname),
+            ("appname", "AppName", "appname", "name", &self.appname),
+            ("address", "Address", "from", "ip", &self.ip),
+        ] {
+            match value {
+                Some(value) => write!(
+                    mandatory,
+                    " MATCH (node)-[:{rel}]->({alias}:{label}) WHERE {alias}.{property} CONTAINS '{value}'",
+                    value = escape(value)
+                ),
+                None => write!(
+                    optional,
+                    " OPTIONAL MATCH (node)-[:{rel}]->({alias}:{label})"
+                ),
+            }
+            .unwrap();
+        }
+
+        if let Some(scope) = &self.scope {
+            write!(
+                optional,
+                " WITH node, severity, facility, hostname, appname, address WHERE {}",
+                scope.condition()
+            )
+            .unwrap();
+        }
+
+        format!("{mandatory}{optional}")
+    }
+
+    /// Builds the read only Cypher query for this filter, returning rows readable by [`Record::from_result`].
+    pub fn to_query(&self) -> String {
+        let order = if self.is_backward() { "DESC" } else { "ASC" };
+        let mut query = format!(
+            "{} {RETURN_RECORD} ORDER BY server_timestamp {order}, id {order}",
+            self.match_clause()
+        );
+        if let Some(limit) = self.limit {
+            write!(query, " LIMIT {limit}").unwrap();
+        }
+        query
+    }
+
+    /// Paging back from a `before` cursor reads the newest records first
+    fn is_backward(&self) -> bool {
+        self.before.is_some() && self.after.is_none()
+    }
+
+    /// Whether an already stored record would be returned by this filter, used to filter live events.
+    pub fn matches(&self, record: &Record) -> bool {
+        fn contains(value: &Option<String>, pattern: &Option<String>) -> bool {
+            match pattern {
+                None => true,
+                Some(p) => value.as_deref().is_some_and(|v| v.contains(p.as_str())),
+            }
+        }
+        self.start.is_none_or(|s| record.server_timestamp >= s)
+            && self.end.is_none_or(|e| record.server_timestamp <= e)
+            && self.msg.as_ref().is_none_or(|m| {
+                record.msg.to_lowercase().contains(&m.to_lowercase())
+            })
+            && contains(&record.severity, &self.severity)
+            && contains(&record.facility, &self.facility)
+            && contains(&record.hostname, &self.hostname)
+            && contains(&record.appname, &self.appname)
+            && contains(&record.ip, &self.ip)
+            && self.scope.as_ref().is_none_or(|scope| scope.matches(record))
+    }
+}
+
+/// Records matching the filter in `(server_timestamp, id)` order
+pub async fn run(con: &mut MultiplexedConnection, filter: &Filter) -> Result<Vec<Record>> {
+    let results = con
+        .graph_ro_query(database::GRAPH_NAME, filter.to_query())
+        .await?;
+    let mut records: Vec<Record> = results.data.iter().filter_map(Record::from_result).collect();
+    if filter.is_backward() {
+        records.reverse();
+    }
+    Ok(records)
+}
+
+/// Most records a page may hold, from `EZSYSLOG_MAX_PAGE_SIZE` (default 10000)
+fn max_page_size_from_env() -> usize {
+    std::env::var("EZSYSLOG_MAX_PAGE_SIZE").ok().and_then(|m| m.parse().ok()).unwrap_or(10_000)
+}
+
+/// Records for a page asking for `limit` (default 1000), at most `max`
+fn page_limit(limit: Option<usize>, max: usize) -> usize {
+    limit.unwrap_or(1000).min(max)
+}
+
+/// Up to `limit` records (default 1000, at most `EZSYSLOG_MAX_PAGE_SIZE`) from the filter's cursor
+pub async fn page(con: &mut MultiplexedConnection, filter: &Filter) -> Result<Page> {
+    let limit = page_limit(filter.limit, max_page_size_from_env());
+    // One extra record tells whether there is another page
+    let query = Filter { limit: Some(limit + 1), ..filter.clone() };
+    let mut records = run(con, &query).await?;
+    let more = records.len() > limit;
+    let backward = filter.is_backward();
+    if more {
+        if backward {
+            records.remove(0);
+        } else {
+            records.pop();
+        }
+    }
+    let first = records.first().map(Cursor::from);
+    let last = records.last().map(Cursor::from);
+    let (prev, next) = match backward {
+        true => (first.filter(|_| more), last),
+        false => (first.filter(|_| filter.after.is_some()), last.filter(|_| more)),
+    };
+    Ok(Page { records, next, prev })
+}
+
+/// Every record matching the filter, or its `limit`, read a page at a time
+pub fn stream(con: MultiplexedConnection, filter: Filter) -> impl Stream<Item = Result<Record>> {
+    let remaining = filter.limit;
+    let filter = Filter { before: None, ..filter };
+    stream::try_unfold((con, filter, remaining), |(mut con, mut filter, remaining)| async move {
+        if remaining == Some(0) {
+            return Ok::<_, anyhow::Error>(None);
+        }
+        filter.limit = Some(remaining.map_or(STREAM_PAGE_SIZE, |r| r.min(STREAM_PAGE_SIZE)));
+        let page = page(&mut con, &filter).await?;
+        if page.records.is_empty() {
+            return Ok(None);
+        }
+        let remaining = match page.next {
+            None => Some(0),
+            Some(_) => remaining.map(|r| r - page.records.len()),
+        };
+        filter.after = page.next;
+        Ok(Some((stream::iter(page.records.into_iter().map(Ok)), (con, filter, remaining))))
+    })
+    .try_flatten()
+}
+
+/// Fetches a single message by its node id.
+pub async fn get(con: &mut MultiplexedConnection, id: u64) -> Result<Option<Record>> {
+    let query = format!(
+        "
+        MATCH (node:Message) WHERE ID(node) = {id}
+        OPTIONAL MATCH (node)-[:severity]->(severity:Severity)
+        OPTIONAL MATCH (node)-[:facility]->(facility:Facility)
+        OPTIONAL MATCH (node)-[:host]->(hostname:Hostname)
+        OPTIONAL MATCH (node)-[:appname]->(appname:AppName)
+        OPTIONAL MATCH (node)-[:from]->(address:Address)
+        {RETURN_RECORD}
+        "
+    );
+    let results = con.graph_ro_query(database::GRAPH_NAME, query).await?;
+    Ok(results.data.first().and_then(Record::from_result))
+}
+
+fn default_context() -> usize {
+    50
+}
+
+/// How many neighbouring messages `/messages/{id}/context` returns and which ones count as neighbours
+#[derive(Debug, Clone, Deserialize)]
+pub struct ContextParams {
+    #[serde(default = "default_context")]
+    pub before: usize,
+    #[serde(default = "default_context")]
+    pub after: usize,
+    /// Only messages from the same app
+    #[serde(default)]
+    pub same_app: bool,
+    /// Only messages from the same address
+    #[serde(default)]
+    pub same_address: bool,
+}
+
+impl Default for ContextParams {
+    fn default() -> Self {
+        ContextParams {
+            before: default_context(),
+            after: default_context(),
+            same_app: false,
+            same_address: false,
+        }
+    }
+}
+
+impl ContextParams {
+    /// Messages sharing the target's `Hostname` (its `Address` when it has none) received just before or after it
+    fn to_query(&self, target: &Record, older: bool) -> String {
+        let (op, order, limit) = match older {
+            true => ("<", "DESC", self.before),
+            false => (">", "ASC", self.after),
+        };
+        let Cursor { server_timestamp, id } = Cursor::from(target);
+        let mut shared = vec![];
+        if target.hostname.is_some() {
+            shared.push(("hostname", "Hostname", "host"));
+        }
+        if self.same_app && target.appname.is_some() {
+            shared.push(("appname", "AppName", "appname"));
+        }
+        if self.same_address || target.hostname.is_none() {
+            shared.push(("address", "Address", "from"));
+        }
+        let mut query = format!(
+            "MATCH (node:Message) WHERE (node.server_timestamp {op} {server_timestamp} OR (node.server_timestamp = {server_timestamp} AND ID(node) {op} {id}))"
+        );
+        for (alias, label, rel) in &shared {
+            write!(
+                query,
+                " MATCH (target:Message)-[:{rel}]->({alias}:{label})<-[:{rel}]-(node) WHERE ID(target) = {id}"
+            )
+            .unwrap();
+        }
+        for (alias, label, rel) in [
+            ("severity", "Severity", "severity"),
+            ("facility", "Facility", "facility"),
+            ("hostname", "Hostname", "host"),
+            ("appname", "AppName", "appname"),
+            ("address", "Address", "from"),
+        ] {
+            if !shared.iter().any(|(a, _, _)| *a == alias) {
+                write!(query, " OPTIONAL MATCH (node)-[:{rel}]->({alias}:{label})").unwrap();
+            }
+        }
+        write!(
+            query,
+            " {RETURN_RECORD} ORDER BY server_timestamp {order}, id {order} LIMIT {}",
+            limit.min(STREAM_PAGE_SIZE)
+        )
+        .unwrap();
+        query
+    }
+}
+
+/// The message with its neighbours from the same host in `(server_timestamp, id)` order, `None` when it doesn't exist
+pub async fn context(
+    con: &mut MultiplexedConnection,
+    id: u64,
+    params: &ContextParams,
+) -> Result<Option<Vec<Record>>> {
+    let Some(target) = get(con, id).await? else {
+        return Ok(None);
+    };
+    let mut records = vec![];
+    for older in [true, false] {
+        let results = con
+            .graph_ro_query(database::GRAPH_NAME, params.to_query(&target, older))
+            .await?;
+        let mut neighbours: Vec<Record> = results.data.iter().filter_map(Record::from_result).collect();
+        if older {
+            neighbours.reverse();
+            records.extend(neighbours);
+            records.push(target.clone());
+        } else {
+            records.extend(neighbours);
+        }
+    }
+    Ok(Some(records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{page_limit, ContextParams, Cursor, Filter, Record};
+    use crate::access::{HostGroup, Scope};
+
+    #[test]
+    fn filter_query() {
+        let filter = Filter {
+            start: Some(1),
+            hostname: Some("it's".to_string()),
+            limit: Some(10),
+            ..Default::default()
+        };
+        let query = filter.to_query();
+        assert!(query.starts_with("MATCH (node:Message) WHERE node.server_timestamp >= 1 MATCH (node)-[:host]->(hostname:Hostname) WHERE hostname.name CONTAINS 'it\\'s' OPTIONAL MATCH"));
+        assert!(query.ends_with("LIMIT 10"));
+    }
+
+    #[test]
+    fn cursor_query() {
+        let filter = Filter {
+            before: Some("1700000000000-42".parse().unwrap()),
+            ..Default::default()
+        };
+        let query = filter.to_query();
+        assert!(query.starts_with("MATCH (node:Message) WHERE (node.server_timestamp < 1700000000000 OR (node.server_timestamp = 1700000000000 AND ID(node) < 42))"));
+        assert!(query.ends_with("ORDER BY server_timestamp DESC, id DESC"));
+        assert!("42".parse::<Cursor>().is_err());
+    }
+
+    #[test]
+    fn context_query() {
+        let target = Record {
+            id: 42,
+            server_timestamp: 1_700_000_000_000,
+            timestamp: None,
+            msgid: None,
+            msg: "Out of memory".to_string(),
+            severity: Some("err".to_string()),
+            facility: None,
+            hostname: Some("web1".to_string()),
+            appname: Some("kernel".to_string()),
+            ip: Some("10.0.0.1".to_string()),
+        };
+        let params = ContextParams { before: 10, same_app: true, ..Default::default() };
+        let query = params.to_query(&target, true);
+        assert!(query.starts_with("MATCH (node:Message) WHERE (node.server_timestamp < 1700000000000 OR (node.server_timestamp = 1700000000000 AND ID(node) < 42)) MATCH (target:Message)-[:host]->(hostname:Hostname)<-[:host]-(node) WHERE ID(target) = 42 MATCH (target:Message)-[:appname]->(appname:AppName)<-[:appname]-(node) WHERE ID(target) = 42 OPTIONAL MATCH (node)-[:severity]->(severity:Severity) OPTIONAL MATCH (node)-[:facility]->(facility:Facility) OPTIONAL MATCH (node)-[:from]->(address:Address) RETURN"));
+        assert!(query.ends_with("ORDER BY server_timestamp DESC, id DESC LIMIT 10"));
+
+        let query = ContextParams::default().to_query(&Record { hostname: None, ..target }, false);
+        assert!(query.contains("MATCH (target:Message)-[:from]->(address:Address)<-[:from]-(node) WHERE ID(target) = 42 OPTIONAL MATCH"));
+        assert!(query.ends_with("ORDER BY server_timestamp ASC, id ASC LIMIT 50"));
+    }
+
+    /// The query with the contents of its string literals removed, as Cypher reads them
+    fn without_strings(query: &str) -> String {
+        let mut code = String::new();
+        let mut chars = query.chars();
+        while let Some(ch) = chars.next() {
+            code.push(ch);
+            if ch == '\'' {
+                while let Some(ch) = chars.next() {
+                    match ch {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '\'' => break,
+                        _ => {}
+                    }
+                }
+                code.push('\'');
+            }
+        }
+        code
+    }
+
+    #[test]
+    fn scoped_injection() {
+        let group: HostGroup = serde_json::from_value(serde_json::json!({ "hostnames": ["web1"] })).unwrap();
+        let filter = Filter {
+            hostname: Some("x\\' OR true //".to_string()),
+            msg: Some("\\".to_string()),
+            scope: Some(Scope::new(&[&group])),
+            ..Default::default()
+        };
+        let code = without_strings(&filter.to_query());
+        assert!(!code.contains("OR true"));
+        assert!(!code.contains("//"));
+        assert!(code.contains("queryNodes('', '') YIELD node"));
+        assert!(code.contains("WHERE hostname.name CONTAINS '' OPTIONAL MATCH"));
+        assert!(code.contains(" WITH node, severity, facility, hostname, appname, address WHERE (hostname.name = '') RETURN"));
+    }
+
+    #[test]
+    fn page_limits() {
+        assert_eq!(page_limit(None, 10_000), 1000);
+        assert_eq!(page_limit(Some(50), 10_000), 50);
+        assert_eq!(page_limit(Some(usize::MAX), 10_000) + 1, 10_001);
+        assert_eq!(page_limit(None, 100), 100);
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use tracing::warn;
+
+/// An append only queue of records on disk that survives restarts.
+///
+/// Records are stored length prefixed in `spool.log`. The position of the first record that has not been
+/// committed yet is kept in `spool.offset`, and once everything is committed both are truncated. Under a steady
+/// backlog the uncommitted records are copied to a new log instead, once the committed ones take up more room.
+pub struct Spool {
+    log: File,
+    log_path: PathBuf,
+    offset_path: PathBuf,
+    offset: u64,
+    len: u64,
+    max_bytes: u64,
+}
+
+impl Spool {
+    pub fn open(dir: &Path, max_bytes: u64) -> Result<Spool> {
+        fs::create_dir_all(dir)?;
+        let log_path = dir.join("spool.log");
+        let mut log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&log_path)?;
+        let offset_path = dir.join("spool.offset");
+        let offset = match fs::read(&offset_path) {
+            Ok(data) if data.len() == 8 => u64::from_le_bytes(data.try_into().unwrap()),
+            _ => 0,
+        };
+        let offset = offset.min(log.metadata()?.len());
+        let len = complete_len(&mut log, offset)?;
+        if len < log.metadata()?.len() {
+            warn!(path = %log_path.display(), "Dropping a partly written record from the spool");
+            log.set_len(len)?;
+        }
+        Ok(Spool {
+            log,
+            log_path,
+            offset_path,
+            offset,
+            len,
+            max_bytes,
+        })
+    }
+
+    /// Bytes waiting to be committed
+    pub fn depth(&self) -> u64 {
+        self.len - self.offset
+    }
+
+    /// Appends a record, returning false when the spool is full and the record was dropped.
+    pub fn push(&mut self, record: &[u8]) -> Result<bool> {
+        if self.depth() + record.len() as u64 + 4 > self.max_bytes {
+            return Ok(false);
+        }
+        let mut buf = Vec::with_capacity(record.len() + 4);
+        buf.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        buf.extend_from_slice(record);
+        self.log.write_all(&buf)?;
+        self.len += buf.len() as u64;
+        Ok(true)
+    }
+
+    /// Reads up to `max` of the oldest records without removing them.
+    pub fn peek(&mut self, max: usize) -> Result<Vec<Vec<u8>>> {
+        let mut records = vec![];
+        let mut position = self.offset;
+        self.log.seek(SeekFrom::Start(position))?;
+        while records.len() < max && position < self.len {
+            let mut len = [0; 4];
+            self.log.read_exact(&mut len)?;
+            let mut record = vec![0; u32::from_le_bytes(len) as usize];
+            self.log.read_exact(&mut record)?;
+            position += 4 + record.len() as u64;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    /// Removes records previously returned by [`Spool::peek`].
+    pub fn commit(&mut self, records: &[Vec<u8>]) -> Result<()> {
+        self.offset += records.iter().map(|r| 4 + r.len() as u64).sum::<u64>();
+        if self.offset >= self.len {
+            self.log.set_len(0)?;
+            self.offset = 0;
+            self.len = 0;
+        } else if self.offset >= self.depth() && self.offset >= self.max_bytes / 4 {
+            self.compact()?;
+        }
+        fs::write(&self.offset_path, self.offset.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Moves the uncommitted records to a new log without the committed ones in front of them
+    fn compact(&mut self) -> Result<()> {
+        let tmp_path = self.log_path.with_extension("log.tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        self.log.seek(SeekFrom::Start(self.offset))?;
+        io::copy(&mut (&self.log).take(self.depth()), &mut tmp)?;
+        tmp.sync_all()?;
+        // Crashing before the rename sends committed records again rather than losing uncommitted ones
+        fs::write(&self.offset_path, 0u64.to_le_bytes())?;
+        fs::rename(&tmp_path, &self.log_path)?;
+        self.log = OpenOptions::new().read(true).append(true).open(&self.log_path)?;
+        self.len = self.depth();
+        self.offset = 0;
+        Ok(())
+    }
+}
+
+/// End of the last whole record from `offset`, a crash while appending can leave a partial one after it
+fn complete_len(log: &mut File, offset: u64) -> Result<u64> {
+    let len = log.metadata()?.len();
+    let mut position = offset;
+    while position + 4 <= len {
+        let mut prefix = [0; 4];
+        log.seek(SeekFrom::Start(position))?;
+        log.read_exact(&mut prefix)?;
+        let end = position + 4 + u32::from_le_bytes(prefix) as u64;
+        if end > len {
+            break;
+        }
+        position = end;
+    }
+    Ok(position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Spool;
+    use std::io::Write;
+
+    #[test]
+    fn push_peek_commit() {
+        let dir = std::env::temp_dir().join(format!("ezsyslog-spool-{}", std::process::id()));
+        let mut spool = Spool::open(&dir, 1024).unwrap();
+        assert!(spool.push(b"one").unwrap());
+        assert!(spool.push(b"two").unwrap());
+        assert!(spool.push(b"three").unwrap());
+
+        let batch = spool.peek(2).unwrap();
+        assert_eq!(batch, [b"one".to_vec(), b"two".to_vec()]);
+        spool.commit(&batch).unwrap();
+
+        // Uncommitted records survive a restart
+        let mut spool = Spool::open(&dir, 1024).unwrap();
+        let batch = spool.peek(10).unwrap();
+        assert_eq!(batch, [b"three".to_vec()]);
+        spool.commit(&batch).unwrap();
+        assert_eq!(spool.depth(), 0);
+        assert!(spool.peek(10).unwrap().is_empty());
+
+        assert!(!spool.push(&[0; 1024]).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_and_torn_records() {
+        let dir = std::env::temp_dir().join(format!("ezsyslog-spool-compact-{}", std::process::id()));
+        let mut spool = Spool::open(&dir, 1024).unwrap();
+        // Never fully drained, so only compaction keeps the log small
+        assert!(spool.push(&[0; 60]).unwrap());
+        for i in 1..100u8 {
+            assert!(spool.push(&[i; 60]).unwrap());
+            let batch = spool.peek(1).unwrap();
+            assert_eq!(batch, [vec![i - 1; 60]]);
+            spool.commit(&batch).unwrap();
+        }
+        assert!(std::fs::metadata(dir.join("spool.log")).unwrap().len() <= 2048);
+        drop(spool);
+
+        // A crash in the middle of appending a record
+        let mut log = std::fs::OpenOptions::new().append(true).open(dir.join("spool.log")).unwrap();
+        log.write_all(&100u32.to_le_bytes()).unwrap();
+        log.write_all(b"torn").unwrap();
+        let mut spool = Spool::open(&dir, 1024).unwrap();
+        assert_eq!(spool.peek(1000).unwrap(), [vec![99; 60]]);
+        assert!(spool.push(b"after").unwrap());
+        assert_eq!(spool.peek(1000).unwrap().last().unwrap(), b"after");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
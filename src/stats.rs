@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+
+use crate::{database, rollup, search::Filter};
+use anyhow::{bail, Context, Result};
+use redis::aio::MultiplexedConnection;
+use redis_graph::AsyncGraphCommands;
+use serde::{Deserialize, Serialize};
+
+/// A property messages can be grouped or ranked by
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Field {
+    Severity,
+    Facility,
+    Host,
+    App,
+    Ip,
+    Msgid,
+}
+
+impl Field {
+    /// Expression for the field over the nodes bound by [`Filter::match_clause`]
+    fn expression(self) -> &'static str {
+        match self {
+            Field::Severity => "severity.name",
+            Field::Facility => "facility.name",
+            Field::Host => "hostname.name",
+            Field::App => "appname.name",
+            Field::Ip => "address.ip",
+            Field::Msgid => "node.id",
+        }
+    }
+}
+
+const DAY: u64 = 86_400_000;
+
+/// Bucket sizes picked when no interval is given, in milliseconds
+const INTERVALS: &[u64] = &[
+    1_000, 5_000, 15_000, 60_000, 300_000, 900_000, 3_600_000, 10_800_000, 21_600_000, DAY, 7 * DAY,
+];
+
+/// Buckets aimed for when picking an interval
+const TARGET_BUCKETS: u64 = 100;
+
+/// Most buckets a histogram may have
+const MAX_BUCKETS: u64 = 10_000;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct HistogramParams {
+    /// Bucket size in seconds, picked from the time range when not set
+    pub interval: Option<u64>,
+    pub group: Option<Field>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Bucket {
+    /// Start of the bucket in milliseconds
+    pub start: u64,
+    pub total: u64,
+    /// Counts per group, messages without a value for the group are counted under `-`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub counts: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Histogram {
+    /// Bucket size in milliseconds
+    pub interval: u64,
+    pub buckets: Vec<Bucket>,
+}
+
+fn pick_interval(start: u64, end: u64) -> u64 {
+    let range = end.saturating_sub(start);
+    INTERVALS
+        .iter()
+        .copied()
+        .find(|i| range / i <= TARGET_BUCKETS)
+        .unwrap_or(7 * DAY)
+}
+
+/// Sets the filter's time range to the last day when it isn't given
+fn time_range(filter: &mut Filter, now: u64) -> (u64, u64) {
+    let end = *filter.end.get_or_insert(now);
+    let start = *filter.start.get_or_insert(end.saturating_sub(DAY));
+    (start, end)
+}
+
+fn histogram_query(filter: &Filter, interval: u64, group: Option<Field>) -> String {
+    let group = group.map_or("null", Field::expression);
+    format!(
+        "{} WITH node.server_timestamp - node.server_timestamp % {interval} as bucket, {group} as group_name, node RETURN bucket, group_name, count(node) as count ORDER BY bucket",
+        filter.match_clause()
+    )
+}
+
+/// Turns `(bucket, group, count)` rows into one bucket per interval from `start` to `end`, including empty ones
+fn fill(rows: Vec<(u64, Option<String>, u64)>, start: u64, end: u64, interval: u64, grouped: bool) -> Vec<Bucket> {
+    let first = start - start % interval;
+    let mut buckets: Vec<Bucket> = (first..=end)
+        .step_by(interval as usize)
+        .map(|start| Bucket { start, total: 0, counts: BTreeMap::new() })
+        .collect();
+    for (bucket, group, count) in rows {
+        let Some(b) = buckets.get_mut(((bucket.saturating_sub(first)) / interval) as usize) else {
+            continue;
+        };
+        b.total += count;
+        if grouped {
+            *b.counts.entry(group.unwrap_or_else(|| "-".to_string())).or_default() += count;
+        }
+    }
+    buckets
+}
+
+/// Message counts per time interval over the filter's range, by default the last day. Read from the rollup
+/// counters when the interval is whole minutes and the filter only uses what they count.
+pub async fn histogram(
+    con: &mut MultiplexedConnection,
+    mut filter: Filter,
+    params: &HistogramParams,
+    now: u64,
+) -> Result<Histogram> {
+    let (start, end) = time_range(&mut filter, now);
+    let interval = match params.interval {
+        Some(0) => bail!("interval has to be at least a second"),
+        Some(seconds) => seconds.checked_mul(1000).context("interval is too long")?,
+        None => pick_interval(start, end),
+    };
+    if end.saturating_sub(start) / interval > MAX_BUCKETS {
+        bail!("Too many buckets, use a larger interval");
+    }
+    let rows = if interval.is_multiple_of(60_000) && rollup::supports(&filter, params.group) {
+        rollup::histogram(con, &filter, start, end, interval, params.group).await?
+    } else {
+        let query = histogram_query(&filter, interval, params.group);
+        let results = con.graph_ro_query(database::GRAPH_NAME, query).await?;
+        results
+            .data
+            .iter()
+            .filter_map(|r| Some((r.get_scalar("bucket")?, r.get_scalar("group_name"), r.get_scalar("count")?)))
+            .collect()
+    };
+    Ok(Histogram {
+        interval,
+        buckets: fill(rows, start, end, interval, params.group.is_some()),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TopParams {
+    pub by: Field,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Count {
+    pub key: String,
+    pub count: u64,
+}
+
+/// The values of a field with the most messages over the filter's range, `limit` of them (default 10)
+pub async fn top(con: &mut MultiplexedConnection, mut filter: Filter, by: Field, now: u64) -> Result<Vec<Count>> {
+    let (start, end) = time_range(&mut filter, now);
+    if rollup::supports(&filter, Some(by)) {
+        return rollup::top(con, &filter, by, start, end, filter.limit.unwrap_or(10)).await;
+    }
+    let query = format!(
+        "{} WITH {} as key, node WHERE key IS NOT NULL RETURN key, count(node) as count ORDER BY count DESC LIMIT {}",
+        filter.match_clause(),
+        by.expression(),
+        filter.limit.unwrap_or(10),
+    );
+    let results = con.graph_ro_query(database::GRAPH_NAME, query).await?;
+    Ok(results
+        .data
+        .iter()
+        .filter_map(|r| Some(Count { key: r.get_scalar("key")?, count: r.get_scalar("count")? }))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fill, histogram_query, pick_interval, Field};
+    use crate::search::Filter;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(pick_interval(0, 86_400_000), 900_000);
+        let query = histogram_query(&Filter::default(), 60_000, Some(Field::Host));
+        assert!(query.ends_with("WITH node.server_timestamp - node.server_timestamp % 60000 as bucket, hostname.name as group_name, node RETURN bucket, group_name, count(node) as count ORDER BY bucket"));
+
+        let rows = vec![(60_000, Some("a".to_string()), 2), (60_000, None, 1), (180_000, Some("a".to_string()), 4)];
+        let buckets = fill(rows, 90_000, 200_000, 60_000, true);
+        assert_eq!(buckets.iter().map(|b| (b.start, b.total)).collect::<Vec<_>>(), [(60_000, 3), (120_000, 0), (180_000, 4)]);
+        assert_eq!(buckets[0].counts["-"], 1);
+    }
+}
@@ -1,14 +1,26 @@
-use std::{env, net::SocketAddr, time::SystemTime};
+use std::{
+    env,
+    fmt::Write,
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 use tokio::sync::{broadcast::Sender, watch::Receiver};
 
-use crate::{database, utils::escape};
+use crate::{database, health, metrics::METRICS, relay::Relay, rollup, systemd, tls, utils::escape};
 use anyhow::{anyhow, Result};
+use chrono::{Local, SecondsFormat};
 use redis::aio::MultiplexedConnection;
 use redis_graph::AsyncGraphCommands;
-use syslog_loose::{parse_message, Message};
-use tokio::net::UdpSocket;
+use syslog_loose::{parse_message, Message, ProcId};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+};
+use tracing::{debug, info, info_span, trace, warn, Instrument};
 
-async fn parse_buffer(len: usize, buffer: &[u8]) -> Result<Message<&str>> {
+pub async fn parse_buffer(len: usize, buffer: &[u8]) -> Result<Message<&str>> {
     let msg_buffer = std::str::from_utf8(&buffer[0..len]);
     let msg_buffer = match msg_buffer {
         Err(e) => {
@@ -20,36 +32,74 @@ async fn parse_buffer(len: usize, buffer: &[u8]) -> Result<Message<&str>> {
     Ok(msg)
 }
 
-async fn store_msg(
+pub async fn store_msg(
     con: &mut MultiplexedConnection,
     msg: Message<&str>,
-    addr: &SocketAddr,
+    ip: &IpAddr,
 ) -> Result<usize> {
-    let timestamp = msg
-        .timestamp
-        .and_then(|t| Some(t.timestamp().to_string()))
-        .unwrap_or_else(|| "null".to_string());
     let server_timestamp = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .expect("Unable to get system time")
         .as_millis();
+    store_msg_at(con, msg, ip, server_timestamp).await
+}
+
+/// Expression for the newest of a node's `last_seen` and `server_timestamp`, so backfilling history doesn't move it back
+fn last_seen(node: &str, server_timestamp: u128) -> String {
+    format!("CASE WHEN {node}.last_seen > {server_timestamp} THEN {node}.last_seen ELSE {server_timestamp} END")
+}
+
+/// Stores a message as if it had been received at `server_timestamp` milliseconds, used when backfilling history
+pub async fn store_msg_at(
+    con: &mut MultiplexedConnection,
+    msg: Message<&str>,
+    ip: &IpAddr,
+    server_timestamp: u128,
+) -> Result<usize> {
+    let id = insert_msg(con, msg.clone(), ip, server_timestamp).await?;
+    rollup::record(
+        con,
+        msg.hostname,
+        msg.severity.map(|s| s.as_str()),
+        msg.appname,
+        server_timestamp as u64,
+    )
+    .await?;
+    Ok(id)
+}
+
+/// Adds a message to the graph without counting it, for messages that were counted before, e.g. restored from
+/// the archive
+pub async fn insert_msg(
+    con: &mut MultiplexedConnection,
+    msg: Message<&str>,
+    ip: &IpAddr,
+    server_timestamp: u128,
+) -> Result<usize> {
+    let timestamp = msg
+        .timestamp
+        .map(|t| t.timestamp().to_string())
+        .unwrap_or_else(|| "null".to_string());
 
     let mut query = format!(
         "
         MERGE (addr:Address {{ip: '{ip}'}})
+        SET addr.last_seen = {last_seen}
         CREATE (msg:Message {{id: {msg_id}, msg: '{msg}', server_timestamp: {server_timestamp}, timestamp: {timestamp}}})-[:from]->(addr)
         ",
         msg = escape(msg.msg),
-        msg_id = msg.msgid.and_then(|id| Some(format!("'{id}'", id=escape(id)))).unwrap_or_else(|| "null".to_string()),
-        ip = addr.ip().to_string()
+        last_seen = last_seen("addr", server_timestamp),
+        msg_id = msg.msgid.map(|id| format!("'{id}'", id=escape(id))).unwrap_or_else(|| "null".to_string())
     );
     if let Some(hostname) = msg.hostname {
         query.push_str(&format!(
             "
             MERGE (host:Hostname {{name: '{hostname}'}})
+            SET host.last_seen = {last_seen}
             MERGE (msg)-[:host]->(host)
         ",
-            hostname = escape(hostname)
+            hostname = escape(hostname),
+            last_seen = last_seen("host", server_timestamp),
         ));
     }
     if let Some(facility) = msg.facility {
@@ -98,9 +148,8 @@ async fn store_msg(
 
     query.push_str("\nRETURN id(msg) as id");
 
-    #[cfg(debug_assertions)]
-    dbg!(&query);
-    
+    trace!(%query, "Storing message");
+
     let result = con.graph_query(database::GRAPH_NAME, query).await?;
     let id: usize = result.data[0]
         .get_scalar("id")
@@ -108,16 +157,147 @@ async fn store_msg(
     Ok(id)
 }
 
+/// A message that was just stored, as broadcast to the rest of the server
+#[derive(Debug)]
+pub struct Stored {
+    pub id: usize,
+    pub ip: IpAddr,
+    pub msg: Message<String>,
+    /// When the server received it, in milliseconds
+    pub server_timestamp: u64,
+}
+
+/// Stores a message, relays it and announces it to everything subscribed to the broadcast channel
+pub async fn ingest(
+    con: &mut MultiplexedConnection,
+    msg: Message<&str>,
+    ip: &IpAddr,
+    sender: &Sender<crate::Signal>,
+    relay: &Relay,
+) -> Result<usize> {
+    let started = Instant::now();
+    let server_timestamp = crate::alert::now_millis();
+    let id = store_msg_at(con, msg.clone(), ip, server_timestamp.into()).await?;
+    METRICS.store_seconds.observe(started.elapsed().as_secs_f64());
+    relay.forward(&msg, ip);
+
+    debug!(id, "Sending new node id to broadcast");
+    sender.send(crate::Signal::NewMessage(Arc::new(Stored {
+        id,
+        ip: *ip,
+        msg: msg.into(),
+        server_timestamp,
+    })))?;
+    Ok(id)
+}
+
+/// Frames a message for a stream transport with octet counting (RFC 6587)
+pub fn frame(msg: &str) -> Vec<u8> {
+    format!("{} {}", msg.len(), msg).into_bytes()
+}
+
+/// Longest message accepted on a stream, so one connection can't make the server buffer without bound
+pub const MAX_FRAME: usize = 64 * 1024;
+
+/// The longest start of `msg` that fits in a frame, cut between characters
+pub fn truncate_to_frame(msg: &str) -> &str {
+    let mut end = msg.len().min(MAX_FRAME);
+    while !msg.is_char_boundary(end) {
+        end -= 1;
+    }
+    &msg[..end]
+}
+
+/// Splits the first message off the front of a stream, accepting both octet counting and newline delimited
+/// framing (RFC 6587). Returns the message and the number of bytes it used, or `None` until it is complete.
+pub fn next_frame(buf: &[u8]) -> Result<Option<(&[u8], usize)>> {
+    if !buf.first().is_some_and(u8::is_ascii_digit) {
+        return match buf.iter().position(|b| *b == b'\n') {
+            Some(i) => Ok(Some((&buf[..i], i + 1))),
+            None if buf.len() > MAX_FRAME => Err(anyhow!("Frame longer than {MAX_FRAME} bytes")),
+            None => Ok(None),
+        };
+    }
+    let space = match buf.iter().position(|b| *b == b' ') {
+        Some(i) => i,
+        None if buf.len() > 10 => return Err(anyhow!("Invalid octet count in frame")),
+        None => return Ok(None),
+    };
+    let len: usize = std::str::from_utf8(&buf[..space])?.parse()?;
+    if len > MAX_FRAME {
+        return Err(anyhow!("Frame of {len} bytes is longer than {MAX_FRAME}"));
+    }
+    let end = (space + 1)
+        .checked_add(len)
+        .ok_or_else(|| anyhow!("Invalid octet count in frame"))?;
+    if buf.len() < end {
+        return Ok(None);
+    }
+    Ok(Some((&buf[space + 1..end], end)))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Rfc3164,
+    Rfc5424,
+}
+
+/// Writes a message back out as a syslog line. Messages without a priority are sent as user.notice, the
+/// default from RFC 3164, and `hostname` is used when the message has none.
+pub fn format_message(msg: &Message<&str>, format: Format, hostname: Option<&str>) -> String {
+    let pri = msg.facility.map_or(1, |f| f as u8) * 8 + msg.severity.map_or(5, |s| s as u8);
+    let timestamp = msg.timestamp.unwrap_or_else(|| Local::now().into());
+    let hostname = msg.hostname.or(hostname).unwrap_or("-");
+    let mut line = String::new();
+    match format {
+        Format::Rfc3164 => {
+            write!(line, "<{pri}>{} {hostname} ", timestamp.format("%b %e %H:%M:%S")).unwrap();
+            match (msg.appname, &msg.procid) {
+                (Some(app), Some(procid)) => write!(line, "{app}[{procid}]: "),
+                (Some(app), None) => write!(line, "{app}: "),
+                _ => Ok(()),
+            }
+            .unwrap();
+        }
+        Format::Rfc5424 => {
+            write!(
+                line,
+                "<{pri}>1 {} {hostname} {} {} {} ",
+                timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+                msg.appname.unwrap_or("-"),
+                msg.procid.as_ref().map_or("-".to_string(), ProcId::to_string),
+                msg.msgid.unwrap_or("-"),
+            )
+            .unwrap();
+            if msg.structured_data.is_empty() {
+                line.push_str("- ");
+            } else {
+                for element in &msg.structured_data {
+                    write!(line, "{element}").unwrap();
+                }
+                line.push(' ');
+            }
+        }
+    }
+    line.push_str(msg.msg);
+    line
+}
+
 pub async fn listen(
     mut shutdown_signal: Receiver<()>,
     sender: Sender<crate::Signal>,
+    relay: Relay,
 ) -> Result<()> {
-    println!("Syslog listener started!");
+    info!("Syslog listener started");
     let mut con = database::connect().await?;
 
     let host: String = env::var("EZSYSLOG_SYSLOG_HOST").unwrap_or("::".to_string());
     let port: String = env::var("EZSYSLOG_SYSLOG_PORT").unwrap_or("514".to_string());
-    let udp = UdpSocket::bind(format!("{}:{}", host, port)).await?;
+    let udp = match systemd::udp_socket("syslog")? {
+        Some(udp) => udp,
+        None => UdpSocket::bind(format!("{}:{}", host, port)).await?,
+    };
+    health::listening("udp", udp.local_addr()?);
 
     // let tcp = TcpSocket::new_v4()?;
     // let tcp6 = TcpSocket::new_v6()?;
@@ -136,26 +316,171 @@ pub async fn listen(
             },
             res = udp.recv_from(&mut buf) => {
                 let (len, addr) = res?;
+                METRICS.received.with_label_values(&["udp"]).inc();
+                METRICS.received_bytes.with_label_values(&["udp"]).inc_by(len as u64);
                 let msg = match parse_buffer(len, &buf).await {
                     Err(e) => {
-                        println!("Unable to parse syslog message: {}", e);
+                        METRICS.failed.with_label_values(&["udp"]).inc();
+                        warn!(%addr, "Unable to parse syslog message: {e}");
                         continue;
                     }
                     Ok(msg) => msg,
                 };
+                METRICS.parsed.with_label_values(&["udp"]).inc();
+
+                trace!(?msg, "Received message");
+
+                ingest(&mut con, msg, &addr.ip(), &sender, &relay).await?;
+            }
+        };
+    }
 
-                #[cfg(debug_assertions)]
-                dbg!(&msg);
+    info!("Syslog listener stopped");
+
+    Ok(())
+}
 
-                let message_node_id = store_msg(&mut con, msg, &addr).await?;
+pub fn agent_port_from_env() -> Option<String> {
+    env::var("EZSYSLOG_AGENT_PORT").ok()
+}
 
-                println!("Sending new node id to broadcast");
-                sender.send(crate::Signal::NewMessage(message_node_id))?;
+/// Stores framed messages from a stream, acknowledging each stored message by writing the number of messages
+/// stored so far on the connection followed by a newline
+async fn handle_agent<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    ip: IpAddr,
+    mut con: MultiplexedConnection,
+    sender: Sender<crate::Signal>,
+    relay: Relay,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    let mut read = [0; 8192];
+    let mut count: u64 = 0;
+    loop {
+        let len = stream.read(&mut read).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&read[..len]);
+        let mut batch = 0;
+        while let Some((frame, used)) = next_frame(&buf)? {
+            let frame = frame.to_vec();
+            buf.drain(..used);
+            METRICS.received.with_label_values(&["agent"]).inc();
+            METRICS.received_bytes.with_label_values(&["agent"]).inc_by(frame.len() as u64);
+            match parse_buffer(frame.len(), &frame).await {
+                Ok(msg) => {
+                    METRICS.parsed.with_label_values(&["agent"]).inc();
+                    ingest(&mut con, msg, &ip, &sender, &relay).await?;
+                }
+                // Acknowledged anyway, sending it again would not help
+                Err(e) => {
+                    METRICS.failed.with_label_values(&["agent"]).inc();
+                    warn!("Unable to parse agent message: {e}");
+                }
+            }
+            count += 1;
+            batch += 1;
+            stream.write_all(format!("{count}\n").as_bytes()).await?;
+        }
+        if batch > 0 {
+            METRICS.batch_size.with_label_values(&["agent"]).observe(batch as f64);
+        }
+    }
+}
+
+/// Listens for `ezsyslog-agent` connections, over TLS when `EZSYSLOG_AGENT_TLS_CERT` and `EZSYSLOG_AGENT_TLS_KEY` are set
+pub async fn listen_agent(
+    mut shutdown_signal: Receiver<()>,
+    sender: Sender<crate::Signal>,
+    relay: Relay,
+) -> Result<()> {
+    info!("Agent listener started");
+    let con = database::connect().await?;
+
+    let host: String = env::var("EZSYSLOG_SYSLOG_HOST").unwrap_or("::".to_string());
+    let port = agent_port_from_env().unwrap_or("5514".to_string());
+    let acceptor = match (env::var("EZSYSLOG_AGENT_TLS_CERT"), env::var("EZSYSLOG_AGENT_TLS_KEY")) {
+        (Ok(cert), Ok(key)) => Some(tls::acceptor(Path::new(&cert), Path::new(&key))?),
+        _ => None,
+    };
+    let listener = match systemd::tcp_listener("agent")? {
+        Some(listener) => TcpListener::from_std(listener)?,
+        None => TcpListener::bind(format!("{}:{}", host, port)).await?,
+    };
+    health::listening("agent", listener.local_addr()?);
+
+    loop {
+        tokio::select! {
+            _ = shutdown_signal.changed() => {
+                break;
+            },
+            res = listener.accept() => {
+                let (stream, addr) = res?;
+                let con = con.clone();
+                let sender = sender.clone();
+                let acceptor = acceptor.clone();
+                let relay = relay.clone();
+                let connection = async move {
+                    let result = match acceptor {
+                        Some(acceptor) => match acceptor.accept(stream).await {
+                            Ok(stream) => handle_agent(stream, addr.ip(), con, sender, relay).await,
+                            Err(e) => Err(e.into()),
+                        },
+                        None => handle_agent(stream, addr.ip(), con, sender, relay).await,
+                    };
+                    if let Err(e) = result {
+                        info!("Agent connection closed: {e}");
+                    }
+                };
+                tokio::spawn(connection.instrument(info_span!("connection", %addr)));
             }
         };
     }
 
-    println!("Syslog listener stopped.");
+    info!("Agent listener stopped");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{format_message, frame, next_frame, truncate_to_frame, Format, MAX_FRAME};
+    use syslog_loose::parse_message;
+
+    #[test]
+    fn framing() {
+        let mut stream = frame("<13>1 - - - - - - hello");
+        stream.extend_from_slice(b"<13>plain\n");
+        let (msg, used) = next_frame(&stream).unwrap().unwrap();
+        assert_eq!(msg, b"<13>1 - - - - - - hello");
+        let (msg, _) = next_frame(&stream[used..]).unwrap().unwrap();
+        assert_eq!(msg, b"<13>plain");
+        assert!(next_frame(b"12 partial").unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_frames() {
+        assert!(next_frame(format!("{} x", MAX_FRAME + 1).as_bytes()).is_err());
+        assert!(next_frame(format!("{} x", usize::MAX).as_bytes()).is_err());
+        assert!(next_frame(format!("{} x", u128::MAX).as_bytes()).is_err());
+        assert!(next_frame(&vec![b'x'; MAX_FRAME]).unwrap().is_none());
+        assert!(next_frame(&vec![b'x'; MAX_FRAME + 1]).is_err());
+
+        // Two byte characters, the last one straddling the limit
+        let long = format!("x{}", "é".repeat(MAX_FRAME / 2));
+        assert_eq!(truncate_to_frame(&long).len(), MAX_FRAME - 1);
+        assert_eq!(truncate_to_frame("short"), "short");
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let line = "<34>1 2003-10-11T22:14:15.003Z mymachine.example.com su - ID47 [exampleSDID@32473 iut=\"3\"] 'su root' failed";
+        let msg = parse_message(line);
+        assert_eq!(format_message(&msg, Format::Rfc5424, None), line);
+        assert_eq!(
+            format_message(&msg, Format::Rfc3164, None),
+            "<34>Oct 11 22:14:15 mymachine.example.com su: 'su root' failed"
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use std::{
+    collections::HashMap,
+    net,
+    os::fd::{FromRawFd, OwnedFd},
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{
+    database,
+    health::{self, Listener},
+};
+use anyhow::{Context, Result};
+use redis::aio::MultiplexedConnection;
+use sd_notify::NotifyState;
+use tokio::sync::watch::Receiver;
+use tracing::{error, info, warn};
+
+/// Sockets passed by socket activation, by their `FileDescriptorName=`
+static SOCKETS: Mutex<Option<HashMap<String, OwnedFd>>> = Mutex::new(None);
+
+/// Takes the sockets systemd passed in `LISTEN_FDS`, must be called before any listener starts
+pub fn init() -> Result<()> {
+    let mut sockets = HashMap::new();
+    for (fd, name) in sd_notify::listen_fds_with_names(true).context("Invalid socket activation")? {
+        info!(socket = %name, "Using socket from systemd");
+        // systemd hands each descriptor to this process alone
+        sockets.insert(name, unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+    *SOCKETS.lock().unwrap() = Some(sockets);
+    Ok(())
+}
+
+fn take(name: &str) -> Option<OwnedFd> {
+    SOCKETS.lock().unwrap().as_mut()?.remove(name)
+}
+
+/// The UDP socket systemd passed as `name`, if any
+pub fn udp_socket(name: &str) -> Result<Option<tokio::net::UdpSocket>> {
+    let Some(fd) = take(name) else {
+        return Ok(None);
+    };
+    let socket = net::UdpSocket::from(fd);
+    socket.set_nonblocking(true)?;
+    Ok(Some(tokio::net::UdpSocket::from_std(socket)?))
+}
+
+/// The listening TCP socket systemd passed as `name`, if any
+pub fn tcp_listener(name: &str) -> Result<Option<net::TcpListener>> {
+    let Some(fd) = take(name) else {
+        return Ok(None);
+    };
+    let listener = net::TcpListener::from(fd);
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+/// Tells systemd the server is up once every listener is, does nothing when not started by systemd with
+/// `Type=notify`
+pub async fn notify_ready() {
+    if let Err(e) = health::all_listening().await {
+        error!("Not telling systemd the server is ready: {e}");
+        return;
+    }
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready, NotifyState::Status("Listening")]) {
+        warn!("Unable to notify systemd: {e}");
+    }
+}
+
+pub fn notify_stopping() {
+    let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+}
+
+async fn healthy(con: &mut MultiplexedConnection) -> bool {
+    !health::listeners().values().any(|l| matches!(l, Listener::Failed { .. })) && health::ping(con).await.is_ok()
+}
+
+/// Pings the systemd watchdog at half its `WatchdogSec=` while no listener has failed and the database answers,
+/// so systemd restarts a server that stopped doing its job
+pub async fn watchdog(mut shutdown_signal: Receiver<()>) -> Result<()> {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return Ok(());
+    }
+    let mut con = database::connect().await?;
+    let mut interval = tokio::time::interval(Duration::from_micros(usec / 2));
+    loop {
+        tokio::select! {
+            _ = shutdown_signal.changed() => break,
+            _ = interval.tick() => {}
+        }
+        if healthy(&mut con).await {
+            sd_notify::notify(false, &[NotifyState::Watchdog])?;
+        } else {
+            warn!("Unhealthy, not pinging the systemd watchdog");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tcp_listener, udp_socket, SOCKETS};
+    use std::{net, os::fd::OwnedFd};
+
+    #[tokio::test]
+    async fn passed_sockets() {
+        let udp = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp.local_addr().unwrap();
+        SOCKETS.lock().unwrap().get_or_insert_default().insert("syslog".to_string(), OwnedFd::from(udp));
+
+        assert_eq!(udp_socket("syslog").unwrap().unwrap().local_addr().unwrap(), addr);
+        assert!(udp_socket("syslog").unwrap().is_none());
+        assert!(tcp_listener("http").unwrap().is_none());
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context, Result};
+use futures_util::{stream, Stream};
+use poem::listener::{RustlsCertificate, RustlsConfig};
+use tokio_rustls::{
+    rustls::{
+        Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Unable to open {}", path.display()))?,
+    );
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Unable to open {}", path.display()))?,
+    );
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key)) => {
+                return Ok(PrivateKey(key))
+            }
+            Some(_) => continue,
+            None => return Err(anyhow!("No private key found in {}", path.display())),
+        }
+    }
+}
+
+/// Server side TLS from a PEM certificate chain and private key
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert)?, load_key(key)?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Client side TLS trusting either the certificates in a PEM file, for a private CA, or the public web PKI roots
+pub fn connector(ca: Option<&Path>) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in load_certs(ca)? {
+                roots.add(&cert)?;
+            }
+        }
+        None => roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })),
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// The certificate and key for the HTTP server, loaded again whenever either file changes so renewed certificates
+/// are picked up without a restart. Files that fail to load are skipped until they change again.
+pub fn reloading_config(cert: PathBuf, key: PathBuf, every: Duration) -> impl Stream<Item = RustlsConfig> {
+    stream::unfold(None, move |loaded| {
+        let (cert, key) = (cert.clone(), key.clone());
+        async move {
+            let mut loaded = loaded;
+            loop {
+                if loaded.is_some() {
+                    tokio::time::sleep(every).await;
+                }
+                let current = (modified(&cert), modified(&key));
+                if loaded == Some(current) {
+                    continue;
+                }
+                let first = loaded.is_none();
+                loaded = Some(current);
+                if let Err(e) = acceptor(&cert, &key) {
+                    tracing::error!("Unable to load HTTPS certificate: {e}");
+                    continue;
+                }
+                let (Ok(cert_pem), Ok(key_pem)) = (fs::read(&cert), fs::read(&key)) else {
+                    continue;
+                };
+                if !first {
+                    tracing::info!(cert = %cert.display(), "Reloaded HTTPS certificate");
+                }
+                return Some((RustlsConfig::new().fallback(RustlsCertificate::new().cert(cert_pem).key(key_pem)), loaded));
+            }
+        }
+    })
+}
@@ -1,10 +1,10 @@
 use std::borrow::Cow;
 
 // https://fullstackmilk.dev/efficiently_escaping_strings_using_cow_in_rust/
-pub fn escape(input: &str) -> Cow<str> {
+pub fn escape(input: &str) -> Cow<'_, str> {
   // Iterate through the characters, checking if each one needs escaping
-  for (i, ch) in input.chars().enumerate() {
-      if ch == '\'' {
+  for (i, ch) in input.char_indices() {
+      if ch == '\'' || ch == '\\' {
           // At least one char needs escaping, so we need to return a brand
           // new `String` rather than the original
 
@@ -24,9 +24,11 @@ pub fn escape(input: &str) -> Cow<str> {
           // Escape the remaining characters if they need it and add them to
           // our escaped string
           for ch in input[i..].chars() {
-              match ch == '\'' {
-                  true => escaped_string.push_str("\\\'"),
-                  false => escaped_string.push(ch),
+              // Backslashes too, or a trailing one would escape the quote ending the literal
+              match ch {
+                  '\\' => escaped_string.push_str("\\\\"),
+                  '\'' => escaped_string.push_str("\\\'"),
+                  _ => escaped_string.push(ch),
               };
           }
 
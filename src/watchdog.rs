@@ -0,0 +1,470 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    env, fs,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{
+    broadcast::{error::RecvError, Sender},
+    watch::Receiver,
+};
+
+use crate::{
+    database, health,
+    notify::{Notification, Notifiers},
+    syslog::Stored,
+    utils::escape,
+};
+use anyhow::{Context, Result};
+use redis::aio::MultiplexedConnection;
+use redis_graph::AsyncGraphCommands;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+/// Start of the rule names the server uses for its own alerts, refused for user rules
+pub const RESERVED_PREFIX: &str = "ezsyslog:";
+
+/// Rule name of the alerts raised for hosts that went quiet
+pub const SILENT_RULE: &str = "ezsyslog:silent";
+
+/// Gaps between messages shorter than this are bursts and aren't used to learn a host's cadence
+const MIN_GAP: u64 = 1000;
+
+fn default_learn() -> bool {
+    true
+}
+
+fn default_factor() -> u64 {
+    3
+}
+
+fn default_min_window() -> u64 {
+    300
+}
+
+fn default_samples() -> usize {
+    10
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// Seconds of silence allowed per hostname or address, overriding anything learned
+    #[serde(default)]
+    pub hosts: HashMap<String, u64>,
+    /// Learn the cadence of hosts that aren't configured
+    #[serde(default = "default_learn")]
+    pub learn: bool,
+    /// A learned host is silent after this many times the longest recent gap between its messages
+    #[serde(default = "default_factor")]
+    pub factor: u64,
+    /// Shortest learned window in seconds
+    #[serde(default = "default_min_window")]
+    pub min_window: u64,
+    /// Gaps needed before a host's cadence is learned, and how many recent gaps are kept
+    #[serde(default = "default_samples")]
+    pub samples: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        serde_json::from_str("{}").unwrap()
+    }
+}
+
+pub fn config_path_from_env() -> Option<String> {
+    env::var("EZSYSLOG_WATCHDOG").ok()
+}
+
+/// Reads the JSON config, an empty path only learns
+pub fn load_config(path: &str) -> Result<Config> {
+    if path.is_empty() {
+        return Ok(Config::default());
+    }
+    let data = fs::read(path).with_context(|| format!("Unable to read watchdog config from {path}"))?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Host {
+    Hostname(String),
+    Address(String),
+}
+
+impl Host {
+    fn name(&self) -> &str {
+        match self {
+            Host::Hostname(name) | Host::Address(name) => name,
+        }
+    }
+
+    /// Node pattern for the host in the graph
+    fn node(&self) -> String {
+        match self {
+            Host::Hostname(name) => format!("(h:Hostname {{name: '{}'}})", escape(name)),
+            Host::Address(ip) => format!("(h:Address {{ip: '{}'}})", escape(ip)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Seen {
+    last_seen: u64,
+    /// Recent gaps between messages in milliseconds
+    gaps: VecDeque<u64>,
+    /// Longest of the recent gaps once enough are known, kept across restarts
+    cadence: Option<u64>,
+    /// Whether `cadence` is stored in the graph
+    saved: bool,
+    /// Only the host a message is attributed to learns, the address of a message with a hostname is only
+    /// watched when configured
+    learn: bool,
+    silent: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Transition {
+    /// No message arrived within the host's window
+    Silent { host: Host, last_seen: u64 },
+    /// A silent host sent a message again
+    Resumed { host: Host },
+}
+
+/// Tracks when each host was last heard from and decides when it has gone silent, leaving storage to the caller
+pub struct Watchdog {
+    config: Config,
+    hosts: HashMap<Host, Seen>,
+}
+
+impl Watchdog {
+    pub fn new(config: Config) -> Watchdog {
+        Watchdog {
+            config,
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Picks up a host from the graph with its learned `cadence`, e.g. after a restart
+    pub fn restore(&mut self, host: Host, last_seen: u64, silent: bool, cadence: Option<u64>) {
+        let seen = self.hosts.entry(host).or_default();
+        seen.last_seen = seen.last_seen.max(last_seen);
+        seen.silent = silent;
+        if cadence.is_some() && seen.cadence.is_none() {
+            seen.cadence = cadence;
+            seen.learn = true;
+            seen.saved = true;
+        }
+    }
+
+    /// Learned cadences that changed since they were last stored
+    pub fn unsaved(&self) -> Vec<(Host, u64)> {
+        self.hosts
+            .iter()
+            .filter(|(_, seen)| !seen.saved)
+            .filter_map(|(host, seen)| Some((host.clone(), seen.cadence?)))
+            .collect()
+    }
+
+    /// Notes that `cadence` was stored for `host`, unless it changed meanwhile
+    pub fn saved(&mut self, host: &Host, cadence: u64) {
+        if let Some(seen) = self.hosts.get_mut(host).filter(|seen| seen.cadence == Some(cadence)) {
+            seen.saved = true;
+        }
+    }
+
+    pub fn observe(&mut self, stored: &Stored, now: u64) -> Vec<Transition> {
+        let address = Host::Address(stored.ip.to_string());
+        let mut hosts = vec![(address, stored.msg.hostname.is_none())];
+        if let Some(hostname) = &stored.msg.hostname {
+            hosts.push((Host::Hostname(hostname.clone()), true));
+        }
+        let mut transitions = vec![];
+        for (host, learn) in hosts {
+            let samples = self.config.samples;
+            let seen = self.hosts.entry(host.clone()).or_default();
+            let gap = now.saturating_sub(seen.last_seen);
+            if seen.last_seen > 0 && gap >= MIN_GAP {
+                seen.gaps.push_back(gap);
+                if seen.gaps.len() > samples {
+                    seen.gaps.pop_front();
+                }
+            }
+            seen.last_seen = now;
+            seen.learn = learn;
+            if learn && seen.gaps.len() >= samples {
+                let longest = seen.gaps.iter().max().copied();
+                if seen.cadence != longest {
+                    seen.cadence = longest;
+                    seen.saved = false;
+                }
+            }
+            if seen.silent {
+                seen.silent = false;
+                transitions.push(Transition::Resumed { host });
+            }
+        }
+        transitions
+    }
+
+    /// Milliseconds a host may stay quiet, if it is configured or its cadence has been learned
+    fn window(&self, host: &Host, seen: &Seen) -> Option<u64> {
+        if let Some(seconds) = self.config.hosts.get(host.name()) {
+            return Some(seconds * 1000);
+        }
+        if !self.config.learn || !seen.learn {
+            return None;
+        }
+        let longest = seen.cadence?;
+        Some(longest.saturating_mul(self.config.factor).max(self.config.min_window * 1000))
+    }
+
+    pub fn tick(&mut self, now: u64) -> Vec<Transition> {
+        let mut silent = vec![];
+        for (host, seen) in &self.hosts {
+            if seen.silent || seen.last_seen == 0 {
+                continue;
+            }
+            if self.window(host, seen).is_some_and(|w| now.saturating_sub(seen.last_seen) >= w) {
+                silent.push(host.clone());
+            }
+        }
+        silent
+            .into_iter()
+            .map(|host| {
+                let seen = self.hosts.get_mut(&host).unwrap();
+                seen.silent = true;
+                Transition::Silent { host, last_seen: seen.last_seen }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HostStatus {
+    /// `hostname` or `address`
+    pub kind: &'static str,
+    pub name: String,
+    /// Milliseconds since the epoch
+    pub last_seen: Option<u64>,
+    pub silent: bool,
+    /// Longest recent gap between its messages in milliseconds, once learned
+    pub cadence: Option<u64>,
+}
+
+/// Every hostname and address with when it last sent a message
+pub async fn hosts(con: &mut MultiplexedConnection) -> Result<Vec<HostStatus>> {
+    let mut hosts = vec![];
+    for (kind, query) in [
+        ("hostname", "MATCH (h:Hostname) RETURN h.name as name, h.last_seen as last_seen, h.silent as silent, h.cadence as cadence ORDER BY name"),
+        ("address", "MATCH (h:Address) RETURN h.ip as name, h.last_seen as last_seen, h.silent as silent, h.cadence as cadence ORDER BY name"),
+    ] {
+        for result in con.graph_ro_query(database::GRAPH_NAME, query).await?.data {
+            if let Some(name) = result.get_scalar("name") {
+                hosts.push(HostStatus {
+                    kind,
+                    name,
+                    last_seen: result.get_scalar("last_seen"),
+                    silent: database::get_bool(&result, "silent"),
+                    cadence: result.get_scalar("cadence"),
+                });
+            }
+        }
+    }
+    Ok(hosts)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Unable to get system time")
+        .as_millis() as u64
+}
+
+async fn restore(con: &mut MultiplexedConnection, watchdog: &mut Watchdog) -> Result<()> {
+    for host in hosts(con).await? {
+        let name = host.name;
+        let host_key = match host.kind {
+            "hostname" => Host::Hostname(name),
+            _ => Host::Address(name),
+        };
+        if let Some(last_seen) = host.last_seen {
+            watchdog.restore(host_key, last_seen, host.silent, host.cadence);
+        }
+    }
+    Ok(())
+}
+
+/// Stores the cadences learned since the last call, so a restart doesn't have to learn them again
+async fn save_cadences(con: &mut MultiplexedConnection, watchdog: &mut Watchdog) -> Result<()> {
+    for (host, cadence) in watchdog.unsaved() {
+        let query = format!("MATCH {node} SET h.cadence = {cadence}", node = host.node());
+        con.graph_query(database::GRAPH_NAME, query).await?;
+        watchdog.saved(&host, cadence);
+    }
+    Ok(())
+}
+
+/// Stores a transition, returning the node id of the alert it raised or resolved
+async fn apply(con: &mut MultiplexedConnection, transition: &Transition, now: u64) -> Result<Option<u64>> {
+    let query = match transition {
+        Transition::Silent { host, last_seen } => format!(
+            "
+            MATCH {node}
+            SET h.silent = true
+            CREATE (alert:Alert {{rule: '{SILENT_RULE}', host: '{name}', state: 'firing', started_at: {now}, last_match: {last_seen}, count: 0}})
+            RETURN ID(alert) as id
+            ",
+            node = host.node(),
+            name = escape(host.name()),
+        ),
+        Transition::Resumed { host } => format!(
+            "
+            MATCH {node}
+            SET h.silent = false
+            WITH h
+            MATCH (alert:Alert) WHERE alert.rule = '{SILENT_RULE}' AND alert.host = '{name}' AND alert.state = 'firing'
+            SET alert.state = 'resolved', alert.resolved_at = {now}
+            RETURN ID(alert) as id
+            ",
+            node = host.node(),
+            name = escape(host.name()),
+        ),
+    };
+    let result = con.graph_query(database::GRAPH_NAME, query).await?;
+    Ok(result.data.first().and_then(|r| r.get_scalar("id")))
+}
+
+pub async fn listen(
+    mut shutdown_signal: Receiver<()>,
+    sender: Sender<crate::Signal>,
+) -> Result<()> {
+    info!("Watchdog started");
+    let mut con = database::connect().await?;
+    let config = load_config(&config_path_from_env().unwrap_or_default())?;
+    let mut watchdog = Watchdog::new(config);
+    restore(&mut con, &mut watchdog).await?;
+    let notifiers = Notifiers::from_env()?;
+    health::running("watchdog");
+
+    let mut receiver = sender.subscribe();
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        let now = now_millis();
+        let transitions = tokio::select! {
+            _ = shutdown_signal.changed() => {
+                break;
+            },
+            _ = interval.tick() => {
+                if let Err(e) = save_cadences(&mut con, &mut watchdog).await {
+                    error!("Unable to store learned cadences: {e:#}");
+                }
+                watchdog.tick(now)
+            },
+            signal = receiver.recv() => match signal {
+                Ok(crate::Signal::NewMessage(stored)) => watchdog.observe(&stored, now),
+                Ok(crate::Signal::Stop) | Err(RecvError::Closed) => break,
+                // Missed messages only make hosts look quieter than they are for one window
+                Err(RecvError::Lagged(_)) => continue,
+            },
+        };
+        for transition in transitions {
+            let alert = match apply(&mut con, &transition, now).await {
+                Ok(alert) => alert,
+                Err(e) => {
+                    error!(?transition, "Unable to store host status: {e:#}");
+                    continue;
+                }
+            };
+            let (host, state) = match &transition {
+                Transition::Silent { host, .. } => {
+                    info!(host = host.name(), "Host went silent");
+                    (host, "firing")
+                }
+                Transition::Resumed { host } => {
+                    info!(host = host.name(), "Host resumed");
+                    (host, "resolved")
+                }
+            };
+            if let Some(alert) = alert {
+                notifiers.notify(Notification {
+                    alert,
+                    rule: SILENT_RULE.to_string(),
+                    host: host.name().to_string(),
+                    state: state.to_string(),
+                    timestamp: now,
+                    message: None,
+                });
+            }
+        }
+    }
+
+    info!("Watchdog stopped");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Host, Transition, Watchdog};
+    use crate::syslog::Stored;
+    use syslog_loose::parse_message;
+
+    fn stored(line: &str) -> Stored {
+        Stored {
+            id: 0,
+            ip: "10.0.0.1".parse().unwrap(),
+            msg: parse_message(line).into(),
+            server_timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn learned_and_configured_windows() {
+        let config: Config =
+            serde_json::from_str(r#"{"hosts": {"core": 30}, "samples": 3, "min_window": 60}"#).unwrap();
+        let mut watchdog = Watchdog::new(config);
+        let router = stored("<38>Oct 11 22:14:15 router sshd[1]: tick");
+        let core = stored("<38>Oct 11 22:14:15 core sshd[1]: tick");
+
+        watchdog.observe(&core, 1_000);
+        for minute in 1..5 {
+            watchdog.observe(&router, minute * 60_000);
+        }
+        // Configured window for core, the address the messages came from isn't learned
+        assert_eq!(
+            watchdog.tick(31_000),
+            [Transition::Silent { host: Host::Hostname("core".to_string()), last_seen: 1_000 }]
+        );
+        // Three times the longest gap after the last message
+        assert!(watchdog.tick(240_000 + 179_000).is_empty());
+        assert_eq!(
+            watchdog.tick(240_000 + 180_000),
+            [Transition::Silent { host: Host::Hostname("router".to_string()), last_seen: 240_000 }]
+        );
+        assert!(watchdog.tick(500_000).is_empty());
+        assert_eq!(
+            watchdog.observe(&router, 500_000),
+            [Transition::Resumed { host: Host::Hostname("router".to_string()) }]
+        );
+    }
+
+    #[test]
+    fn restored_cadence() {
+        let config: Config = serde_json::from_str(r#"{"samples": 3, "min_window": 60}"#).unwrap();
+        let mut watchdog = Watchdog::new(config);
+        let router = stored("<38>Oct 11 22:14:15 router sshd[1]: tick");
+        for minute in 1..5 {
+            watchdog.observe(&router, minute * 60_000);
+        }
+        let host = Host::Hostname("router".to_string());
+        assert_eq!(watchdog.unsaved(), [(host.clone(), 60_000)]);
+        watchdog.saved(&host, 60_000);
+        assert!(watchdog.unsaved().is_empty());
+
+        // After a restart the stored cadence applies before any new gaps are seen
+        let config: Config = serde_json::from_str(r#"{"samples": 3, "min_window": 60}"#).unwrap();
+        let mut restarted = Watchdog::new(config);
+        restarted.restore(host.clone(), 240_000, false, Some(60_000));
+        assert!(restarted.unsaved().is_empty());
+        assert!(restarted.tick(240_000 + 179_000).is_empty());
+        assert_eq!(restarted.tick(240_000 + 180_000), [Transition::Silent { host, last_seen: 240_000 }]);
+    }
+}